@@ -34,6 +34,20 @@ namespace dnfcxx {
   rust::String dnf_package_get_arch(DnfPackage &pkg) {
     return rust::String(::dnf_package_get_arch(&pkg));
   }
+  // These two may be unset
+  rust::String dnf_package_get_license(DnfPackage &pkg) {
+    return rust::String(::dnf_package_get_license(&pkg) ?: "");
+  }
+  rust::String dnf_package_get_sourcerpm(DnfPackage &pkg) {
+    return rust::String(::dnf_package_get_sourcerpm(&pkg) ?: "");
+  }
+  rust::Vec<rust::String> dnf_package_get_files(DnfPackage &pkg) {
+    g_auto(GStrv) files = ::dnf_package_get_files(&pkg);
+    rust::Vec<rust::String> r;
+    for (char **it = files; it && *it; it++)
+      r.push_back(rust::String(*it));
+    return r;
+  }
 
   rust::String dnf_repo_get_id(DnfRepo &repo) {
     return rust::String(::dnf_repo_get_id(&repo));
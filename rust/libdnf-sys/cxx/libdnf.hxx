@@ -31,6 +31,9 @@ namespace dnfcxx {
   rust::String dnf_package_get_name(DnfPackage &pkg);
   rust::String dnf_package_get_evr(DnfPackage &pkg);
   rust::String dnf_package_get_arch(DnfPackage &pkg);
+  rust::String dnf_package_get_license(DnfPackage &pkg);
+  rust::String dnf_package_get_sourcerpm(DnfPackage &pkg);
+  rust::Vec<rust::String> dnf_package_get_files(DnfPackage &pkg);
 
   typedef ::DnfRepo DnfRepo;
   rust::String dnf_repo_get_id(DnfRepo &repo);
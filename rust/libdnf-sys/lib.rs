@@ -36,6 +36,9 @@ pub mod ffi {
         fn dnf_package_get_name(pkg: &mut DnfPackage) -> Result<String>;
         fn dnf_package_get_evr(pkg: &mut DnfPackage) -> Result<String>;
         fn dnf_package_get_arch(pkg: &mut DnfPackage) -> Result<String>;
+        fn dnf_package_get_license(pkg: &mut DnfPackage) -> Result<String>;
+        fn dnf_package_get_sourcerpm(pkg: &mut DnfPackage) -> Result<String>;
+        fn dnf_package_get_files(pkg: &mut DnfPackage) -> Result<Vec<String>>;
 
         type DnfRepo = crate::DnfRepo;
         fn dnf_repo_get_id(repo: &mut DnfRepo) -> Result<String>;
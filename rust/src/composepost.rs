@@ -8,22 +8,27 @@
 use crate::bwrap;
 use crate::cxxrsutil::{CxxResult, FFIGObjectWrapper};
 use crate::passwd::PasswdDB;
-use crate::treefile::Treefile;
+use crate::treefile::{parse_mode, AddPath, AddPathType, PostprocessOp, Treefile};
+use crate::utils::source_date_epoch;
 use anyhow::{anyhow, bail, Context, Result};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use fn_error_context::context;
 use gio::CancellableExt;
-use nix::sys::stat::Mode;
+use nix::sys::stat::{utimensat, Mode, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
 use openat_ext::OpenatDirExt;
 use rayon::prelude::*;
 use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
+use std::ffi::CString;
 use std::fmt::Write as FmtWrite;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Seek, Write};
+use std::io::{BufRead, BufReader, Seek, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 
 /* See rpmostree-core.h */
@@ -32,6 +37,30 @@ const RPMOSTREE_RPMDB_LOCATION: &str = "usr/share/rpm";
 const RPMOSTREE_SYSIMAGE_RPMDB: &str = "usr/lib/sysimage/rpm";
 pub(crate) const TRADITIONAL_RPMDB_LOCATION: &str = "var/lib/rpm";
 
+/// Set the access and modification times of `path`, without following symlinks.
+fn set_timestamps(dir: &openat::Dir, path: &str, secs: i64) -> Result<()> {
+    let t = TimeSpec::seconds(secs);
+    utimensat(
+        Some(dir.as_raw_fd()),
+        path,
+        &t,
+        &t,
+        UtimensatFlags::NoFollowSymlink,
+    )
+    .with_context(|| format!("Setting timestamps of {}", path))?;
+    Ok(())
+}
+
+/// In reproducible mode, clamp the modification time of `path` to `SOURCE_DATE_EPOCH`.
+fn clamp_mtime(dir: &openat::Dir, path: &str) -> Result<()> {
+    if let Some(epoch) = source_date_epoch()? {
+        if dir.metadata(path)?.stat().st_mtime > epoch {
+            set_timestamps(dir, path, epoch)?;
+        }
+    }
+    Ok(())
+}
+
 #[context("Moving {}", name)]
 fn dir_move_if_exists(src: &openat::Dir, dest: &openat::Dir, name: &str) -> Result<()> {
     if src.exists(name)? {
@@ -193,6 +222,7 @@ fn postprocess_rpm_macro(rootfs_dfd: &openat::Dir) -> Result<()> {
         Ok(())
     })?;
     rpm_macros_dfd.set_mode(MACRO_FILENAME, 0o644)?;
+    clamp_mtime(&rpm_macros_dfd, MACRO_FILENAME)?;
     Ok(())
 }
 
@@ -240,6 +270,53 @@ pub fn compose_postprocess_final(rootfs_dfd: i32) -> CxxResult<()> {
     Ok(tasks.par_iter().try_for_each(|f| f(&rootfs_dfd))?)
 }
 
+/// Directories holding system unit files, in the rootfs after moving /etc.
+static UNIT_DIRS: &[&str] = &["usr/etc/systemd/system", "usr/lib/systemd/system"];
+
+/// Whether a unit file for `unit` exists; for template instances like
+/// `foo@bar.service`, the template `foo@.service` is enough.
+fn unit_exists(rootfs_dfd: &openat::Dir, unit: &str) -> Result<bool> {
+    let mut names = vec![unit.to_string()];
+    if let (Some(at), Some(dot)) = (unit.find('@'), unit.rfind('.')) {
+        if dot > at + 1 {
+            names.push(format!("{}{}", &unit[..=at], &unit[dot..]));
+        }
+    }
+    for dir in UNIT_DIRS {
+        for name in names.iter() {
+            let path = format!("{}/{}", dir, name);
+            if rootfs_dfd.metadata_optional(path.as_str())?.is_some() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Verify that all the units referenced by the treefile exist, rather than
+/// creating dangling symlinks.
+#[context("Checking systemd units")]
+fn check_units_exist(rootfs_dfd: &openat::Dir, treefile: &Treefile) -> Result<()> {
+    let parsed = &treefile.parsed;
+    let units = parsed
+        .units
+        .iter()
+        .flatten()
+        .map(|s| s.as_str())
+        .chain(parsed.default_target.as_deref())
+        .chain(parsed.systemd.iter().flat_map(|s| s.units()));
+    let mut missing = Vec::new();
+    for unit in units {
+        if !missing.contains(&unit) && !unit_exists(rootfs_dfd, unit)? {
+            missing.push(unit);
+        }
+    }
+    if !missing.is_empty() {
+        bail!("Unit files not found: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
 #[context("Handling treefile 'units'")]
 fn compose_postprocess_units(rootfs_dfd: &openat::Dir, treefile: &mut Treefile) -> Result<()> {
     let units = if let Some(u) = treefile.parsed.units.as_ref() {
@@ -282,6 +359,77 @@ fn compose_postprocess_default_target(rootfs_dfd: &openat::Dir, target: &str) ->
     Ok(())
 }
 
+/// Implementation of the treefile `systemd` field.
+#[context("Handling treefile 'systemd'")]
+fn compose_postprocess_systemd(
+    rootfs_dfd: &openat::Dir,
+    treefile: &Treefile,
+    unified_core: bool,
+) -> Result<()> {
+    let systemd = if let Some(s) = treefile.parsed.systemd.as_ref() {
+        s
+    } else {
+        return Ok(());
+    };
+
+    for (unit, dropins) in systemd.dropins.iter().flatten() {
+        let dir = format!("usr/lib/systemd/system/{}.d", unit);
+        rootfs_dfd.ensure_dir_all(dir.as_str(), 0o755)?;
+        for (name, contents) in dropins {
+            println!("Adding drop-in {} for {}", name, unit);
+            let path = format!("{}/{}", dir, name);
+            rootfs_dfd.write_file_contents(path.as_str(), 0o644, contents)?;
+        }
+    }
+
+    let enable = systemd.preset_enable.iter().flatten();
+    let disable = systemd.preset_disable.iter().flatten();
+    let mut preset = String::new();
+    let mut units = Vec::new();
+    for (action, unit) in enable
+        .map(|u| ("enable", u))
+        .chain(disable.map(|u| ("disable", u)))
+    {
+        writeln!(preset, "{} {}", action, unit).unwrap();
+        units.push(unit.clone());
+    }
+    if !units.is_empty() {
+        // Preset files sort by name, and the first match wins; override the
+        // presets shipped by packages.
+        let preset_dir = "usr/lib/systemd/system-preset";
+        rootfs_dfd.ensure_dir_all(preset_dir, 0o755)?;
+        let path = format!("{}/10-rpm-ostree-treefile.preset", preset_dir);
+        rootfs_dfd.write_file_contents(path.as_str(), 0o644, preset.as_bytes())?;
+        // And apply them now, as we can't rely on `systemctl preset-all` at
+        // first boot; see `machineid-compat`.
+        println!("Applying presets for {}", units.join(", "));
+        let mut argv = vec![
+            "systemctl".to_string(),
+            "preset".to_string(),
+            "-q".to_string(),
+        ];
+        argv.extend(units);
+        bwrap::bubblewrap_run_sync(rootfs_dfd.as_raw_fd(), &argv, false, unified_core)?;
+    }
+
+    let etc_units = Path::new("usr/etc/systemd/system");
+    for unit in systemd.mask.iter().flatten() {
+        let dest = etc_units.join(unit);
+        match rootfs_dfd
+            .metadata_optional(&dest)?
+            .map(|m| m.simple_type())
+        {
+            None => {}
+            Some(openat::SimpleType::Symlink) => rootfs_dfd.remove_file(&dest)?,
+            Some(_) => bail!("Cannot mask {}: /etc/systemd/system/{} exists", unit, unit),
+        }
+        println!("Masking {}", unit);
+        rootfs_dfd.ensure_dir_all(etc_units, 0o755)?;
+        rootfs_dfd.symlink(&dest, "/dev/null")?;
+    }
+    Ok(())
+}
+
 /// The treefile format has two kinds of postprocessing scripts;
 /// there's a single `postprocess-script` as well as inline (anonymous)
 /// scripts.  This function executes both kinds in bwrap containers.
@@ -383,6 +531,277 @@ fn compose_postprocess_add_files(rootfs_dfd: &openat::Dir, treefile: &mut Treefi
     Ok(())
 }
 
+/// Implementation of the treefile `add-paths` field.
+#[context("Handling `add-paths`")]
+fn compose_postprocess_add_paths(rootfs_dfd: &openat::Dir, treefile: &Treefile) -> Result<()> {
+    let paths = treefile.parsed.add_paths.as_deref().unwrap_or_default();
+    add_paths(rootfs_dfd, paths)
+}
+
+fn add_paths(rootfs: &openat::Dir, paths: &[AddPath]) -> Result<()> {
+    // Only load the users and groups DB if we need it.
+    let pwdb = if paths.iter().any(|p| p.user.is_some() || p.group.is_some()) {
+        Some(PasswdDB::populate_new(rootfs)?)
+    } else {
+        None
+    };
+    for p in paths {
+        add_path(rootfs, p, pwdb.as_ref()).with_context(|| format!("Adding {}", p.path))?;
+    }
+    Ok(())
+}
+
+fn add_path(rootfs: &openat::Dir, p: &AddPath, pwdb: Option<&PasswdDB>) -> Result<()> {
+    // This runs with /etc in place (see `prepare_tempetc_guard`).
+    let dest = Path::new(p.path.trim_start_matches('/'));
+    ensure_no_symlink_parents(rootfs, dest)?;
+    if let Some(parent) = dest.parent() {
+        rootfs.ensure_dir_all(parent, 0o755)?;
+    }
+    // Don't replace symlinks, the mode would be applied to their target, which
+    // may be outside of the rootfs.
+    if p.path_type != AddPathType::Symlink {
+        if let Some(meta) = rootfs.metadata_optional(dest)? {
+            if meta.simple_type() == openat::SimpleType::Symlink {
+                bail!("Content already exists at path (as a symlink)");
+            }
+        }
+    }
+
+    let mode = p.mode()?;
+    match p.path_type {
+        AddPathType::Directory => {
+            println!("Adding directory {}", p.path);
+            rootfs.ensure_dir_all(dest, mode)?;
+        }
+        AddPathType::File => {
+            println!("Adding file {}", p.path);
+            let content = p.content.as_deref().unwrap_or_default();
+            rootfs.write_file_contents(dest, mode, content)?;
+        }
+        AddPathType::Symlink => {
+            println!("Adding symlink {}", p.path);
+            replace_symlink(rootfs, dest, p.target.as_deref().unwrap_or_default())?;
+        }
+    }
+
+    let (uid, gid) = match pwdb {
+        Some(db) => (
+            p.user
+                .as_deref()
+                .map(|u| db.lookup_user_id(u))
+                .transpose()?,
+            p.group
+                .as_deref()
+                .map(|g| db.lookup_group_id(g))
+                .transpose()?,
+        ),
+        None => (None, None),
+    };
+    if uid.is_some() || gid.is_some() {
+        nix::unistd::fchownat(
+            Some(rootfs.as_raw_fd()),
+            dest,
+            uid,
+            gid,
+            nix::unistd::FchownatFlags::NoFollowSymlink,
+        )?;
+    }
+    // Set the mode after changing ownership, since the latter clears setuid/setgid bits.
+    if p.path_type != AddPathType::Symlink {
+        nix::sys::stat::fchmodat(
+            Some(rootfs.as_raw_fd()),
+            dest,
+            Mode::from_bits_truncate(mode),
+            nix::sys::stat::FchmodatFlags::FollowSymlink,
+        )?;
+    }
+    Ok(())
+}
+
+/// Error out if a parent directory of `path` in the rootfs is a symlink, since
+/// following it could lead outside of the rootfs (e.g. if it is absolute).
+fn ensure_no_symlink_parents(rootfs: &openat::Dir, path: &Path) -> Result<()> {
+    use openat::SimpleType;
+
+    let mut prefix = PathBuf::new();
+    for component in path.parent().into_iter().flat_map(|p| p.components()) {
+        if !matches!(component, std::path::Component::Normal(_)) {
+            bail!("Unsupported path {}", path.display());
+        }
+        prefix.push(component);
+        match rootfs.metadata_optional(&prefix)? {
+            Some(meta) if meta.simple_type() == SimpleType::Symlink => {
+                bail!("Refusing to follow symlink {}", prefix.display())
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+/// Create a symlink at `dest`, replacing an existing symlink (but nothing else).
+fn replace_symlink(rootfs: &openat::Dir, dest: &Path, target: &str) -> Result<()> {
+    use openat::SimpleType;
+
+    if let Some(meta) = rootfs.metadata_optional(dest)? {
+        if meta.simple_type() != SimpleType::Symlink {
+            bail!("Content already exists at link path");
+        }
+        rootfs.remove_file(dest)?;
+    }
+    rootfs.symlink(dest, target)?;
+    Ok(())
+}
+
+/// Implementation of the treefile `postprocess-ops` field; unlike the
+/// `postprocess` scripts, these run directly against the rootfs.
+fn compose_postprocess_ops(rootfs_dfd: &openat::Dir, treefile: &Treefile) -> Result<()> {
+    for (i, op) in treefile.parsed.postprocess_ops.iter().flatten().enumerate() {
+        println!("Executing `postprocess-ops` {}", op);
+        postprocess_op(rootfs_dfd, op)
+            .with_context(|| format!("Executing postprocess-ops[{}] ({})", i, op))?;
+    }
+    Ok(())
+}
+
+fn postprocess_op(rootfs: &openat::Dir, op: &PostprocessOp) -> Result<()> {
+    let path = Path::new(op.path().trim_start_matches('/'));
+    match op {
+        PostprocessOp::Delete { glob } => {
+            for p in expand_glob(rootfs, glob)? {
+                rootfs.remove_all(p.as_std_path())?;
+            }
+        }
+        PostprocessOp::Chmod { mode, .. } => {
+            let mode = parse_mode(mode)?;
+            ensure_no_symlink_parents(rootfs, path)?;
+            if rootfs.metadata(path)?.simple_type() == openat::SimpleType::Symlink {
+                bail!("Refusing to change the mode of symlink {}", path.display());
+            }
+            nix::sys::stat::fchmodat(
+                Some(rootfs.as_raw_fd()),
+                path,
+                Mode::from_bits_truncate(mode),
+                nix::sys::stat::FchmodatFlags::FollowSymlink,
+            )?;
+        }
+        PostprocessOp::Write { content, mode, .. } => {
+            let mode = mode
+                .as_deref()
+                .map(parse_mode)
+                .transpose()?
+                .unwrap_or(0o644);
+            ensure_no_symlink_parents(rootfs, path)?;
+            if let Some(parent) = path.parent() {
+                rootfs.ensure_dir_all(parent, 0o755)?;
+            }
+            rootfs.write_file_contents(path, mode, content)?;
+        }
+        PostprocessOp::Symlink { target, .. } => {
+            ensure_no_symlink_parents(rootfs, path)?;
+            if let Some(parent) = path.parent() {
+                rootfs.ensure_dir_all(parent, 0o755)?;
+            }
+            replace_symlink(rootfs, path, target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Find the paths matching `glob` in the rootfs, in sorted order.  Symlinks
+/// to directories are not descended into.
+fn expand_glob(rootfs: &openat::Dir, glob: &str) -> Result<Vec<Utf8PathBuf>> {
+    use openat::SimpleType;
+
+    let mut matches = vec![Utf8PathBuf::new()];
+    for component in glob.split('/').filter(|c| !c.is_empty()) {
+        let mut next = Vec::new();
+        for parent in matches {
+            let is_root = parent.as_str().is_empty();
+            if !is_root {
+                match rootfs.metadata_optional(parent.as_std_path())? {
+                    Some(meta) if meta.simple_type() == SimpleType::Dir => {}
+                    _ => continue,
+                }
+            }
+            if !component.contains(|c| c == '*' || c == '?' || c == '[') {
+                let path = parent.join(component);
+                if rootfs.metadata_optional(path.as_std_path())?.is_some() {
+                    next.push(path);
+                }
+                continue;
+            }
+            let dir = if is_root { "." } else { parent.as_str() };
+            for entry in rootfs.list_dir(dir)? {
+                let entry = entry?;
+                let name: &Utf8Path = Path::new(entry.file_name()).try_into()?;
+                if glob_match(component, name.as_str()) {
+                    next.push(parent.join(name));
+                }
+            }
+        }
+        matches = next;
+    }
+    matches.retain(|p| !p.as_str().is_empty());
+    matches.sort();
+    Ok(matches)
+}
+
+/// Match a file name against a shell-style pattern, supporting `*`, `?` and
+/// `[...]` classes.  As in the shell, a leading `.` must be matched explicitly.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob_match_chars(&pattern, &name)
+}
+
+fn glob_match_chars(p: &[char], n: &[char]) -> bool {
+    match p.first() {
+        None => n.is_empty(),
+        Some(&'*') => (0..=n.len()).any(|i| glob_match_chars(&p[1..], &n[i..])),
+        Some(&'?') => !n.is_empty() && glob_match_chars(&p[1..], &n[1..]),
+        Some(&'[') => {
+            let (negate, start) = if p.get(1) == Some(&'!') {
+                (true, 2)
+            } else {
+                (false, 1)
+            };
+            // A `]` right at the start of the class is literal.
+            let end = p
+                .iter()
+                .skip(start + 1)
+                .position(|&c| c == ']')
+                .map(|i| i + start + 1);
+            match (end, n.first()) {
+                (Some(end), Some(&c)) => {
+                    let class = &p[start..end];
+                    let mut matched = false;
+                    let mut i = 0;
+                    while i < class.len() {
+                        if i + 2 < class.len() && class[i + 1] == '-' {
+                            matched |= class[i] <= c && c <= class[i + 2];
+                            i += 3;
+                        } else {
+                            matched |= class[i] == c;
+                            i += 1;
+                        }
+                    }
+                    matched != negate && glob_match_chars(&p[end + 1..], &n[1..])
+                }
+                // An unterminated class is a literal `[`
+                (None, Some(&'[')) => glob_match_chars(&p[1..], &n[1..]),
+                _ => false,
+            }
+        }
+        Some(c) => n.first() == Some(c) && glob_match_chars(&p[1..], &n[1..]),
+    }
+}
+
 #[context("Symlinking {}", TRADITIONAL_RPMDB_LOCATION)]
 fn compose_postprocess_rpmdb(rootfs_dfd: &openat::Dir) -> Result<()> {
     /* This works around a potential issue with libsolv if we go down the
@@ -420,10 +839,12 @@ pub fn compose_postprocess(
     }
 
     compose_postprocess_rpmdb(rootfs_dfd)?;
+    check_units_exist(&rootfs_dfd, treefile)?;
     compose_postprocess_units(&rootfs_dfd, treefile)?;
     if let Some(t) = treefile.parsed.default_target.as_deref() {
         compose_postprocess_default_target(&rootfs_dfd, t)?;
     }
+    compose_postprocess_systemd(&rootfs_dfd, treefile, unified_core)?;
 
     treefile.write_compose_json(rootfs_dfd)?;
 
@@ -432,6 +853,8 @@ pub fn compose_postprocess(
     compose_postprocess_mutate_os_release(rootfs_dfd, treefile, next_version)?;
     compose_postprocess_remove_files(rootfs_dfd, treefile)?;
     compose_postprocess_add_files(rootfs_dfd, treefile)?;
+    compose_postprocess_add_paths(rootfs_dfd, treefile)?;
+    compose_postprocess_ops(rootfs_dfd, treefile)?;
     etc_guard.undo()?;
 
     compose_postprocess_scripts(rootfs_dfd, treefile, unified_core)?;
@@ -439,52 +862,78 @@ pub fn compose_postprocess(
     Ok(())
 }
 
-/// Implementation of the treefile `mutate-os-release` field.
-#[context("Updating os-release with commit version")]
+/// Implementation of the treefile `mutate-os-release` and `os-release` fields.
+#[context("Updating os-release")]
 fn compose_postprocess_mutate_os_release(
     rootfs_dfd: &openat::Dir,
     treefile: &mut Treefile,
     next_version: &str,
 ) -> Result<()> {
-    let base_version = if let Some(base_version) = treefile.parsed.mutate_os_release.as_deref() {
-        base_version
-    } else {
-        return Ok(());
-    };
-    if next_version.is_empty() {
+    let mut base_version = treefile.parsed.mutate_os_release.as_deref();
+    if base_version.is_some() && next_version.is_empty() {
         println!("Ignoring mutate-os-release: no commit version specified.");
+        base_version = None;
+    }
+    let mut overrides = Vec::new();
+    for (key, value) in treefile.parsed.os_release.iter().flatten() {
+        if value.contains("${version}") && next_version.is_empty() {
+            println!(
+                "Ignoring os-release key {}: no commit version specified.",
+                key
+            );
+            continue;
+        }
+        overrides.push((key.as_str(), value.replace("${version}", next_version)));
+    }
+    if base_version.is_none() && overrides.is_empty() {
         return Ok(());
     }
-    // find the real path to os-release using bwrap; this is an overkill but safer way
+
+    for path in os_release_paths(rootfs_dfd)? {
+        println!("Updating {}", path);
+        let contents = rootfs_dfd
+            .read_to_string(path.as_str())
+            .with_context(|| format!("Reading {}", path))?;
+        let mut new_contents = match base_version {
+            Some(base_version) => mutate_os_release_contents(&contents, base_version, next_version),
+            None => contents,
+        };
+        if !overrides.is_empty() {
+            new_contents = override_os_release_contents(&new_contents, &overrides);
+        }
+        rootfs_dfd
+            .write_file_contents(path.as_str(), 0o644, new_contents.as_bytes())
+            .with_context(|| format!("Writing {}", path))?;
+    }
+    Ok(())
+}
+
+/// Find the distinct os-release files in the rootfs: /etc/os-release is
+/// usually a symlink to /usr/lib/os-release, which may itself be a symlink.
+fn os_release_paths(rootfs_dfd: &openat::Dir) -> Result<Vec<String>> {
+    // find the real paths to os-release using bwrap; this is an overkill but safer way
     // of resolving a symlink relative to a rootfs (see discussions in
     // https://github.com/projectatomic/rpm-ostree/pull/410/)
     let mut bwrap = crate::bwrap::Bubblewrap::new_with_mutability(
         rootfs_dfd,
         crate::ffi::BubblewrapMutability::Immutable,
     )?;
-    bwrap.append_child_argv(&["realpath", "/etc/os-release"]);
+    bwrap.append_child_argv(&["realpath", "-m", "/etc/os-release", "/usr/lib/os-release"]);
     let cancellable = &gio::Cancellable::new();
     let cancellable = Some(cancellable);
-    let path = bwrap.run_captured(cancellable)?;
-    let path = std::str::from_utf8(&path)
-        .context("Parsing realpath")?
-        .trim_start_matches('/')
-        .trim_end();
-    let path = if path.is_empty() {
+    let output = bwrap.run_captured(cancellable)?;
+    let output = std::str::from_utf8(&output).context("Parsing realpath")?;
+    let mut paths: Vec<String> = Vec::new();
+    for path in output.lines().map(|p| p.trim_start_matches('/')) {
+        if !path.is_empty() && !paths.iter().any(|p| p == path) && rootfs_dfd.exists(path)? {
+            paths.push(path.to_string());
+        }
+    }
+    if paths.is_empty() {
         // fallback on just overwriting etc/os-release
-        "etc/os-release"
-    } else {
-        path
-    };
-    println!("Updating {}", path);
-    let contents = rootfs_dfd
-        .read_to_string(path)
-        .with_context(|| format!("Reading {}", path))?;
-    let new_contents = mutate_os_release_contents(&contents, base_version, next_version);
-    rootfs_dfd
-        .write_file_contents(path, 0o644, new_contents.as_bytes())
-        .with_context(|| format!("Writing {}", path))?;
-    Ok(())
+        paths.push("etc/os-release".to_string());
+    }
+    Ok(paths)
 }
 
 /// Given the contents of a /usr/lib/os-release file,
@@ -515,6 +964,37 @@ fn mutate_os_release_contents(contents: &str, base_version: &str, next_version:
     buf
 }
 
+/// Given the contents of an os-release file, set the keys in `overrides`,
+/// replacing existing assignments in place and appending the others.
+fn override_os_release_contents(contents: &str, overrides: &[(&str, String)]) -> String {
+    let assignment = |key: &str, value: &str| {
+        // Unwrap safety; we provided it UTF-8
+        let quoted = glib::shell_quote(value).unwrap();
+        format!("{}={}\n", key, quoted.to_str().unwrap())
+    };
+    let mut buf = String::new();
+    let mut done = HashSet::new();
+    for line in contents.lines() {
+        let key = line.splitn(2, '=').next().unwrap_or_default().trim();
+        match overrides.iter().find(|(k, _)| *k == key) {
+            // Drop any duplicate assignments
+            Some(_) if done.contains(key) => {}
+            Some((k, v)) => {
+                buf.push_str(&assignment(k, v));
+                done.insert(*k);
+            }
+            None => {
+                buf.push_str(line);
+                buf.push('\n');
+            }
+        }
+    }
+    for (k, v) in overrides.iter().filter(|(k, _)| !done.contains(k)) {
+        buf.push_str(&assignment(k, v));
+    }
+    buf
+}
+
 /// Given a string and a set of possible prefixes, return the split
 /// prefix and remaining string, or `None` if no matches.
 fn strip_any_prefix<'a, 'b>(s: &'a str, prefixes: &[&'b str]) -> Option<(&'b str, &'a str)> {
@@ -525,6 +1005,11 @@ fn strip_any_prefix<'a, 'b>(s: &'a str, prefixes: &[&'b str]) -> Option<(&'b str
 
 /// Inject `altfiles` after `files` for `passwd:` and `group:` entries.
 fn add_altfiles(buf: &str) -> Result<String> {
+    add_nss_module(buf, "altfiles")
+}
+
+/// Inject `module` after `files` for `passwd:` and `group:` entries.
+fn add_nss_module(buf: &str, module: &str) -> Result<String> {
     let mut r = String::with_capacity(buf.len());
     for line in buf.lines() {
         let parts = if let Some(p) = strip_any_prefix(line, &["passwd:", "group:"]) {
@@ -539,13 +1024,14 @@ fn add_altfiles(buf: &str) -> Result<String> {
 
         let mut inserted = false;
         for elt in rest.split_whitespace() {
-            // Already have altfiles?  We're done
-            if elt == "altfiles" {
+            // Already have the module?  We're done
+            if elt == module {
                 return Ok(buf.to_string());
             }
-            // We prefer `files altfiles`
+            // We prefer `files <module>`
             if !inserted && elt == "files" {
-                r.push_str(" files altfiles");
+                r.push_str(" files ");
+                r.push_str(module);
                 inserted = true;
             } else {
                 r.push(' ');
@@ -553,7 +1039,8 @@ fn add_altfiles(buf: &str) -> Result<String> {
             }
         }
         if !inserted {
-            r.push_str(" altfiles");
+            r.push(' ');
+            r.push_str(module);
         }
         r.push('\n');
     }
@@ -573,20 +1060,315 @@ pub fn composepost_nsswitch_altfiles(rootfs_dfd: i32) -> CxxResult<()> {
     Ok(())
 }
 
+/// Alternative to `composepost_nsswitch_altfiles`: write the users and groups
+/// from /usr/lib as systemd userdb records, and add `systemd` entries to
+/// `nsswitch.conf`.
+#[context("Adding systemd userdb records")]
+pub fn composepost_nsswitch_userdb(rootfs_dfd: i32) -> CxxResult<()> {
+    let rootfs_dfd = &crate::ffiutil::ffi_view_openat_dir(rootfs_dfd);
+    write_userdb_records(rootfs_dfd)?;
+    let path = "usr/etc/nsswitch.conf";
+    let nsswitch = rootfs_dfd.read_to_string(path)?;
+    let nsswitch = add_nss_module(&nsswitch, "systemd")?;
+    rootfs_dfd.write_file_contents(path, 0o644, nsswitch.as_bytes())?;
+    Ok(())
+}
+
+/// Drop-in directory for systemd userdb records.
+pub(crate) const USERDB_DIR: &str = "usr/lib/userdb";
+/// The records in `USERDB_DIR` we wrote, one per line; other files there
+/// are left alone.
+const USERDB_MANIFEST: &str = ".rpm-ostree-records";
+
+/// The `disposition` of a user or group record, from its ID.
+fn userdb_disposition(id: u32) -> &'static str {
+    match id {
+        0 | 65534 => "intrinsic",
+        1..=999 => "system",
+        _ => "regular",
+    }
+}
+
+/// Replace `<name>.<suffix>` with `record`, and point `<id>.<suffix>` to it.
+fn write_userdb_record(
+    rootfs: &openat::Dir,
+    name: &str,
+    id: u32,
+    suffix: &str,
+    record: serde_json::Value,
+) -> Result<()> {
+    let target = format!("{}.{}", name, suffix);
+    let mut buf = serde_json::to_vec_pretty(&record)?;
+    buf.push(b'\n');
+    rootfs.write_file_contents(format!("{}/{}", USERDB_DIR, target), 0o644, buf)?;
+    let link = format!("{}/{}.{}", USERDB_DIR, id, suffix);
+    rootfs.remove_file_optional(link.as_str())?;
+    rootfs.symlink(link.as_str(), target.as_str())?;
+    Ok(())
+}
+
+/// Write a systemd userdb record for every user and group in /usr/lib/passwd
+/// and /usr/lib/group, as `NAME.user` (resp. `NAME.group`) with a
+/// `UID.user` (resp. `GID.group`) symlink.  Password data stays in
+/// /etc/shadow.  Records written previously for users and groups which are
+/// gone are removed.
+#[context("Writing userdb records")]
+pub(crate) fn write_userdb_records(rootfs: &openat::Dir) -> Result<()> {
+    use crate::nameservice::{group, passwd};
+    let users = passwd::parse_passwd_content(BufReader::new(rootfs.open_file("usr/lib/passwd")?))?;
+    let groups = group::parse_group_content(BufReader::new(rootfs.open_file("usr/lib/group")?))?;
+    rootfs.ensure_dir_all(USERDB_DIR, 0o755)?;
+
+    let manifest_path = format!("{}/{}", USERDB_DIR, USERDB_MANIFEST);
+    let previous: BTreeSet<String> = match rootfs.open_file_optional(manifest_path.as_str())? {
+        Some(f) => BufReader::new(f).lines().collect::<std::io::Result<_>>()?,
+        None => BTreeSet::new(),
+    };
+    let records: BTreeSet<String> = users
+        .iter()
+        .map(|u| format!("{}.user", u.name))
+        .chain(groups.iter().map(|g| format!("{}.group", g.name)))
+        .collect();
+
+    // Drop the ID symlinks to our records; the IDs may have changed.
+    let ours: HashSet<&str> = previous
+        .iter()
+        .chain(records.iter())
+        .map(|r| r.as_str())
+        .collect();
+    for entry in rootfs.list_dir(USERDB_DIR)? {
+        let entry = entry?;
+        let name = match entry.file_name().to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let path = format!("{}/{}", USERDB_DIR, name);
+        if rootfs.metadata(path.as_str())?.simple_type() != openat::SimpleType::Symlink {
+            continue;
+        }
+        let target = rootfs.read_link(path.as_str())?;
+        if target.to_str().map(|t| ours.contains(t)).unwrap_or(false) {
+            rootfs.remove_file(path.as_str())?;
+        }
+    }
+    for stale in previous.difference(&records) {
+        rootfs.remove_file_optional(format!("{}/{}", USERDB_DIR, stale).as_str())?;
+    }
+
+    for user in users.iter() {
+        let mut record = serde_json::json!({
+            "userName": user.name,
+            "uid": user.uid,
+            "gid": user.gid,
+            "homeDirectory": user.home_dir,
+            "shell": user.shell,
+            "disposition": userdb_disposition(user.uid),
+        });
+        if !user.gecos.is_empty() {
+            record["realName"] = user.gecos.clone().into();
+        }
+        write_userdb_record(rootfs, &user.name, user.uid, "user", record)?;
+    }
+    for group in groups.iter() {
+        let members: Vec<&str> = group
+            .users
+            .iter()
+            .filter(|u| !u.is_empty())
+            .map(|u| u.as_str())
+            .collect();
+        let mut record = serde_json::json!({
+            "groupName": group.name,
+            "gid": group.gid,
+            "disposition": userdb_disposition(group.gid),
+        });
+        if !members.is_empty() {
+            record["members"] = members.into();
+        }
+        write_userdb_record(rootfs, &group.name, group.gid, "group", record)?;
+    }
+    rootfs.write_file_with(manifest_path.as_str(), 0o644, |w| -> Result<()> {
+        for record in records.iter() {
+            writeln!(w, "{}", record)?;
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
 pub fn convert_var_to_tmpfiles_d(
     rootfs_dfd: i32,
+    mut packages: Pin<&mut crate::ffi::CxxGObjectArray>,
     mut cancellable: Pin<&mut crate::FFIGCancellable>,
 ) -> CxxResult<()> {
     let rootfs = crate::ffiutil::ffi_view_openat_dir(rootfs_dfd);
     let cancellable = &cancellable.gobj_wrap();
 
+    let mut owners = HashMap::new();
+    for i in 0..(packages.as_mut().length()) {
+        let pkg = packages.as_mut().get(i);
+        let pkg_ref = unsafe { &mut *(&mut pkg.0 as *mut _ as *mut libdnf_sys::DnfPackage) };
+        let name = libdnf_sys::dnf_package_get_name(pkg_ref)?;
+        for path in libdnf_sys::dnf_package_get_files(pkg_ref)? {
+            // If multiple packages own a path, the first one (by name) wins.
+            if path.starts_with("/var/") {
+                owners.entry(path).or_insert_with(|| name.clone());
+            }
+        }
+    }
+
     // TODO(lucab): unify this logic with the one in rpmostree-importer.cxx.
-    var_to_tmpfiles(&rootfs, Some(cancellable))?;
+    var_to_tmpfiles(&rootfs, &owners, Some(cancellable))?;
     Ok(())
 }
 
+/// Directories holding tmpfiles.d snippets, in the final rootfs layout.
+pub(crate) static TMPFILES_DIRS: &[&str] = &["usr/lib/tmpfiles.d", "usr/etc/tmpfiles.d"];
+
+/// The tmpfiles.d snippet for converted /var content no package owns.
+static AUTOVAR_PATH: &str = "usr/lib/tmpfiles.d/rpm-ostree-1-autovar.conf";
+
+/// Whether the tmpfiles.d snippet at `path` was generated from /var content,
+/// either by the importer (`pkg-*.conf`) or by `var_to_tmpfiles`.
+pub(crate) fn is_generated_tmpfiles(path: &str) -> bool {
+    path == AUTOVAR_PATH || path.starts_with("usr/lib/tmpfiles.d/pkg-")
+}
+
+/// A single tmpfiles.d entry; unset (`-`) fields are `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TmpfilesEntry {
+    /// The entry type, e.g. `d` or `L+`.
+    kind: String,
+    path: String,
+    mode: Option<u32>,
+    user: Option<String>,
+    group: Option<String>,
+    argument: Option<String>,
+}
+
+impl TmpfilesEntry {
+    /// Parse a tmpfiles.d line; returns `None` for comments, blank lines
+    /// and lines we can't make sense of.
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let mut fields = line.split_whitespace();
+        let kind = fields.next()?.to_string();
+        let path = fields.next()?.to_string();
+        let field = |f: Option<&str>| f.filter(|v| *v != "-").map(|v| v.to_string());
+        // The mode may be prefixed with `~` or `:` to adjust how it's applied.
+        let mode = field(fields.next()).and_then(|m| {
+            u32::from_str_radix(m.trim_start_matches(|c: char| c == '~' || c == ':'), 8).ok()
+        });
+        let user = field(fields.next());
+        let group = field(fields.next());
+        let _age = fields.next();
+        let argument = fields.collect::<Vec<_>>().join(" ");
+        let argument = field(Some(argument.as_str()).filter(|a| !a.is_empty()));
+        Some(Self {
+            kind,
+            path,
+            mode,
+            user,
+            group,
+            argument,
+        })
+    }
+
+    /// The kind of filesystem object this entry creates, if any; entries
+    /// which only adjust existing paths (e.g. `z` or `x`) return `None`.
+    fn file_type(&self) -> Option<char> {
+        match self.kind.chars().next()? {
+            'f' | 'F' => Some('f'),
+            'd' | 'D' | 'v' | 'q' | 'Q' => Some('d'),
+            c @ 'L' | c @ 'p' | c @ 'c' | c @ 'b' => Some(c),
+            _ => None,
+        }
+    }
+
+    /// If `other` creates the same path in an incompatible way, describe how.
+    fn conflict(&self, other: &TmpfilesEntry) -> Option<&'static str> {
+        fn differ<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool {
+            matches!((a, b), (Some(a), Some(b)) if a != b)
+        }
+        let (ours, theirs) = (self.file_type()?, other.file_type()?);
+        if ours != theirs {
+            Some("type")
+        } else if differ(&self.mode, &other.mode) {
+            Some("mode")
+        } else if differ(&self.user, &other.user) {
+            Some("user")
+        } else if differ(&self.group, &other.group) {
+            Some("group")
+        } else if ours == 'L' && differ(&self.argument, &other.argument) {
+            Some("symlink target")
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for TmpfilesEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let opt = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+        let mode = self
+            .mode
+            .map(|m| format!("{:04o}", m))
+            .unwrap_or_else(|| "-".to_string());
+        write!(
+            f,
+            "{} {} {} {} {} - {}",
+            self.kind,
+            self.path,
+            mode,
+            opt(&self.user),
+            opt(&self.group),
+            opt(&self.argument)
+        )
+    }
+}
+
+/// Gather the tmpfiles.d entries already present in the rootfs, keyed by
+/// path, along with the file providing each one.
+fn existing_tmpfiles_entries(
+    rootfs: &openat::Dir,
+) -> Result<HashMap<String, Vec<(String, TmpfilesEntry)>>> {
+    let mut entries: HashMap<_, Vec<_>> = HashMap::new();
+    for dir in TMPFILES_DIRS {
+        if !rootfs.exists(*dir)? {
+            continue;
+        }
+        let mut names = Vec::new();
+        for entry in rootfs.list_dir(*dir)? {
+            let entry = entry?;
+            let name: &Utf8Path = Path::new(entry.file_name()).try_into()?;
+            if name.as_str().ends_with(".conf") {
+                names.push(format!("{}/{}", dir, name));
+            }
+        }
+        names.sort();
+        for name in names {
+            let f = BufReader::new(rootfs.open_file(name.as_str())?);
+            for line in f.lines() {
+                if let Some(entry) = TmpfilesEntry::parse(&line?) {
+                    entries
+                        .entry(entry.path.clone())
+                        .or_default()
+                        .push((name.clone(), entry));
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
 #[context("Converting /var to tmpfiles.d")]
-fn var_to_tmpfiles(rootfs: &openat::Dir, cancellable: Option<&gio::Cancellable>) -> Result<()> {
+pub(crate) fn var_to_tmpfiles(
+    rootfs: &openat::Dir,
+    owners: &HashMap<String, String>,
+    cancellable: Option<&gio::Cancellable>,
+) -> Result<()> {
     /* List of files that are known to possibly exist, but in practice
      * things work fine if we simply ignore them.  Don't add something
      * to this list unless you've verified it's handled correctly at
@@ -621,19 +1403,71 @@ fn var_to_tmpfiles(rootfs: &openat::Dir, cancellable: Option<&gio::Cancellable>)
 
     // Convert /var wholesale to tmpfiles.d. Note that with unified core, this
     // code should no longer be necessary as we convert packages on import.
-    // Make output file world-readable, no reason why not to
+    let existing = existing_tmpfiles_entries(rootfs)?;
+    let mut entries = Vec::new();
+    let mut prefix = "var".to_string();
+    convert_path_to_tmpfiles_d_recurse(&mut entries, &pwdb, &rootfs, &mut prefix, &cancellable)
+        .with_context(|| format!("Analyzing /{} content", prefix))?;
+
+    // Drop entries which packages already provide, and split the rest by
+    // owning package; anything else goes into the autovar file.
+    let mut conflicts = Vec::new();
+    let mut by_package: BTreeMap<&str, Vec<TmpfilesEntry>> = BTreeMap::new();
+    let mut unowned = Vec::new();
+    for entry in entries {
+        let provided: Vec<_> = existing
+            .get(&entry.path)
+            .into_iter()
+            .flatten()
+            .filter(|(_, e)| e.file_type().is_some())
+            .collect();
+        if !provided.is_empty() {
+            for (source, e) in provided {
+                if let Some(reason) = entry.conflict(e) {
+                    conflicts.push(format!(
+                        "{}: {} differs from {} ({})",
+                        entry.path, reason, source, e
+                    ));
+                }
+            }
+            continue;
+        }
+        match owners.get(&entry.path) {
+            Some(pkg) => by_package.entry(pkg.as_str()).or_default().push(entry),
+            None => unowned.push(entry),
+        }
+    }
+    if !conflicts.is_empty() {
+        bail!(
+            "{} conflicting tmpfiles.d entries:\n  {}",
+            conflicts.len(),
+            conflicts.join("\n  ")
+        );
+    }
+
+    // Make output files world-readable, no reason why not to
     // https://bugzilla.redhat.com/show_bug.cgi?id=1631794
     rootfs.ensure_dir_all("usr/lib/tmpfiles.d", 0o755)?;
-    rootfs.write_file_with_sync(
-        "usr/lib/tmpfiles.d/rpm-ostree-1-autovar.conf",
-        0o644,
-        |bufwr| -> Result<()> {
-            let mut prefix = "var".to_string();
-            convert_path_to_tmpfiles_d_recurse(bufwr, &pwdb, &rootfs, &mut prefix, &cancellable)
-                .with_context(|| format!("Analyzing /{} content", prefix))?;
+    for (pkg, entries) in by_package {
+        // Use the same name as the importer, appending to any file it wrote.
+        let path = format!("usr/lib/tmpfiles.d/pkg-{}.conf", pkg);
+        let prev = rootfs.read_to_string_optional(&path)?.unwrap_or_default();
+        rootfs.write_file_with_sync(&path, 0o644, |bufwr| -> Result<()> {
+            bufwr.write_all(prev.as_bytes())?;
+            for entry in &entries {
+                writeln!(bufwr, "{}", entry)?;
+            }
             Ok(())
-        },
-    )?;
+        })?;
+        clamp_mtime(rootfs, &path)?;
+    }
+    rootfs.write_file_with_sync(AUTOVAR_PATH, 0o644, |bufwr| -> Result<()> {
+        for entry in &unowned {
+            writeln!(bufwr, "{}", entry)?;
+        }
+        Ok(())
+    })?;
+    clamp_mtime(rootfs, AUTOVAR_PATH)?;
 
     Ok(())
 }
@@ -644,7 +1478,7 @@ fn var_to_tmpfiles(rootfs: &openat::Dir, cancellable: Option<&gio::Cancellable>)
 /// `prefix` is updated at each recursive step, so that in case of errors it can be
 /// used to pinpoint the faulty path.
 fn convert_path_to_tmpfiles_d_recurse(
-    tmpfiles_bufwr: &mut BufWriter<File>,
+    entries: &mut Vec<TmpfilesEntry>,
     pwdb: &PasswdDB,
     rootfs: &openat::Dir,
     prefix: &mut String,
@@ -653,12 +1487,16 @@ fn convert_path_to_tmpfiles_d_recurse(
     use openat::SimpleType;
 
     let current_prefix = prefix.clone();
-    for subpath in rootfs.list_dir(&current_prefix)? {
+    // Sort the entries so that the output is reproducible.
+    let mut subpaths = rootfs
+        .list_dir(&current_prefix)?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    subpaths.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    for subpath in subpaths {
         if cancellable.map(|c| c.is_cancelled()).unwrap_or_default() {
             bail!("Cancelled");
         };
 
-        let subpath = subpath?;
         let fname: &Utf8Path = Path::new(subpath.file_name()).try_into()?;
         let full_path = format!("{}/{}", &current_prefix, fname);
         let path_type = subpath.simple_type().unwrap_or(SimpleType::Other);
@@ -677,39 +1515,39 @@ fn convert_path_to_tmpfiles_d_recurse(
         }
 
         let filetype_char = match path_type {
-            SimpleType::Dir => 'd',
-            SimpleType::Symlink => 'L',
-            SimpleType::File => 'f',
+            SimpleType::Dir => "d",
+            SimpleType::Symlink => "L",
+            SimpleType::File => "f",
             x => unreachable!("invalid path type: {:?}", x),
         };
-        write!(tmpfiles_bufwr, "{} ", filetype_char)?;
-        write!(tmpfiles_bufwr, "/{} ", full_path)?;
+        let mut entry = TmpfilesEntry {
+            kind: filetype_char.to_string(),
+            path: format!("/{}", full_path),
+            mode: None,
+            user: None,
+            group: None,
+            argument: None,
+        };
 
         if path_type == SimpleType::Symlink {
             let link_target = rootfs.read_link(&full_path)?;
-            write!(tmpfiles_bufwr, "- - - - ")?;
-            write!(tmpfiles_bufwr, "{}", link_target.display())?;
+            entry.argument = Some(link_target.display().to_string());
         } else {
             let meta = rootfs.metadata(&full_path)?;
-            let perm = meta.stat().st_mode & !libc::S_IFMT;
-            write!(tmpfiles_bufwr, "{:04o} ", perm)?;
-            let username = pwdb.lookup_user(meta.stat().st_uid)?;
-            write!(tmpfiles_bufwr, "{} ", username)?;
-            let groupname = pwdb.lookup_group(meta.stat().st_gid)?;
-            write!(tmpfiles_bufwr, "{} ", groupname)?;
-            write!(tmpfiles_bufwr, "- -")?;
+            entry.mode = Some(meta.stat().st_mode & !libc::S_IFMT);
+            entry.user = Some(pwdb.lookup_user(meta.stat().st_uid)?);
+            entry.group = Some(pwdb.lookup_group(meta.stat().st_gid)?);
         };
-        writeln!(tmpfiles_bufwr)?;
+        entries.push(entry);
 
         if path_type == SimpleType::Dir {
             // New subdirectory discovered, recurse into it.
             *prefix = full_path.clone();
-            convert_path_to_tmpfiles_d_recurse(tmpfiles_bufwr, pwdb, rootfs, prefix, cancellable)?;
+            convert_path_to_tmpfiles_d_recurse(entries, pwdb, rootfs, prefix, cancellable)?;
         }
 
         rootfs.remove_all(&full_path)?;
     }
-    tmpfiles_bufwr.flush()?;
     Ok(())
 }
 
@@ -852,9 +1690,16 @@ fn workaround_selinux_cross_labeling_recurse(
             workaround_selinux_cross_labeling_recurse(rootfs, prefix, cancellable)?;
         } else {
             if let Some(nonbin_name) = full_path.strip_suffix(".bin") {
-                rootfs
-                    .update_timestamps(nonbin_name)
-                    .with_context(|| format!("Updating timestamps of /{}", nonbin_name))?;
+                // In reproducible mode, keep the source newer than the
+                // compiled version without using the current time.
+                if let Some(epoch) = source_date_epoch()? {
+                    set_timestamps(rootfs, &full_path, epoch - 1)?;
+                    set_timestamps(rootfs, nonbin_name, epoch)?;
+                } else {
+                    rootfs
+                        .update_timestamps(nonbin_name)
+                        .with_context(|| format!("Updating timestamps of /{}", nonbin_name))?;
+                }
             }
         }
     }
@@ -974,10 +1819,166 @@ fn hardlink_recurse(
     Ok(())
 }
 
+/// Implementation of the treefile `hardlink-dedup` field.
+pub fn compose_hardlink_dedup(rootfs_dfd: i32, treefile: &Treefile) -> CxxResult<()> {
+    if !treefile.parsed.hardlink_dedup.unwrap_or_default() {
+        return Ok(());
+    }
+    let rootfs = crate::ffiutil::ffi_view_openat_dir(rootfs_dfd);
+    let (n_linked, saved) = hardlink_dedup(&rootfs, "usr")?;
+    println!(
+        "Hardlinked {} duplicate files, saving {} bytes",
+        n_linked, saved
+    );
+    Ok(())
+}
+
+/// Metadata which must match for two files to be hardlinked.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DedupKey {
+    size: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+#[derive(Debug)]
+struct DedupFile {
+    path: String,
+    devino: (u64, u64),
+}
+
+/// Extended attributes, sorted by name.
+type Xattrs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Call a `flistxattr()` or `fgetxattr()` style function, first to find
+/// the size of the buffer.
+fn xattr_buf(f: impl Fn(*mut u8, usize) -> isize) -> std::io::Result<Vec<u8>> {
+    loop {
+        let n = f(std::ptr::null_mut(), 0);
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; n as usize];
+        let n = f(buf.as_mut_ptr(), buf.len());
+        if n < 0 {
+            let e = std::io::Error::last_os_error();
+            // The value grew in between; try again
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(e);
+        }
+        buf.truncate(n as usize);
+        return Ok(buf);
+    }
+}
+
+fn fd_xattrs(f: &File) -> Result<Xattrs> {
+    let fd = f.as_raw_fd();
+    let names = match xattr_buf(|buf, len| unsafe {
+        libc::flistxattr(fd, buf as *mut libc::c_char, len)
+    }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("flistxattr"),
+    };
+    let mut r = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let cname = CString::new(name)?;
+        let value = xattr_buf(|buf, len| unsafe {
+            libc::fgetxattr(fd, cname.as_ptr(), buf as *mut libc::c_void, len)
+        })
+        .with_context(|| format!("fgetxattr({})", String::from_utf8_lossy(name)))?;
+        r.push((name.to_vec(), value));
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Replace byte-identical regular files under `dir` that have the same mode,
+/// ownership and xattrs with hardlinks to the first of them in sorted order.
+/// Returns the number of replaced files and the number of bytes saved in the
+/// tree; files may also be hardlinked from outside of `dir` (e.g. to the
+/// pkgcache in unified core mode), so only the links under `dir` are counted.
+#[context("Hardlinking identical files in /{}", dir)]
+fn hardlink_dedup(rootfs: &openat::Dir, dir: &str) -> Result<(u64, u64)> {
+    use openat::SimpleType;
+
+    // First group by metadata, which is cheap.
+    let mut candidates: BTreeMap<DedupKey, Vec<DedupFile>> = BTreeMap::new();
+    // The number of links to each inode under `dir`.
+    let mut links: HashMap<(u64, u64), u64> = HashMap::new();
+    crate::lint::walk(rootfs, dir, &mut |path, meta| {
+        if meta.simple_type() != SimpleType::File || meta.len() == 0 {
+            return Ok(());
+        }
+        let st = meta.stat();
+        let key = DedupKey {
+            size: meta.len(),
+            mode: st.st_mode,
+            uid: st.st_uid,
+            gid: st.st_gid,
+        };
+        let devino = (st.st_dev as u64, st.st_ino as u64);
+        *links.entry(devino).or_default() += 1;
+        candidates.entry(key).or_default().push(DedupFile {
+            path: path.to_string(),
+            devino,
+        });
+        Ok(())
+    })?;
+
+    let mut n_linked = 0;
+    let mut saved = 0;
+    // The content of each inode we've looked at, and how many of its links
+    // we replaced.
+    let mut contents: HashMap<(u64, u64), (String, Xattrs)> = HashMap::new();
+    let mut replaced: HashMap<(u64, u64), u64> = HashMap::new();
+    for (key, files) in candidates {
+        if files.iter().all(|f| f.devino == files[0].devino) {
+            continue;
+        }
+        // Then by content and xattrs.
+        let mut canonical: HashMap<(String, Xattrs), &DedupFile> = HashMap::new();
+        for f in files.iter() {
+            let content = match contents.entry(f.devino) {
+                Entry::Occupied(e) => e.get().clone(),
+                Entry::Vacant(e) => {
+                    let fd = rootfs.open_file(f.path.as_str())?;
+                    let mut hasher = glib::Checksum::new(glib::ChecksumType::Sha256);
+                    crate::treefile::hash_file(&mut hasher, &fd)?;
+                    let digest = hasher.get_string().expect("hash");
+                    e.insert((digest, fd_xattrs(&fd)?)).clone()
+                }
+            };
+            let target = match canonical.entry(content) {
+                Entry::Vacant(e) => {
+                    e.insert(f);
+                    continue;
+                }
+                Entry::Occupied(e) => *e.get(),
+            };
+            if target.devino == f.devino {
+                continue;
+            }
+            let tmp = format!("{}.rpmostree-dedup", f.path);
+            openat::hardlink(rootfs, target.path.as_str(), rootfs, tmp.as_str())?;
+            rootfs.local_rename(tmp.as_str(), f.path.as_str())?;
+            n_linked += 1;
+            let count = replaced.entry(f.devino).or_default();
+            *count += 1;
+            if *count == links[&f.devino] {
+                saved += key.size;
+            }
+        }
+    }
+    Ok((n_linked, saved))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashSet;
 
     #[test]
     fn stripany() {
@@ -989,9 +1990,7 @@ mod tests {
         );
     }
 
-    #[test]
-    fn altfiles_replaced() {
-        let orig = r##"# blah blah nss stuff
+    static NSSWITCH_ORIG: &str = r##"# blah blah nss stuff
 # more blah blah
 
 # passwd: db files
@@ -1004,6 +2003,10 @@ group:      sss files systemd
 hosts:      files resolve [!UNAVAIL=return] myhostname dns
 automount:  files sss
 "##;
+
+    #[test]
+    fn altfiles_replaced() {
+        let orig = NSSWITCH_ORIG;
         let expected = r##"# blah blah nss stuff
 # more blah blah
 
@@ -1023,6 +2026,94 @@ automount:  files sss
         assert_eq!(replaced2.as_str(), expected);
     }
 
+    #[test]
+    fn nss_systemd_added() {
+        // Already there
+        let replaced = add_nss_module(NSSWITCH_ORIG, "systemd").unwrap();
+        assert_eq!(replaced.as_str(), NSSWITCH_ORIG);
+
+        let orig = NSSWITCH_ORIG.replace(" systemd", "");
+        let expected = NSSWITCH_ORIG
+            .replace("passwd:     sss files systemd", "passwd: sss files systemd")
+            .replace("group:      sss files systemd", "group: sss files systemd");
+        let replaced = add_nss_module(&orig, "systemd").unwrap();
+        assert_eq!(replaced, expected);
+        let replaced2 = add_nss_module(&replaced, "systemd").unwrap();
+        assert_eq!(replaced2, expected);
+    }
+
+    #[test]
+    fn test_write_userdb_records() -> Result<()> {
+        let temp_rootfs = tempfile::tempdir()?;
+        let rootfs = openat::Dir::open(temp_rootfs.path())?;
+        rootfs.ensure_dir_all("usr/lib", 0o755)?;
+        rootfs.write_file_contents(
+            "usr/lib/passwd",
+            0o644,
+            "bin:x:1:1:bin:/bin:/sbin/nologin\nfoo:x:990:990::/:/sbin/nologin\n",
+        )?;
+        rootfs.write_file_contents("usr/lib/group", 0o644, "bin:x:1:\nfoo:x:990:bin\n")?;
+        write_userdb_records(&rootfs)?;
+        let read = |path: &str| -> Result<serde_json::Value> {
+            let path = format!("{}/{}", USERDB_DIR, path);
+            Ok(serde_json::from_str(
+                &rootfs.read_to_string(path.as_str())?,
+            )?)
+        };
+        assert_eq!(
+            read("990.user")?,
+            serde_json::json!({
+                "userName": "foo",
+                "uid": 990,
+                "gid": 990,
+                "homeDirectory": "/",
+                "shell": "/sbin/nologin",
+                "disposition": "system",
+            })
+        );
+        assert_eq!(read("bin.user")?["realName"], "bin");
+        assert_eq!(
+            read("foo.group")?,
+            serde_json::json!({
+                "groupName": "foo",
+                "gid": 990,
+                "members": ["bin"],
+                "disposition": "system",
+            })
+        );
+        assert!(read("bin.group")?.get("members").is_none());
+
+        // IDs changed
+        rootfs.write_file_contents(
+            "usr/lib/passwd",
+            0o644,
+            "bin:x:1:1:bin:/bin:/sbin/nologin\nfoo:x:980:980::/:/sbin/nologin\n",
+        )?;
+        rootfs.write_file_contents("usr/lib/group", 0o644, "bin:x:1:\nfoo:x:980:bin\n")?;
+        write_userdb_records(&rootfs)?;
+        assert_eq!(read("980.user")?["uid"], 980);
+        assert_eq!(read("980.group")?["gid"], 980);
+        assert!(!rootfs.exists(format!("{}/990.user", USERDB_DIR).as_str())?);
+
+        // A user and group removed, and a record we don't own
+        let exists = |path: &str| rootfs.exists(format!("{}/{}", USERDB_DIR, path).as_str());
+        rootfs.write_file_contents(format!("{}/other.user", USERDB_DIR), 0o644, "{}\n")?;
+        rootfs.write_file_contents(
+            "usr/lib/passwd",
+            0o644,
+            "bin:x:1:1:bin:/bin:/sbin/nologin\n",
+        )?;
+        rootfs.write_file_contents("usr/lib/group", 0o644, "bin:x:1:\n")?;
+        write_userdb_records(&rootfs)?;
+        for removed in &["foo.user", "foo.group", "980.user", "980.group"] {
+            assert!(!exists(removed)?, "{}", removed);
+        }
+        for kept in &["bin.user", "1.user", "bin.group", "1.group", "other.user"] {
+            assert!(exists(kept)?, "{}", kept);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_mutate_os_release() {
         let orig = r##"NAME=Fedora
@@ -1046,6 +2137,26 @@ OSTREE_VERSION='33.4'
         assert_eq!(replaced.as_str(), expected);
     }
 
+    #[test]
+    fn test_override_os_release() {
+        let orig = r##"NAME=Fedora
+VARIANT_ID=container
+ID=fedora
+VARIANT_ID=duplicate
+"##;
+        let expected = r##"NAME=Fedora
+VARIANT_ID='coreos'
+ID=fedora
+IMAGE_VERSION='33.4'
+"##;
+        let overrides = &[
+            ("VARIANT_ID", "coreos".to_string()),
+            ("IMAGE_VERSION", "33.4".to_string()),
+        ];
+        let replaced = override_os_release_contents(orig, overrides);
+        assert_eq!(replaced.as_str(), expected);
+    }
+
     #[test]
     fn test_init_rootfs() -> Result<()> {
         {
@@ -1066,12 +2177,11 @@ OSTREE_VERSION='33.4'
         Ok(())
     }
 
-    #[test]
-    fn test_tmpfiles_d_translation() {
+    /// Prepare a minimal rootfs as playground for tmpfiles.d translation.
+    fn tmpfiles_rootfs() -> (tempfile::TempDir, openat::Dir) {
         use nix::sys::stat::{umask, Mode};
         use nix::unistd::{getegid, geteuid};
 
-        // Prepare a minimal rootfs as playground.
         umask(Mode::empty());
         let temp_rootfs = tempfile::tempdir().unwrap();
         let rootfs = openat::Dir::open(temp_rootfs.path()).unwrap();
@@ -1097,6 +2207,12 @@ OSTREE_VERSION='33.4'
                 )
                 .unwrap();
         }
+        (temp_rootfs, rootfs)
+    }
+
+    #[test]
+    fn test_tmpfiles_d_translation() {
+        let (_temp_rootfs, rootfs) = tmpfiles_rootfs();
 
         // Add test content.
         rootfs.ensure_dir_all("var/lib/systemd", 0o755).unwrap();
@@ -1111,7 +2227,7 @@ OSTREE_VERSION='33.4'
             .symlink("var/lib/test/nested/symlink", "../")
             .unwrap();
 
-        var_to_tmpfiles(&rootfs, gio::NONE_CANCELLABLE).unwrap();
+        var_to_tmpfiles(&rootfs, &HashMap::new(), gio::NONE_CANCELLABLE).unwrap();
 
         let autovar_path = "usr/lib/tmpfiles.d/rpm-ostree-1-autovar.conf";
         assert!(!rootfs.exists("var/lib").unwrap());
@@ -1135,6 +2251,152 @@ OSTREE_VERSION='33.4'
             assert!(entries.contains(*line), "{:#?}", entries);
         }
         assert_eq!(entries.len(), expected.len(), "{:#?}", entries);
+
+        // The entries are written depth-first, in sorted order
+        let paths: Vec<String> = rootfs
+            .read_to_string(autovar_path)
+            .unwrap()
+            .lines()
+            .map(|s| s.split(' ').nth(1).unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            paths,
+            &[
+                "/var/lib",
+                "/var/lib/nfs",
+                "/var/lib/nfs/etab",
+                "/var/lib/systemd",
+                "/var/lib/test",
+                "/var/lib/test/nested",
+                "/var/lib/test/nested/symlink",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tmpfiles_entry() {
+        let e = TmpfilesEntry::parse("d /var/lib/foo ~0750 foo - 10d").unwrap();
+        assert_eq!(e.kind, "d");
+        assert_eq!(e.mode, Some(0o750));
+        assert_eq!(e.user.as_deref(), Some("foo"));
+        assert_eq!(e.group, None);
+        assert_eq!(e.argument, None);
+        assert!(TmpfilesEntry::parse("# comment").is_none());
+        assert!(TmpfilesEntry::parse("  ").is_none());
+        assert!(TmpfilesEntry::parse("d").is_none());
+        let l = TmpfilesEntry::parse("L+ /var/foo - - - - ../bar baz").unwrap();
+        assert_eq!(l.file_type(), Some('L'));
+        assert_eq!(l.argument.as_deref(), Some("../bar baz"));
+        assert_eq!(l.to_string(), "L+ /var/foo - - - - ../bar baz");
+
+        let d = TmpfilesEntry::parse("d /var/foo 0755 root root - -").unwrap();
+        let same = TmpfilesEntry::parse("D /var/foo - root").unwrap();
+        assert_eq!(d.conflict(&same), None);
+        let modes = TmpfilesEntry::parse("d /var/foo 0700 root root").unwrap();
+        assert_eq!(d.conflict(&modes), Some("mode"));
+        let owner = TmpfilesEntry::parse("d /var/foo - bin").unwrap();
+        assert_eq!(d.conflict(&owner), Some("user"));
+        assert_eq!(d.conflict(&l), Some("type"));
+        let relabel = TmpfilesEntry::parse("Z /var/foo 0600 bin bin").unwrap();
+        assert_eq!(d.conflict(&relabel), None);
+    }
+
+    #[test]
+    fn test_tmpfiles_d_packages() {
+        let (_temp_rootfs, rootfs) = tmpfiles_rootfs();
+        rootfs.ensure_dir_all("usr/lib/tmpfiles.d", 0o755).unwrap();
+        rootfs
+            .write_file_contents(
+                "usr/lib/tmpfiles.d/bar.conf",
+                0o644,
+                "d /var/lib/bar 0755 - - -\n",
+            )
+            .unwrap();
+        rootfs
+            .write_file_contents(
+                "usr/lib/tmpfiles.d/pkg-foo.conf",
+                0o644,
+                "d /var/cache/foo 0755 root root - -\n",
+            )
+            .unwrap();
+        for d in &["var/lib/foo/data", "var/lib/bar", "var/lib/other"] {
+            rootfs.ensure_dir_all(*d, 0o755).unwrap();
+        }
+        let owners: HashMap<String, String> = vec![
+            ("/var/lib/foo", "foo"),
+            ("/var/lib/foo/data", "foo"),
+            ("/var/lib/bar", "bar"),
+        ]
+        .into_iter()
+        .map(|(p, n)| (p.to_string(), n.to_string()))
+        .collect();
+
+        var_to_tmpfiles(&rootfs, &owners, gio::NONE_CANCELLABLE).unwrap();
+
+        assert_eq!(
+            rootfs
+                .read_to_string("usr/lib/tmpfiles.d/pkg-foo.conf")
+                .unwrap(),
+            "d /var/cache/foo 0755 root root - -
+d /var/lib/foo 0755 test-user test-group - -
+d /var/lib/foo/data 0755 test-user test-group - -
+"
+        );
+        // Already provided by bar.conf
+        assert!(!rootfs.exists("usr/lib/tmpfiles.d/pkg-bar.conf").unwrap());
+        assert_eq!(
+            rootfs
+                .read_to_string("usr/lib/tmpfiles.d/rpm-ostree-1-autovar.conf")
+                .unwrap(),
+            "d /var/lib 0755 test-user test-group - -
+d /var/lib/other 0755 test-user test-group - -
+"
+        );
+
+        // Conflicting package entries are an error
+        let (_temp_rootfs, rootfs) = tmpfiles_rootfs();
+        rootfs.ensure_dir_all("usr/lib/tmpfiles.d", 0o755).unwrap();
+        rootfs
+            .write_file_contents(
+                "usr/lib/tmpfiles.d/bar.conf",
+                0o644,
+                "d /var/lib/bar 0700 - - -\nL /var/lib/baz - - - - /foo\n",
+            )
+            .unwrap();
+        rootfs.ensure_dir_all("var/lib/bar", 0o755).unwrap();
+        rootfs.ensure_dir_all("var/lib/baz", 0o755).unwrap();
+        let err = var_to_tmpfiles(&rootfs, &owners, gio::NONE_CANCELLABLE).unwrap_err();
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("2 conflicting tmpfiles.d entries"), "{}", msg);
+        assert!(msg.contains("/var/lib/bar: mode differs"), "{}", msg);
+        assert!(msg.contains("/var/lib/baz: type differs"), "{}", msg);
+    }
+
+    #[test]
+    fn test_unit_exists() {
+        let temp_rootfs = tempfile::tempdir().unwrap();
+        let rootfs = openat::Dir::open(temp_rootfs.path()).unwrap();
+        rootfs
+            .ensure_dir_all("usr/lib/systemd/system", 0o755)
+            .unwrap();
+        rootfs
+            .ensure_dir_all("usr/etc/systemd/system", 0o755)
+            .unwrap();
+        rootfs
+            .write_file_contents("usr/lib/systemd/system/foo.service", 0o644, "")
+            .unwrap();
+        rootfs
+            .write_file_contents("usr/lib/systemd/system/getty@.service", 0o644, "")
+            .unwrap();
+        rootfs
+            .symlink("usr/etc/systemd/system/bar.service", "/nonexistent")
+            .unwrap();
+        for unit in &["foo.service", "getty@tty1.service", "bar.service"] {
+            assert!(unit_exists(&rootfs, unit).unwrap(), "{}", unit);
+        }
+        for unit in &["baz.service", "foo@tty1.service", "getty@.socket"] {
+            assert!(!unit_exists(&rootfs, unit).unwrap(), "{}", unit);
+        }
     }
 
     #[test]
@@ -1236,6 +2498,50 @@ OSTREE_VERSION='33.4'
         assert_eq!(&sysimage_link, Path::new("../../share/rpm"));
     }
 
+    #[test]
+    fn test_hardlink_dedup() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let rootfs = &openat::Dir::open(td.path())?;
+        rootfs.ensure_dir_all("usr/lib/a", 0o755)?;
+        rootfs.ensure_dir_all("usr/lib/b", 0o755)?;
+        rootfs.ensure_dir_all("etc", 0o755)?;
+        let content = "x".repeat(100);
+        for path in &["usr/lib/a/foo", "usr/lib/b/foo", "usr/lib/b/bar", "etc/foo"] {
+            rootfs.write_file_contents(*path, 0o644, &content)?;
+        }
+        // Different mode, content or empty: not linked
+        rootfs.write_file_contents("usr/lib/b/exec", 0o755, &content)?;
+        rootfs.write_file_contents("usr/lib/b/other", 0o644, "y".repeat(100))?;
+        rootfs.write_file_contents("usr/lib/a/empty", 0o644, "")?;
+        rootfs.write_file_contents("usr/lib/b/empty", 0o644, "")?;
+        // An existing hardlink to a duplicate
+        openat::hardlink(rootfs, "usr/lib/b/foo", rootfs, "usr/lib/b/foo2")?;
+        // A duplicate also linked from outside of the tree, like the pkgcache
+        rootfs.write_file_contents("usr/lib/b/cached", 0o644, &content)?;
+        rootfs.ensure_dir_all("cache", 0o755)?;
+        openat::hardlink(rootfs, "usr/lib/b/cached", rootfs, "cache/cached")?;
+
+        assert_eq!(hardlink_dedup(rootfs, "usr")?, (4, 300));
+        let ino = |p: &str| rootfs.metadata(p).unwrap().stat().st_ino;
+        let first = ino("usr/lib/a/foo");
+        for p in &[
+            "usr/lib/b/foo",
+            "usr/lib/b/foo2",
+            "usr/lib/b/bar",
+            "usr/lib/b/cached",
+        ] {
+            assert_eq!(ino(p), first, "{}", p);
+        }
+        for p in &["etc/foo", "usr/lib/b/exec", "usr/lib/b/other"] {
+            assert_ne!(ino(p), first, "{}", p);
+        }
+        assert_ne!(ino("usr/lib/a/empty"), ino("usr/lib/b/empty"));
+        assert_eq!(rootfs.read_to_string("usr/lib/b/bar")?, content);
+        // Idempotent
+        assert_eq!(hardlink_dedup(rootfs, "usr")?, (0, 0));
+        Ok(())
+    }
+
     #[test]
     fn test_postprocess_rpm_macro() {
         static MACRO_PATH: &str = "usr/lib/rpm/macros.d/macros.rpm-ostree";
@@ -1260,4 +2566,210 @@ OSTREE_VERSION='33.4'
             assert_eq!(content, expected_content);
         }
     }
+
+    #[test]
+    fn test_add_paths() -> Result<()> {
+        let temp_rootfs = tempfile::tempdir()?;
+        let rootfs = openat::Dir::open(temp_rootfs.path())?;
+        // Map a user and group to ourselves, so that chown works unprivileged.
+        let uid = nix::unistd::getuid();
+        let gid = nix::unistd::getgid();
+        // The layout within `prepare_tempetc_guard`
+        for dir in &["etc", "usr/lib"] {
+            rootfs.ensure_dir_all(*dir, 0o755)?;
+        }
+        rootfs.symlink("usr/etc", "../etc")?;
+        rootfs.write_file_contents(
+            "etc/passwd",
+            0o644,
+            format!("core:x:{}:{}::/var/home/core:/bin/bash\n", uid, gid),
+        )?;
+        rootfs.write_file_contents("etc/group", 0o644, format!("core:x:{}:\n", gid))?;
+        rootfs.write_file_contents("usr/lib/passwd", 0o644, "")?;
+        rootfs.write_file_contents("usr/lib/group", 0o644, "")?;
+
+        let paths: Vec<AddPath> = serde_yaml::from_str(indoc::indoc! {r#"
+            - type: directory
+              path: /usr/lib/foo
+              mode: "0750"
+              user: core
+              group: core
+            - type: file
+              path: /etc/foo.conf
+              mode: "0600"
+              content: "foo=bar\n"
+            - type: directory
+              path: /etc/foo.d
+            - type: symlink
+              path: /usr/lib/foo/foo.conf
+              target: ../../etc/foo.conf
+        "#})?;
+        add_paths(&rootfs, &paths)?;
+        // Check idempotency
+        add_paths(&rootfs, &paths)?;
+
+        let meta = rootfs.metadata("usr/lib/foo")?;
+        assert!(meta.is_dir());
+        assert_eq!(meta.stat().st_mode & 0o7777, 0o750);
+        assert_eq!(meta.stat().st_uid, uid.as_raw());
+        assert_eq!(meta.stat().st_gid, gid.as_raw());
+        let meta = rootfs.metadata("etc/foo.conf")?;
+        assert_eq!(meta.stat().st_mode & 0o7777, 0o600);
+        assert_eq!(rootfs.read_to_string("etc/foo.conf")?, "foo=bar\n");
+        assert!(rootfs.metadata("etc/foo.d")?.is_dir());
+        assert_eq!(
+            rootfs.read_link("usr/lib/foo/foo.conf")?,
+            Path::new("../../etc/foo.conf")
+        );
+
+        let paths: Vec<AddPath> = serde_yaml::from_str(indoc::indoc! {r#"
+            - type: directory
+              path: /usr/lib/bar
+              user: nosuchuser
+        "#})?;
+        let e = add_paths(&rootfs, &paths).err().unwrap();
+        assert!(format!("{:#}", e).contains("failed to find user 'nosuchuser'"));
+
+        // Symlinks, e.g. absolute ones pointing outside of the rootfs, aren't followed
+        let outside = tempfile::tempdir()?;
+        let outside_path = outside.path().to_str().unwrap();
+        rootfs.symlink("usr/lib/outside", outside_path)?;
+        let outside_conf = outside.path().join("foo.conf");
+        rootfs.symlink("usr/lib/outside.conf", &outside_conf)?;
+        std::fs::write(&outside_conf, "foo")?;
+        let orig_mode = std::fs::metadata(&outside_conf)?.permissions().mode();
+        for (path, msg) in &[
+            (
+                "/usr/lib/outside/bar.conf",
+                "Refusing to follow symlink usr/lib/outside",
+            ),
+            ("/usr/lib/outside.conf", "Content already exists at path"),
+        ] {
+            let paths: Vec<AddPath> = serde_yaml::from_str(&format!(
+                "- {{type: file, path: {}, mode: \"0777\", content: bar}}\n",
+                path
+            ))?;
+            let e = add_paths(&rootfs, &paths).err().unwrap();
+            assert!(format!("{:#}", e).contains(msg), "{:#}", e);
+        }
+        assert!(!outside.path().join("bar.conf").exists());
+        let meta = std::fs::metadata(&outside_conf)?;
+        assert_eq!(meta.permissions().mode(), orig_mode);
+        assert_eq!(std::fs::read_to_string(&outside_conf)?, "foo");
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let cases = &[
+            ("*", "foo", true),
+            ("*", ".foo", false),
+            (".*", ".foo", true),
+            ("*.conf", "foo.conf", true),
+            ("*.conf", "foo.conf.rpmnew", false),
+            ("foo?", "foo1", true),
+            ("foo?", "foo", false),
+            ("[a-c]*", "bar", true),
+            ("[a-c]*", "dar", false),
+            ("[!a-c]*", "dar", true),
+            ("[]]", "]", true),
+            ("[foo", "[foo", true),
+            ("foo", "foo", true),
+        ];
+        for &(pattern, name, expected) in cases {
+            assert_eq!(glob_match(pattern, name), expected, "{} {}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn test_postprocess_ops() -> Result<()> {
+        let temp_rootfs = tempfile::tempdir()?;
+        let rootfs = openat::Dir::open(temp_rootfs.path())?;
+        rootfs.ensure_dir_all("usr/share/doc/foo", 0o755)?;
+        rootfs.ensure_dir_all("usr/share/doc/bar", 0o755)?;
+        rootfs.write_file_contents("usr/share/doc/foo/README", 0o644, "foo")?;
+        rootfs.write_file_contents("usr/share/doc/bar/README", 0o644, "bar")?;
+        rootfs.write_file_contents("usr/share/doc/bar/.keep", 0o644, "")?;
+        rootfs.symlink("usr/share/doc/baz", "foo")?;
+        rootfs.ensure_dir_all("usr/bin", 0o755)?;
+        rootfs.write_file_contents("usr/bin/foo", 0o755, "#!/bin/sh")?;
+
+        let ops: Vec<PostprocessOp> = serde_yaml::from_str(indoc::indoc! {r#"
+            - type: delete
+              glob: /usr/share/doc/*/README
+            - type: chmod
+              path: /usr/bin/foo
+              mode: "0700"
+            - type: write
+              path: /usr/lib/foo/foo.conf
+              content: "foo=bar\n"
+            - type: symlink
+              path: /usr/lib/foo/bar.conf
+              target: foo.conf
+        "#})?;
+        for op in ops.iter() {
+            postprocess_op(&rootfs, op)?;
+        }
+        assert!(!rootfs.exists("usr/share/doc/foo/README")?);
+        assert!(!rootfs.exists("usr/share/doc/bar/README")?);
+        assert!(rootfs.exists("usr/share/doc/bar/.keep")?);
+        assert!(rootfs.exists("usr/share/doc/baz")?);
+        let meta = rootfs.metadata("usr/bin/foo")?;
+        assert_eq!(meta.stat().st_mode & 0o7777, 0o700);
+        let meta = rootfs.metadata("usr/lib/foo/foo.conf")?;
+        assert_eq!(meta.stat().st_mode & 0o7777, 0o644);
+        assert_eq!(rootfs.read_to_string("usr/lib/foo/foo.conf")?, "foo=bar\n");
+        assert_eq!(
+            rootfs.read_link("usr/lib/foo/bar.conf")?,
+            Path::new("foo.conf")
+        );
+
+        // Symlinks aren't followed in the middle of a glob
+        assert!(expand_glob(&rootfs, "usr/share/doc/baz/*")?.is_empty());
+        assert_eq!(
+            expand_glob(&rootfs, "usr/share/doc/*")?,
+            vec![
+                Utf8PathBuf::from("usr/share/doc/bar"),
+                Utf8PathBuf::from("usr/share/doc/baz"),
+                Utf8PathBuf::from("usr/share/doc/foo"),
+            ]
+        );
+
+        let op = PostprocessOp::Chmod {
+            path: "/usr/bin/nosuchfile".into(),
+            mode: "0755".into(),
+        };
+        assert!(postprocess_op(&rootfs, &op).is_err());
+
+        // Symlinks, e.g. absolute ones pointing outside of the rootfs, aren't followed
+        let outside = tempfile::tempdir()?;
+        let outside_conf = outside.path().join("foo.conf");
+        std::fs::write(&outside_conf, "foo")?;
+        let orig_mode = std::fs::metadata(&outside_conf)?.permissions().mode();
+        rootfs.symlink("usr/lib/outside", outside.path())?;
+        rootfs.symlink("usr/bin/outside", &outside_conf)?;
+        let ops: Vec<PostprocessOp> = serde_yaml::from_str(indoc::indoc! {r#"
+            - type: chmod
+              path: /usr/bin/outside
+              mode: "0777"
+            - type: chmod
+              path: /usr/lib/outside/foo.conf
+              mode: "0777"
+            - type: write
+              path: /usr/lib/outside/foo.conf
+              content: bar
+            - type: symlink
+              path: /usr/lib/outside/bar.conf
+              target: foo.conf
+        "#})?;
+        for op in ops.iter() {
+            let e = postprocess_op(&rootfs, op).err().unwrap().to_string();
+            assert!(e.starts_with("Refusing to"), "{}", e);
+        }
+        let meta = std::fs::metadata(&outside_conf)?;
+        assert_eq!(meta.permissions().mode(), orig_mode);
+        assert_eq!(std::fs::read_to_string(&outside_conf)?, "foo");
+        assert!(!outside.path().join("bar.conf").exists());
+        Ok(())
+    }
 }
@@ -8,6 +8,7 @@
 
 use anyhow::{bail, Context, Result};
 use openat_ext::OpenatDirExt;
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -17,7 +18,7 @@ use crate::utils;
 
 const RPMOSTREE_EXTENSIONS_STATE_FILE: &str = ".rpm-ostree-state-chksum";
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Extensions {
     extensions: HashMap<String, Extension>,
@@ -25,7 +26,7 @@ pub struct Extensions {
     repos: Option<Vec<String>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Extension {
     packages: Vec<String>,
@@ -37,7 +38,7 @@ pub struct Extension {
     kind: ExtensionKind,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 enum ExtensionKind {
     OsExtension,
@@ -51,11 +52,12 @@ impl Default for ExtensionKind {
 }
 
 fn extensions_load_stream(
+    fmt: &utils::InputFormat,
     stream: &mut impl std::io::Read,
     basearch: &str,
     base_pkgs: &[StringMapping],
 ) -> Result<Box<Extensions>> {
-    let mut parsed: Extensions = serde_yaml::from_reader(stream)?;
+    let mut parsed: Extensions = utils::parse_stream(fmt, stream)?;
 
     parsed.extensions.retain(|_, ext| {
         ext.architectures
@@ -98,9 +100,14 @@ pub(crate) fn extensions_load(
     basearch: &str,
     base_pkgs: &Vec<StringMapping>,
 ) -> CxxResult<Box<Extensions>> {
+    // This has historically always been parsed as YAML (which JSON is a subset of)
+    let fmt = match utils::InputFormat::detect_from_filename(path)? {
+        utils::InputFormat::TOML => utils::InputFormat::TOML,
+        _ => utils::InputFormat::YAML,
+    };
     let f = utils::open_file(path)?;
     let mut f = std::io::BufReader::new(f);
-    Ok(extensions_load_stream(&mut f, basearch, base_pkgs)
+    Ok(extensions_load_stream(&fmt, &mut f, basearch, base_pkgs)
         .with_context(|| format!("parsing {}", path))?)
 }
 
@@ -181,7 +188,13 @@ extensions:
             - bazboo
 "###;
         let mut input = std::io::BufReader::new(buf.as_bytes());
-        let extensions = extensions_load_stream(&mut input, "x86_64", &base_rpmdb()).unwrap();
+        let extensions = extensions_load_stream(
+            &utils::InputFormat::YAML,
+            &mut input,
+            "x86_64",
+            &base_rpmdb(),
+        )
+        .unwrap();
         assert!(extensions.get_repos() == vec!["my-repo"]);
         assert!(extensions.get_os_extension_packages() == vec!["bazboo"]);
         assert!(extensions.get_development_packages().is_empty());
@@ -196,7 +209,12 @@ extensions:
             - foobar
 "###;
         let mut input = std::io::BufReader::new(buf.as_bytes());
-        match extensions_load_stream(&mut input, "x86_64", &base_rpmdb()) {
+        match extensions_load_stream(
+            &utils::InputFormat::YAML,
+            &mut input,
+            "x86_64",
+            &base_rpmdb(),
+        ) {
             Ok(_) => panic!("expected failure from extension in base"),
             Err(ref e) => assert!(e.to_string() == "package foobar already present in base"),
         }
@@ -212,7 +230,13 @@ extensions:
         kind: development
 "###;
         let mut input = std::io::BufReader::new(buf.as_bytes());
-        let extensions = extensions_load_stream(&mut input, "x86_64", &base_rpmdb()).unwrap();
+        let extensions = extensions_load_stream(
+            &utils::InputFormat::YAML,
+            &mut input,
+            "x86_64",
+            &base_rpmdb(),
+        )
+        .unwrap();
         assert!(extensions.get_os_extension_packages().is_empty());
         assert!(extensions.get_development_packages() == vec!["foobar"]);
     }
@@ -234,13 +258,50 @@ extensions:
             - s390x
 "###;
         let mut input = std::io::BufReader::new(buf.as_bytes());
-        let extensions = extensions_load_stream(&mut input, "x86_64", &base_rpmdb()).unwrap();
+        let extensions = extensions_load_stream(
+            &utils::InputFormat::YAML,
+            &mut input,
+            "x86_64",
+            &base_rpmdb(),
+        )
+        .unwrap();
         assert!(extensions.get_os_extension_packages() == vec!["bazboo"]);
         let mut input = std::io::BufReader::new(buf.as_bytes());
-        let extensions = extensions_load_stream(&mut input, "s390x", &base_rpmdb()).unwrap();
+        let extensions = extensions_load_stream(
+            &utils::InputFormat::YAML,
+            &mut input,
+            "s390x",
+            &base_rpmdb(),
+        )
+        .unwrap();
         assert!(extensions.get_os_extension_packages() == vec!["dodo", "dada"]);
     }
 
+    #[test]
+    fn toml() {
+        let buf = r###"
+repos = ["my-repo"]
+
+[extensions.bazboo]
+packages = ["bazboo"]
+
+[extensions.devel]
+packages = ["foobar-devel"]
+kind = "development"
+"###;
+        let mut input = std::io::BufReader::new(buf.as_bytes());
+        let extensions = extensions_load_stream(
+            &utils::InputFormat::TOML,
+            &mut input,
+            "x86_64",
+            &base_rpmdb(),
+        )
+        .unwrap();
+        assert!(extensions.get_repos() == vec!["my-repo"]);
+        assert!(extensions.get_os_extension_packages() == vec!["bazboo"]);
+        assert!(extensions.get_development_packages() == vec!["foobar-devel"]);
+    }
+
     #[test]
     fn matching_evr() {
         let buf = r###"
@@ -257,7 +318,13 @@ extensions:
         kind: development
 "###;
         let mut input = std::io::BufReader::new(buf.as_bytes());
-        let extensions = extensions_load_stream(&mut input, "x86_64", &base_rpmdb()).unwrap();
+        let extensions = extensions_load_stream(
+            &utils::InputFormat::YAML,
+            &mut input,
+            "x86_64",
+            &base_rpmdb(),
+        )
+        .unwrap();
         assert!(extensions.get_os_extension_packages() == vec!["foobar-ext-1.2-3"]);
         assert!(extensions.get_development_packages() == vec!["foobar-devel-1.2-3"]);
     }
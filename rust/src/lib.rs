@@ -145,6 +145,7 @@ pub mod ffi {
             treefile: &mut Treefile,
         ) -> Result<()>;
         fn composepost_nsswitch_altfiles(rootfs_dfd: i32) -> Result<()>;
+        fn composepost_nsswitch_userdb(rootfs_dfd: i32) -> Result<()>;
         fn compose_postprocess(
             rootfs_dfd: i32,
             treefile: &mut Treefile,
@@ -154,6 +155,7 @@ pub mod ffi {
         fn compose_postprocess_final(rootfs_dfd: i32) -> Result<()>;
         fn convert_var_to_tmpfiles_d(
             rootfs_dfd: i32,
+            packages: Pin<&mut CxxGObjectArray>,
             cancellable: Pin<&mut GCancellable>,
         ) -> Result<()>;
         fn rootfs_prepare_links(rootfs_dfd: i32) -> Result<()>;
@@ -166,6 +168,7 @@ pub mod ffi {
             cancellable: Pin<&mut GCancellable>,
         ) -> Result<()>;
         fn compose_postprocess_rpm_macro(rootfs_dfd: i32) -> Result<()>;
+        fn compose_hardlink_dedup(rootfs_dfd: i32, treefile: &Treefile) -> Result<()>;
     }
 
     // A grab-bag of metadata from the deployment's ostree commit
@@ -270,14 +273,26 @@ pub mod ffi {
         type Treefile;
 
         fn treefile_new(filename: &str, basearch: &str, workdir: i32) -> Result<Box<Treefile>>;
+        fn treefile_new_with_variables(
+            filename: &str,
+            basearch: &str,
+            workdir: i32,
+            variables: &Vec<String>,
+        ) -> Result<Box<Treefile>>;
+        fn describe_input_digest_changes(
+            previous: &Vec<StringMapping>,
+            current: &Vec<StringMapping>,
+        ) -> Vec<String>;
 
         fn get_workdir(&self) -> i32;
         fn get_passwd_fd(&mut self) -> i32;
         fn get_group_fd(&mut self) -> i32;
         fn get_json_string(&self) -> String;
+        fn get_json_string_with_provenance(&self) -> Result<String>;
         fn get_ostree_layers(&self) -> Vec<String>;
         fn get_ostree_override_layers(&self) -> Vec<String>;
         fn get_all_ostree_layers(&self) -> Vec<String>;
+        fn has_size_budgets(&self) -> bool;
         fn get_repos(&self) -> Vec<String>;
         fn get_packages(&self) -> Vec<String>;
         fn get_exclude_packages(&self) -> Vec<String>;
@@ -295,7 +310,9 @@ pub mod ffi {
         fn get_files_remove_regex(&self, package: &str) -> Vec<String>;
         fn print_deprecation_warnings(&self);
         fn sanitycheck_externals(&self) -> Result<()>;
+        fn validate_strict(&self) -> Result<()>;
         fn get_checksum(&self, repo: Pin<&mut OstreeRepo>) -> Result<String>;
+        fn get_input_digests(&self, repo: Pin<&mut OstreeRepo>) -> Result<Vec<StringMapping>>;
         fn get_ostree_ref(&self) -> String;
         fn get_repo_packages(&self) -> &[RepoPackage];
         fn clear_repo_packages(&mut self);
@@ -315,6 +332,7 @@ pub mod ffi {
         fn get_features() -> Vec<String>;
         fn sealed_memfd(description: &str, content: &[u8]) -> Result<i32>;
         fn running_in_systemd() -> bool;
+        fn get_source_date_epoch() -> Result<i64>;
         fn calculate_advisories_diff(
             repo: Pin<&mut OstreeRepo>,
             checksum_from: &str,
@@ -348,6 +366,16 @@ pub mod ffi {
         ) -> Result<()>;
     }
 
+    // lint.rs
+    extern "Rust" {
+        fn lint_rootfs(
+            ffi_repo: Pin<&mut OstreeRepo>,
+            rootfs_dfd: i32,
+            treefile: &Treefile,
+            previous_rev: &str,
+        ) -> Result<()>;
+    }
+
     // passwd.rs
     extern "Rust" {
         fn prepare_rpm_layering(rootfs: i32, merge_passwd_dir: &str) -> Result<bool>;
@@ -355,6 +383,7 @@ pub mod ffi {
         fn passwd_cleanup(rootfs: i32) -> Result<()>;
         fn migrate_group_except_root(rootfs: i32, preserved_groups: &Vec<String>) -> Result<()>;
         fn migrate_passwd_except_root(rootfs: i32) -> Result<()>;
+        fn sync_shadow_files(rootfs: i32, etc_dir: &str) -> Result<()>;
         fn passwd_compose_prep(rootfs: i32, treefile: &mut Treefile) -> Result<()>;
         fn passwd_compose_prep_repo(
             rootfs: i32,
@@ -387,6 +416,16 @@ pub mod ffi {
         fn lookup_group_id(self: &PasswdEntries, group: &str) -> Result<u32>;
     }
 
+    // sysusers.rs
+    extern "Rust" {
+        fn sysusers_compose(
+            ffi_repo: Pin<&mut OstreeRepo>,
+            rootfs_dfd: i32,
+            treefile: &Treefile,
+            previous_rev: &str,
+        ) -> Result<()>;
+    }
+
     // extensions.rs
     extern "Rust" {
         type Extensions;
@@ -425,6 +464,34 @@ pub mod ffi {
         fn get_locked_src_packages(&self) -> Result<Vec<LockedPackage>>;
     }
 
+    // sbom.rs
+    extern "Rust" {
+        fn sbom_write(
+            filename: &str,
+            packages: Pin<&mut CxxGObjectArray>,
+            treefile: &Treefile,
+            lockfiles: &Vec<String>,
+            repo: Pin<&mut OstreeRepo>,
+        ) -> Result<String>;
+    }
+
+    // sizereport.rs
+    extern "Rust" {
+        fn size_report_write(
+            filename: &str,
+            rootfs_dfd: i32,
+            packages: Pin<&mut CxxGObjectArray>,
+            treefile: &Treefile,
+            repo: Pin<&mut OstreeRepo>,
+        ) -> Result<()>;
+        fn check_size_budgets(
+            rootfs_dfd: i32,
+            packages: Pin<&mut CxxGObjectArray>,
+            treefile: &Treefile,
+            repo: Pin<&mut OstreeRepo>,
+        ) -> Result<()>;
+    }
+
     // rpmutils.rs
     extern "Rust" {
         fn cache_branch_to_nevra(nevra: &str) -> String;
@@ -550,6 +617,8 @@ pub(crate) use self::initramfs::*;
 mod isolation;
 mod journal;
 pub(crate) use self::journal::*;
+mod lint;
+pub(crate) use self::lint::*;
 mod lockfile;
 pub(crate) use self::lockfile::*;
 mod live;
@@ -559,19 +628,28 @@ mod nameservice;
 // we're ready to try porting the C++ code.
 #[cfg(test)]
 mod origin;
+pub mod ownership;
 mod passwd;
 use passwd::*;
 mod console_progress;
 pub(crate) use self::console_progress::*;
 mod progress;
+mod sbom;
+pub(crate) use self::sbom::*;
 mod scripts;
 pub(crate) use self::scripts::*;
 mod rpmutils;
 pub(crate) use self::rpmutils::*;
+pub mod schema;
+pub mod sizereport;
+pub(crate) use self::sizereport::{check_size_budgets, size_report_write};
+mod sysusers;
+pub(crate) use self::sysusers::*;
 mod testutils;
 pub(crate) use self::testutils::*;
 mod treefile;
 pub use self::treefile::*;
+pub mod treefile_migrate;
 mod utils;
 pub use self::utils::*;
 mod variant_utils;
@@ -0,0 +1,473 @@
+//! Checks of the final rootfs at compose time, configured via the
+//! treefile `lint` section.
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use crate::composepost::{glob_match, is_generated_tmpfiles, TMPFILES_DIRS};
+use crate::cxxrsutil::*;
+use crate::ffiutil;
+use crate::ownership::audit_owner;
+use crate::passwd::PasswdDB;
+use crate::treefile::{LintConfig, LintPolicy, Treefile};
+use anyhow::{anyhow, bail, Result};
+use camino::Utf8Path;
+use openat::SimpleType;
+use openat_ext::OpenatDirExt;
+use std::collections::{BTreeSet, HashSet};
+use std::convert::TryInto;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+
+/// Toplevel directories which are populated at runtime, so symlinks
+/// pointing into them can't be checked.
+static RUNTIME_DIRS: &[&str] = &["dev", "proc", "run", "sys", "sysroot", "tmp", "var"];
+
+/// Symlink resolution gives up after this many hops, like the kernel.
+const MAX_SYMLINK_HOPS: u32 = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LintRule {
+    Setuid,
+    WorldWritable,
+    DanglingSymlinks,
+    VarWithoutTmpfiles,
+    UnknownOwner,
+    ChangedOwner,
+}
+
+impl LintRule {
+    /// The name of the rule, as used in the treefile.
+    fn name(&self) -> &'static str {
+        match self {
+            LintRule::Setuid => "setuid",
+            LintRule::WorldWritable => "world-writable",
+            LintRule::DanglingSymlinks => "dangling-symlinks",
+            LintRule::VarWithoutTmpfiles => "var-without-tmpfiles",
+            LintRule::UnknownOwner => "unknown-owner",
+            LintRule::ChangedOwner => "changed-owner",
+        }
+    }
+
+    fn policy(&self, config: &LintConfig) -> LintPolicy {
+        let policy = match self {
+            LintRule::Setuid => config.setuid,
+            LintRule::WorldWritable => config.world_writable,
+            LintRule::DanglingSymlinks => config.dangling_symlinks,
+            LintRule::VarWithoutTmpfiles => config.var_without_tmpfiles,
+            LintRule::UnknownOwner => config.unknown_owner,
+            LintRule::ChangedOwner => config.changed_owner,
+        };
+        policy.unwrap_or(LintPolicy::Warn)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LintFinding {
+    rule: LintRule,
+    path: String,
+    detail: String,
+}
+
+/// Run the treefile `lint` checks on the final rootfs, printing findings and
+/// failing if any of them is from a rule with the `fail` policy.
+pub fn lint_rootfs(
+    mut ffi_repo: Pin<&mut crate::ffi::OstreeRepo>,
+    rootfs_dfd: i32,
+    treefile: &Treefile,
+    previous_rev: &str,
+) -> CxxResult<()> {
+    let config = match treefile.parsed.lint.as_ref() {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    let rootfs = ffiutil::ffi_view_openat_dir(rootfs_dfd);
+    let repo = ffi_repo.gobj_wrap();
+    // C side uses "" for None
+    let previous_pwdb =
+        if !previous_rev.is_empty() && LintRule::ChangedOwner.policy(config) != LintPolicy::Off {
+            Some(PasswdDB::populate_from_commit(&repo, previous_rev)?)
+        } else {
+            None
+        };
+    println!("Linting rootfs");
+    let findings = lint(&rootfs, config, previous_pwdb.as_ref())?;
+    let mut failures = 0;
+    for f in findings.iter() {
+        let prefix = match f.rule.policy(config) {
+            LintPolicy::Fail => {
+                failures += 1;
+                "error"
+            }
+            _ => "warning",
+        };
+        eprintln!("{}: {}: /{}: {}", prefix, f.rule.name(), f.path, f.detail);
+    }
+    if failures > 0 {
+        return Err(anyhow!("Rootfs lint failed with {} error(s)", failures).into());
+    }
+    Ok(())
+}
+
+/// Collect the findings of all the rules which aren't turned off; owners are
+/// compared with `previous_pwdb` for `changed-owner`.
+fn lint(
+    rootfs: &openat::Dir,
+    config: &LintConfig,
+    previous_pwdb: Option<&PasswdDB>,
+) -> Result<Vec<LintFinding>> {
+    let enabled = |rule: LintRule| rule.policy(config) != LintPolicy::Off;
+    let setuid_allowlist: HashSet<&str> = config
+        .setuid_allowlist
+        .iter()
+        .flatten()
+        .map(|p| p.trim_start_matches('/'))
+        .collect();
+    let pwdb = if enabled(LintRule::UnknownOwner) || enabled(LintRule::ChangedOwner) {
+        Some(PasswdDB::populate_new(rootfs)?)
+    } else {
+        None
+    };
+    // /var has usually been converted to tmpfiles.d by now, so check the
+    // generated entries as well as what's left in /var.
+    let (tmpfiles, converted) = if enabled(LintRule::VarWithoutTmpfiles) {
+        tmpfiles_paths(rootfs)?
+    } else {
+        (Vec::new(), Vec::new())
+    };
+    let shipped = |path: &str| tmpfiles.iter().any(|p| tmpfiles_path_matches(p, path));
+
+    let mut findings = Vec::new();
+    let mut add = |rule: LintRule, path: &str, detail: String| {
+        if enabled(rule) {
+            findings.push(LintFinding {
+                rule,
+                path: path.to_string(),
+                detail,
+            });
+        }
+    };
+    walk(rootfs, ".", &mut |path, meta| {
+        let stat = meta.stat();
+        let mode = stat.st_mode & 0o7777;
+        let path_type = meta.simple_type();
+        if path_type == SimpleType::File
+            && mode & (libc::S_ISUID | libc::S_ISGID) != 0
+            && !setuid_allowlist.contains(path)
+        {
+            add(LintRule::Setuid, path, format!("mode {:04o}", mode));
+        }
+        let sticky_dir = path_type == SimpleType::Dir && mode & libc::S_ISVTX != 0;
+        if path_type != SimpleType::Symlink && mode & 0o002 != 0 && !sticky_dir {
+            add(LintRule::WorldWritable, path, format!("mode {:04o}", mode));
+        }
+        if path_type == SimpleType::Symlink && !symlink_resolves(rootfs, path)? {
+            let target = rootfs.read_link(path)?;
+            let detail = format!("target {} does not exist", target.display());
+            add(LintRule::DanglingSymlinks, path, detail);
+        }
+        if path.starts_with("var/") && !shipped(path) {
+            add(
+                LintRule::VarWithoutTmpfiles,
+                path,
+                "no tmpfiles.d entry".into(),
+            );
+        }
+        if let Some(pwdb) = pwdb.as_ref() {
+            for issue in audit_owner(stat.st_uid, stat.st_gid, pwdb, previous_pwdb) {
+                let rule = if issue.is_unknown() {
+                    LintRule::UnknownOwner
+                } else {
+                    LintRule::ChangedOwner
+                };
+                add(rule, path, issue.to_string());
+            }
+        }
+        Ok(())
+    })?;
+    for path in converted.iter().filter(|p| !shipped(p)) {
+        add(
+            LintRule::VarWithoutTmpfiles,
+            path,
+            "no tmpfiles.d entry shipped by packages".into(),
+        );
+    }
+    Ok(findings)
+}
+
+/// Recursively call `f` on all the paths under `dir` (relative to the rootfs),
+/// in sorted order and without following symlinks.
+pub(crate) fn walk(
+    rootfs: &openat::Dir,
+    dir: &str,
+    f: &mut dyn FnMut(&str, &openat::Metadata) -> Result<()>,
+) -> Result<()> {
+    let mut names = Vec::new();
+    for entry in rootfs.list_dir(dir)? {
+        let entry = entry?;
+        let name: &Utf8Path = Path::new(entry.file_name()).try_into()?;
+        names.push(name.to_string());
+    }
+    names.sort();
+    for name in names {
+        let path = if dir == "." {
+            name
+        } else {
+            format!("{}/{}", dir, name)
+        };
+        let meta = rootfs.metadata(path.as_str())?;
+        f(&path, &meta)?;
+        if meta.simple_type() == SimpleType::Dir {
+            walk(rootfs, &path, f)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check whether the symlink at `path` resolves to something in the rootfs,
+/// resolving absolute targets relative to the rootfs rather than the host.
+fn symlink_resolves(rootfs: &openat::Dir, path: &str) -> Result<bool> {
+    // Components left to resolve, in reverse order.
+    let mut pending: Vec<String> = path.rsplit('/').map(String::from).collect();
+    let mut resolved: Vec<String> = Vec::new();
+    let mut hops = 0;
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "" | "." => continue,
+            ".." => {
+                resolved.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let mut candidate = resolved.join("/");
+        if !candidate.is_empty() {
+            candidate.push('/');
+        }
+        candidate.push_str(&component);
+        let meta = match rootfs.metadata_optional(candidate.as_str())? {
+            Some(meta) => meta,
+            None => {
+                let toplevel = resolved.first().unwrap_or(&component);
+                return Ok(RUNTIME_DIRS.contains(&toplevel.as_str()));
+            }
+        };
+        match meta.simple_type() {
+            SimpleType::Symlink => {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Ok(false);
+                }
+                let target = rootfs.read_link(candidate.as_str())?;
+                let target: &Utf8Path = target.as_path().try_into()?;
+                if target.is_absolute() {
+                    resolved.clear();
+                }
+                pending.extend(target.as_str().rsplit('/').map(String::from));
+            }
+            SimpleType::Dir => resolved.push(component),
+            _ if pending.iter().all(|c| c.is_empty() || c == ".") => resolved.push(component),
+            _ => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// Gather the paths (relative to the rootfs) of the tmpfiles.d entries shipped
+/// by packages, and separately those of the entries generated from /var content.
+fn tmpfiles_paths(rootfs: &openat::Dir) -> Result<(Vec<String>, Vec<String>)> {
+    let mut shipped = Vec::new();
+    let mut converted = BTreeSet::new();
+    for dir in TMPFILES_DIRS {
+        if !rootfs.exists(*dir)? {
+            continue;
+        }
+        for entry in rootfs.list_dir(*dir)? {
+            let entry = entry?;
+            let name: &Utf8Path = Path::new(entry.file_name()).try_into()?;
+            if !name.as_str().ends_with(".conf") {
+                continue;
+            }
+            let path = format!("{}/{}", dir, name);
+            let f = rootfs.open_file(path.as_str())?;
+            let paths = parse_tmpfiles_paths(BufReader::new(f))?;
+            if is_generated_tmpfiles(&path) {
+                converted.extend(paths);
+            } else {
+                shipped.extend(paths);
+            }
+        }
+    }
+    Ok((shipped, converted.into_iter().collect()))
+}
+
+/// Extract the paths from tmpfiles.d content.
+fn parse_tmpfiles_paths(r: impl BufRead) -> Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for line in r.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_whitespace().nth(1) {
+            Some(path) => paths.push(path.trim_start_matches('/').to_string()),
+            None => bail!("Invalid tmpfiles.d entry: {}", line),
+        }
+    }
+    Ok(paths)
+}
+
+/// Whether the tmpfiles.d `pattern` (which may contain globs) matches `path`.
+fn tmpfiles_path_matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    pattern.len() == path.len()
+        && pattern
+            .iter()
+            .zip(path.iter())
+            .all(|(p, c)| glob_match(p, c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    fn rule_paths(findings: &[LintFinding], rule: LintRule) -> Vec<&str> {
+        findings
+            .iter()
+            .filter(|f| f.rule == rule)
+            .map(|f| f.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_tmpfiles_paths() {
+        let content = "# comment\n\nd /var/lib/foo 0755 root root - -\nL /var/foo - - - - bar\n";
+        let paths = parse_tmpfiles_paths(content.as_bytes()).unwrap();
+        assert_eq!(paths, vec!["var/lib/foo", "var/foo"]);
+        assert!(parse_tmpfiles_paths("d\n".as_bytes()).is_err());
+        assert!(tmpfiles_path_matches("var/log/*", "var/log/foo"));
+        assert!(!tmpfiles_path_matches("var/log/*", "var/log/foo/bar"));
+    }
+
+    #[test]
+    fn test_lint() -> Result<()> {
+        let temp_rootfs = tempfile::tempdir()?;
+        let rootfs = openat::Dir::open(temp_rootfs.path())?;
+        let uid = nix::unistd::getuid();
+        let gid = nix::unistd::getgid();
+        for dir in &["usr/etc", "usr/lib/tmpfiles.d", "usr/bin", "var/lib/foo"] {
+            rootfs.ensure_dir_all(*dir, 0o755)?;
+        }
+        rootfs.write_file_contents(
+            "usr/etc/passwd",
+            0o644,
+            format!("core:x:{}:{}::/var/home/core:/bin/bash\n", uid, gid),
+        )?;
+        rootfs.write_file_contents("usr/etc/group", 0o644, format!("core:x:{}:\n", gid))?;
+        rootfs.write_file_contents("usr/lib/passwd", 0o644, "")?;
+        rootfs.write_file_contents("usr/lib/group", 0o644, "")?;
+        rootfs.write_file_contents(
+            "usr/lib/tmpfiles.d/foo.conf",
+            0o644,
+            "d /var/lib 0755 root root - -\n",
+        )?;
+        for name in &["usr/bin/sudo", "usr/bin/su", "usr/bin/foo"] {
+            rootfs.write_file_contents(*name, 0o755, "")?;
+        }
+        let chmod = |path: &str, mode: u32| {
+            nix::sys::stat::fchmodat(
+                Some(rootfs.as_raw_fd()),
+                path,
+                nix::sys::stat::Mode::from_bits_truncate(mode),
+                nix::sys::stat::FchmodatFlags::FollowSymlink,
+            )
+        };
+        chmod("usr/bin/sudo", 0o4755)?;
+        chmod("usr/bin/su", 0o4755)?;
+        chmod("usr/bin/foo", 0o777)?;
+        rootfs.ensure_dir_all("tmp", 0o755)?;
+        chmod("tmp", 0o1777)?;
+        rootfs.symlink("usr/bin/bar", "foo")?;
+        rootfs.symlink("usr/bin/baz", "nosuchfile")?;
+        rootfs.symlink("usr/bin/loop", "loop")?;
+        rootfs.symlink("usr/lib/abs", "/usr/bin/foo")?;
+        rootfs.symlink("usr/lib/mounts", "../../proc/self/mounts")?;
+
+        let config: LintConfig = serde_yaml::from_str(indoc::indoc! {"
+            setuid: fail
+            setuid-allowlist:
+              - /usr/bin/sudo
+        "})?;
+        let findings = lint(&rootfs, &config, None)?;
+        assert_eq!(rule_paths(&findings, LintRule::Setuid), vec!["usr/bin/su"]);
+        assert_eq!(
+            rule_paths(&findings, LintRule::WorldWritable),
+            vec!["usr/bin/foo"]
+        );
+        assert_eq!(
+            rule_paths(&findings, LintRule::DanglingSymlinks),
+            vec!["usr/bin/baz", "usr/bin/loop"]
+        );
+        assert_eq!(
+            rule_paths(&findings, LintRule::VarWithoutTmpfiles),
+            vec!["var/lib/foo"]
+        );
+        assert!(rule_paths(&findings, LintRule::UnknownOwner).is_empty());
+
+        let config: LintConfig = serde_yaml::from_str(indoc::indoc! {"
+            setuid: off
+            world-writable: off
+            dangling-symlinks: off
+            var-without-tmpfiles: off
+        "})?;
+        assert!(lint(&rootfs, &config, None)?.is_empty());
+        assert!(serde_yaml::from_str::<LintConfig>("setuids: fail").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_converted_var() -> Result<()> {
+        let temp_rootfs = tempfile::tempdir()?;
+        let rootfs = openat::Dir::open(temp_rootfs.path())?;
+        let uid = nix::unistd::getuid();
+        let gid = nix::unistd::getgid();
+        for dir in &["usr/etc", "usr/lib/tmpfiles.d"] {
+            rootfs.ensure_dir_all(*dir, 0o755)?;
+        }
+        rootfs.write_file_contents(
+            "usr/etc/passwd",
+            0o644,
+            format!("core:x:{}:{}::/var/home/core:/bin/bash\n", uid, gid),
+        )?;
+        rootfs.write_file_contents("usr/etc/group", 0o644, format!("core:x:{}:\n", gid))?;
+        rootfs.write_file_contents("usr/lib/passwd", 0o644, "")?;
+        rootfs.write_file_contents("usr/lib/group", 0o644, "")?;
+        rootfs.write_file_contents(
+            "usr/lib/tmpfiles.d/bar.conf",
+            0o644,
+            "d /var/lib - - - -\nd /var/lib/bar - - - -\n",
+        )?;
+        for dir in &["var/lib/foo", "var/lib/bar", "var/lib/other"] {
+            rootfs.ensure_dir_all(*dir, 0o755)?;
+        }
+        let owners = vec![
+            ("/var/lib/foo".to_string(), "foo".to_string()),
+            ("/var/lib/bar".to_string(), "bar".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        crate::composepost::var_to_tmpfiles(&rootfs, &owners, gio::NONE_CANCELLABLE)?;
+        assert!(!rootfs.exists("var/lib")?);
+
+        let config: LintConfig = serde_yaml::from_str("var-without-tmpfiles: fail")?;
+        let findings = lint(&rootfs, &config, None)?;
+        assert_eq!(
+            rule_paths(&findings, LintRule::VarWithoutTmpfiles),
+            vec!["var/lib/foo", "var/lib/other"]
+        );
+        Ok(())
+    }
+}
@@ -15,6 +15,7 @@ use crate::utils;
 use anyhow::{anyhow, bail, Result};
 use chrono::prelude::*;
 use openat_ext::OpenatDirExt;
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::convert::TryInto;
@@ -100,7 +101,7 @@ fn lockfile_parse_multiple<P: AsRef<Path>>(filenames: &[P]) -> Result<LockfileCo
 /// XXX: One known limitation of this format right now is that it's not compatible with multilib.
 /// TBD whether we care about this.
 ///
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct LockfileConfig {
@@ -110,20 +111,20 @@ pub(crate) struct LockfileConfig {
     metadata: Option<LockfileConfigMetadata>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(deny_unknown_fields)]
 struct LockfileConfigMetadata {
     generated: Option<DateTime<Utc>>,
     rpmmd_repos: Option<BTreeMap<String, LockfileRepoMetadata>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(deny_unknown_fields)]
 struct LockfileRepoMetadata {
     generated: DateTime<Utc>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(untagged, deny_unknown_fields)]
 enum LockedPackage {
     Evr {
@@ -268,6 +269,29 @@ mod tests {
         assert_eq!(assert_entry(&lockfile.source_packages, "bam"), "1.2.3-4");
     }
 
+    #[test]
+    fn basic_valid_toml() {
+        let buf = r###"
+[packages]
+foo = { evra = "1.0-1.noarch", digest = "sha256:deadcafe" }
+baz = { evr = "2.1.1-1", metadata = {} }
+
+[source-packages]
+boo = "3.2.1-5"
+
+[metadata]
+generated = "2021-01-01T00:00:00Z"
+"###;
+        let mut input = io::BufReader::new(buf.as_bytes());
+        let lockfile: LockfileConfig =
+            utils::parse_stream(&utils::InputFormat::TOML, &mut input).unwrap();
+        assert_eq!(lockfile.packages.as_ref().unwrap().len(), 2);
+        assert_evra(assert_entry(&lockfile.packages, "foo"), "1.0-1.noarch");
+        assert_evr(assert_entry(&lockfile.packages, "baz"), "2.1.1-1");
+        assert_eq!(assert_entry(&lockfile.source_packages, "boo"), "3.2.1-5");
+        assert!(lockfile.metadata.unwrap().generated.is_some());
+    }
+
     static OVERRIDE_JS: &str = r###"
 {
     "packages": {
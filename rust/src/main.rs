@@ -8,7 +8,11 @@ fn inner_main(args: &[&str]) -> Result<()> {
     match args.get(1).copied() {
         // Add custom Rust commands here, and also in `libmain.cxx` if user-visible.
         Some("countme") => rpmostree_rust::countme::entrypoint(args),
+        Some("ex-audit-ownership") => rpmostree_rust::ownership::entrypoint(args),
         Some("ex-container") => rpmostree_rust::container::entrypoint(args),
+        Some("ex-json-schema") => rpmostree_rust::schema::entrypoint(args),
+        Some("ex-size-diff") => rpmostree_rust::sizereport::entrypoint(args),
+        Some("ex-treefile-migrate") => rpmostree_rust::treefile_migrate::entrypoint(args),
         _ => {
             // Otherwise fall through to C++ main().
             Ok(rpmostree_rust::ffi::rpmostree_main(&args)?)
@@ -0,0 +1,144 @@
+//! Helpers for [shadowed group file](https://man7.org/linux/man-pages/man5/gshadow.5.html).
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use anyhow::{anyhow, Context, Result};
+use std::io::{BufRead, Write};
+
+// Entry from gshadow file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct GshadowEntry {
+    pub(crate) name: String,
+    pub(crate) passwd: String,
+    pub(crate) admins: Vec<String>,
+    pub(crate) members: Vec<String>,
+}
+
+impl GshadowEntry {
+    /// A new entry for `name` with a locked password.
+    pub fn new_locked(name: impl Into<String>, members: Vec<String>) -> Self {
+        Self {
+            name: name.into(),
+            passwd: "!".to_string(),
+            admins: vec![],
+            members,
+        }
+    }
+
+    /// Parse a single gshadow entry.
+    pub fn parse_line(s: impl AsRef<str>) -> Option<Self> {
+        let mut parts = s.as_ref().splitn(4, ':');
+        let entry = Self {
+            name: parts.next().filter(|s| !s.is_empty())?.to_string(),
+            passwd: parts.next()?.to_string(),
+            admins: {
+                let admins = parts.next()?;
+                admins.split(',').map(String::from).collect()
+            },
+            members: {
+                let members = parts.next().filter(|s| !s.contains(':'))?;
+                members.split(',').map(String::from).collect()
+            },
+        };
+        Some(entry)
+    }
+
+    /// Serialize entry to writer, as a gshadow line.
+    pub fn to_writer(&self, writer: &mut impl Write) -> Result<()> {
+        std::writeln!(
+            writer,
+            "{}:{}:{}:{}",
+            self.name,
+            self.passwd,
+            self.admins.join(","),
+            self.members.join(","),
+        )
+        .with_context(|| "failed to write gshadow entry")
+    }
+}
+
+pub(crate) fn parse_gshadow_content(content: impl BufRead) -> Result<Vec<GshadowEntry>> {
+    let mut entries = vec![];
+    for (line_num, line) in content.lines().enumerate() {
+        let input =
+            line.with_context(|| format!("failed to read gshadow entry at line {}", line_num))?;
+
+        // Skip empty and comment lines
+        if input.is_empty() || input.starts_with('#') {
+            continue;
+        }
+        // Skip NSS compat lines, see "Compatibility mode" in
+        // https://man7.org/linux/man-pages/man5/nsswitch.conf.5.html
+        if input.starts_with('+') || input.starts_with('-') {
+            continue;
+        }
+
+        // Don't include the content in the error, it may contain a password hash.
+        let entry = GshadowEntry::parse_line(&input).ok_or_else(|| {
+            anyhow!(
+                "failed to parse gshadow entry at line {}, for name: {}",
+                line_num,
+                input.split(':').next().unwrap_or_default()
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+
+    fn mock_gshadow_entry() -> GshadowEntry {
+        GshadowEntry {
+            name: "wheel".to_string(),
+            passwd: "!".to_string(),
+            admins: vec!["".to_string()],
+            members: vec!["core".to_string(), "admin".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let content = r#"
+root:::
+bin:::
+
+# Dummy comment
+wheel:!::core,admin
+"#;
+
+        let input = BufReader::new(Cursor::new(content));
+        let entries = parse_gshadow_content(input).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2], mock_gshadow_entry());
+
+        for malformed in &[
+            "wheel:$6$salt$hash:",
+            ":$6$salt$hash::",
+            "wheel:$6$salt$hash:::",
+        ] {
+            let input = BufReader::new(Cursor::new(*malformed));
+            let e = parse_gshadow_content(input).unwrap_err().to_string();
+            assert!(
+                e.starts_with("failed to parse gshadow entry at line 0"),
+                "{}",
+                e
+            );
+            assert!(!e.contains("$6$"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_write_entry() {
+        let mut buf = Vec::new();
+        mock_gshadow_entry().to_writer(&mut buf).unwrap();
+        assert_eq!(&buf, b"wheel:!::core,admin\n");
+        let mut buf = Vec::new();
+        GshadowEntry::new_locked("foo", vec!["".to_string()])
+            .to_writer(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"foo:!::\n");
+    }
+}
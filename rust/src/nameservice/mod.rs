@@ -3,4 +3,6 @@
 // TODO(lucab): consider moving this to its own crate.
 
 pub(crate) mod group;
+pub(crate) mod gshadow;
 pub(crate) mod passwd;
+pub(crate) mod shadow;
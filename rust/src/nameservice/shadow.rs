@@ -0,0 +1,175 @@
+//! Helpers for [shadowed password file](https://man7.org/linux/man-pages/man5/shadow.5.html).
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use anyhow::{anyhow, Context, Result};
+use std::io::{BufRead, Write};
+
+// Entry from shadow file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ShadowEntry {
+    pub(crate) name: String,
+    pub(crate) passwd: String,
+    pub(crate) last_change: Option<i64>,
+    pub(crate) min_age: Option<i64>,
+    pub(crate) max_age: Option<i64>,
+    pub(crate) warn_period: Option<i64>,
+    pub(crate) inactivity_period: Option<i64>,
+    pub(crate) expiration: Option<i64>,
+    pub(crate) reserved: String,
+}
+
+/// Parse an optional numeric field; `None` if it is malformed.
+fn parse_days(s: &str) -> Option<Option<i64>> {
+    if s.is_empty() {
+        Some(None)
+    } else {
+        s.parse().ok().map(Some)
+    }
+}
+
+fn fmt_days(v: Option<i64>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+impl ShadowEntry {
+    /// A new entry for `name` with a locked password.
+    pub fn new_locked(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            passwd: "!!".to_string(),
+            last_change: None,
+            min_age: None,
+            max_age: None,
+            warn_period: None,
+            inactivity_period: None,
+            expiration: None,
+            reserved: String::new(),
+        }
+    }
+
+    /// Parse a single shadow entry.
+    pub fn parse_line(s: impl AsRef<str>) -> Option<Self> {
+        let mut parts = s.as_ref().splitn(9, ':');
+        let entry = Self {
+            name: parts.next().filter(|s| !s.is_empty())?.to_string(),
+            passwd: parts.next()?.to_string(),
+            last_change: parts.next().and_then(parse_days)?,
+            min_age: parts.next().and_then(parse_days)?,
+            max_age: parts.next().and_then(parse_days)?,
+            warn_period: parts.next().and_then(parse_days)?,
+            inactivity_period: parts.next().and_then(parse_days)?,
+            expiration: parts.next().and_then(parse_days)?,
+            reserved: parts.next().filter(|s| !s.contains(':'))?.to_string(),
+        };
+        Some(entry)
+    }
+
+    /// Serialize entry to writer, as a shadow line.
+    pub fn to_writer(&self, writer: &mut impl Write) -> Result<()> {
+        std::writeln!(
+            writer,
+            "{}:{}:{}:{}:{}:{}:{}:{}:{}",
+            self.name,
+            self.passwd,
+            fmt_days(self.last_change),
+            fmt_days(self.min_age),
+            fmt_days(self.max_age),
+            fmt_days(self.warn_period),
+            fmt_days(self.inactivity_period),
+            fmt_days(self.expiration),
+            self.reserved,
+        )
+        .with_context(|| "failed to write shadow entry")
+    }
+}
+
+pub(crate) fn parse_shadow_content(content: impl BufRead) -> Result<Vec<ShadowEntry>> {
+    let mut entries = vec![];
+    for (line_num, line) in content.lines().enumerate() {
+        let input =
+            line.with_context(|| format!("failed to read shadow entry at line {}", line_num))?;
+
+        // Skip empty and comment lines
+        if input.is_empty() || input.starts_with('#') {
+            continue;
+        }
+        // Skip NSS compat lines, see "Compatibility mode" in
+        // https://man7.org/linux/man-pages/man5/nsswitch.conf.5.html
+        if input.starts_with('+') || input.starts_with('-') {
+            continue;
+        }
+
+        // Don't include the content in the error, it may contain a password hash.
+        let entry = ShadowEntry::parse_line(&input).ok_or_else(|| {
+            anyhow!(
+                "failed to parse shadow entry at line {}, for name: {}",
+                line_num,
+                input.split(':').next().unwrap_or_default()
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+
+    fn mock_shadow_entry() -> ShadowEntry {
+        ShadowEntry {
+            name: "core".to_string(),
+            passwd: "$6$salt$hash".to_string(),
+            last_change: Some(18600),
+            min_age: Some(0),
+            max_age: Some(99999),
+            warn_period: Some(7),
+            inactivity_period: None,
+            expiration: None,
+            reserved: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let content = r#"
+root:!::0:99999:7:::
+bin:*:18600:0:99999:7:::
+
+# Dummy comment
+core:$6$salt$hash:18600:0:99999:7:::
+"#;
+
+        let input = BufReader::new(Cursor::new(content));
+        let entries = parse_shadow_content(input).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2], mock_shadow_entry());
+
+        for malformed in &[
+            "core:$6$salt$hash:1:2:3",
+            "core:$6$salt$hash:abc::::::",
+            ":$6$salt$hash:::::::",
+            "core:$6$salt$hash::::::::",
+        ] {
+            let input = BufReader::new(Cursor::new(*malformed));
+            let e = parse_shadow_content(input).unwrap_err().to_string();
+            assert!(
+                e.starts_with("failed to parse shadow entry at line 0"),
+                "{}",
+                e
+            );
+            assert!(!e.contains("$6$"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_write_entry() {
+        let mut buf = Vec::new();
+        mock_shadow_entry().to_writer(&mut buf).unwrap();
+        assert_eq!(&buf, b"core:$6$salt$hash:18600:0:99999:7:::\n");
+        let mut buf = Vec::new();
+        ShadowEntry::new_locked("foo").to_writer(&mut buf).unwrap();
+        assert_eq!(&buf, b"foo:!!:::::::\n");
+    }
+}
@@ -0,0 +1,234 @@
+//! Audit of file ownership against the users and groups of a tree, used by
+//! the treefile `lint` section and the hidden `rpm-ostree ex-audit-ownership`
+//! CLI (e.g. to check `/var` before booting a deployment whose IDs changed).
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use crate::passwd::PasswdDB;
+use anyhow::{bail, Context, Result};
+use nix::unistd::{Gid, Uid};
+use std::fmt;
+use std::io::Write;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IdKind {
+    User,
+    Group,
+}
+
+/// A file owner (user or group) which is unknown, or which maps to another
+/// name than it did before.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OwnershipIssue {
+    pub(crate) kind: IdKind,
+    pub(crate) id: u32,
+    /// The name of `id` in the previous users and groups
+    pub(crate) old_name: Option<String>,
+    /// The name of `id` in the current users and groups
+    pub(crate) new_name: Option<String>,
+    /// The current ID of `old_name`
+    pub(crate) old_name_id: Option<u32>,
+}
+
+impl OwnershipIssue {
+    /// Whether `id` has no entry in the current users and groups.
+    pub(crate) fn is_unknown(&self) -> bool {
+        self.new_name.is_none()
+    }
+}
+
+impl fmt::Display for OwnershipIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            IdKind::User => "UID",
+            IdKind::Group => "GID",
+        };
+        match (self.new_name.as_ref(), self.old_name.as_ref()) {
+            (None, None) => write!(f, "unknown {} {}", kind, self.id)?,
+            (None, Some(old)) => write!(f, "unknown {} {} (was {})", kind, self.id, old)?,
+            (Some(new), Some(old)) => write!(f, "{} {} was {}, now {}", kind, self.id, old, new)?,
+            (Some(new), None) => write!(f, "{} {} is {}", kind, self.id, new)?,
+        }
+        if let (Some(old), Some(id)) = (self.old_name.as_ref(), self.old_name_id) {
+            write!(f, "; {} is now {} {}", old, kind, id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check a single owner ID against the `current` users and groups and,
+/// if given, the `previous` ones.
+fn audit_id(
+    kind: IdKind,
+    id: u32,
+    current: &PasswdDB,
+    previous: Option<&PasswdDB>,
+) -> Option<OwnershipIssue> {
+    let (new_name, old_name) = match kind {
+        IdKind::User => (
+            current.lookup_user(id).ok(),
+            previous.and_then(|p| p.lookup_user(id).ok()),
+        ),
+        IdKind::Group => (
+            current.lookup_group(id).ok(),
+            previous.and_then(|p| p.lookup_group(id).ok()),
+        ),
+    };
+    if new_name.is_some() && (old_name.is_none() || old_name == new_name) {
+        return None;
+    }
+    let old_name_id = old_name.as_ref().and_then(|name| match kind {
+        IdKind::User => current.lookup_user_id(name).ok().map(Uid::as_raw),
+        IdKind::Group => current.lookup_group_id(name).ok().map(Gid::as_raw),
+    });
+    Some(OwnershipIssue {
+        kind,
+        id,
+        old_name,
+        new_name,
+        old_name_id,
+    })
+}
+
+/// Check the owner and group of a file.
+pub(crate) fn audit_owner(
+    uid: u32,
+    gid: u32,
+    current: &PasswdDB,
+    previous: Option<&PasswdDB>,
+) -> Vec<OwnershipIssue> {
+    audit_id(IdKind::User, uid, current, previous)
+        .into_iter()
+        .chain(audit_id(IdKind::Group, gid, current, previous))
+        .collect()
+}
+
+/// Check the ownership of everything under `dir`, returning the paths
+/// (relative to `dir`) with an issue.
+pub(crate) fn audit_ownership(
+    dir: &openat::Dir,
+    current: &PasswdDB,
+    previous: Option<&PasswdDB>,
+) -> Result<Vec<(String, OwnershipIssue)>> {
+    let mut r = Vec::new();
+    crate::lint::walk(dir, ".", &mut |path, meta| {
+        let stat = meta.stat();
+        for issue in audit_owner(stat.st_uid, stat.st_gid, current, previous) {
+            r.push((path.to_string(), issue));
+        }
+        Ok(())
+    })?;
+    Ok(r)
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ex-audit-ownership")]
+#[structopt(rename_all = "kebab-case")]
+struct Opt {
+    /// Root whose users and groups owners are checked against
+    #[structopt(long, default_value = "/")]
+    root: String,
+    /// Root with the users and groups previously in use, to find owners
+    /// whose name changed
+    #[structopt(long)]
+    previous_root: Option<String>,
+    /// Directories to audit
+    #[structopt(default_value = "/var")]
+    paths: Vec<String>,
+}
+
+fn open_passwddb(root: &str) -> Result<PasswdDB> {
+    let dir = openat::Dir::open(root).with_context(|| format!("Opening {}", root))?;
+    PasswdDB::populate_from_root(&dir)
+}
+
+/// Main entrypoint for ex-audit-ownership
+pub fn entrypoint(args: &[&str]) -> Result<()> {
+    // Skip the main `rpm-ostree` argument
+    let opt = Opt::from_iter(args.iter().skip(1));
+    let current = open_passwddb(&opt.root)?;
+    let previous = opt
+        .previous_root
+        .as_deref()
+        .map(open_passwddb)
+        .transpose()?;
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let mut n_files = 0;
+    for path in opt.paths.iter() {
+        let dir = openat::Dir::open(path.as_str()).with_context(|| format!("Opening {}", path))?;
+        let mut last = None;
+        for (subpath, issue) in audit_ownership(&dir, &current, previous.as_ref())? {
+            writeln!(
+                stdout,
+                "{}/{}: {}",
+                path.trim_end_matches('/'),
+                subpath,
+                issue
+            )?;
+            if last.as_ref() != Some(&subpath) {
+                n_files += 1;
+                last = Some(subpath);
+            }
+        }
+    }
+    if n_files > 0 {
+        bail!("Found {} files with unknown or changed owners", n_files);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openat_ext::OpenatDirExt;
+
+    fn passwddb(passwd: &str, group: &str) -> PasswdDB {
+        let temp_rootfs = tempfile::tempdir().unwrap();
+        let rootfs = openat::Dir::open(temp_rootfs.path()).unwrap();
+        rootfs.create_dir("etc", 0o755).unwrap();
+        rootfs
+            .write_file_contents("etc/passwd", 0o644, passwd)
+            .unwrap();
+        rootfs
+            .write_file_contents("etc/group", 0o644, group)
+            .unwrap();
+        PasswdDB::populate_from_root(&rootfs).unwrap()
+    }
+
+    #[test]
+    fn test_audit_owner() {
+        let previous = passwddb(
+            "root:x:0:0::/root:/bin/bash\nfoo:x:990:990::/:/sbin/nologin\nbar:x:980:980::/:/sbin/nologin\n",
+            "root:x:0:\nfoo:x:990:\nbar:x:980:\n",
+        );
+        let current = passwddb(
+            "root:x:0:0::/root:/bin/bash\nbar:x:990:990::/:/sbin/nologin\nfoo:x:970:970::/:/sbin/nologin\n",
+            "root:x:0:\nbar:x:990:\nfoo:x:970:\n",
+        );
+        assert!(audit_owner(0, 0, &current, Some(&previous)).is_empty());
+        assert!(audit_owner(970, 970, &current, Some(&previous)).is_empty());
+        let render = |uid, gid, previous| {
+            audit_owner(uid, gid, &current, previous)
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            render(990, 0, Some(&previous)),
+            vec!["UID 990 was foo, now bar; foo is now UID 970"]
+        );
+        assert_eq!(
+            render(980, 980, Some(&previous)),
+            vec![
+                "unknown UID 980 (was bar); bar is now UID 990",
+                "unknown GID 980 (was bar); bar is now GID 990"
+            ]
+        );
+        assert_eq!(render(990, 1000, None), vec!["unknown GID 1000"]);
+        let issues = audit_owner(1000, 0, &current, None);
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].is_unknown());
+    }
+}
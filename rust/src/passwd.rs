@@ -5,7 +5,7 @@
 use crate::cxxrsutil::*;
 use crate::ffiutil;
 use crate::nameservice;
-use crate::treefile::{CheckGroups, CheckPasswd, Treefile};
+use crate::treefile::{CheckGroups, CheckPasswd, IdAllocation, Treefile};
 use anyhow::{anyhow, Context, Result};
 use fn_error_context::context;
 use gio::prelude::InputStreamExtManual;
@@ -247,6 +247,7 @@ fn compare_uid_gid(metadata: openat::Metadata, uid: &Option<Uid>, gid: &Option<G
 pub fn passwd_compose_prep(rootfs_dfd: i32, treefile: &mut Treefile) -> CxxResult<()> {
     let rootfs = ffiutil::ffi_view_openat_dir(rootfs_dfd);
     passwd_compose_prep_impl(&rootfs, treefile, None, true)?;
+    crate::sysusers::sysusers_compose_prep(&rootfs, treefile, None, true)?;
     Ok(())
 }
 
@@ -256,6 +257,8 @@ pub fn passwd_compose_prep(rootfs_dfd: i32, treefile: &mut Treefile) -> CxxResul
 /// responsible for handling the "previous" and "file" paths; in both
 /// cases we inject data into the tree before even laying
 /// down any files, and notably before running RPM `useradd` etc.
+/// The users and groups of the treefile `sysusers` entries are seeded
+/// the same way.
 pub fn passwd_compose_prep_repo(
     rootfs_dfd: i32,
     treefile: &mut Treefile,
@@ -271,7 +274,8 @@ pub fn passwd_compose_prep_repo(
     } else {
         Some((&repo, previous_checksum))
     };
-    passwd_compose_prep_impl(&rootfs, treefile, repo_previous_rev, unified_core)
+    passwd_compose_prep_impl(&rootfs, treefile, repo_previous_rev, unified_core)?;
+    crate::sysusers::sysusers_compose_prep(&rootfs, treefile, repo_previous_rev, unified_core)
 }
 
 fn passwd_compose_prep_impl(
@@ -510,14 +514,158 @@ fn complete_pwgrp(rootfs: &openat::Dir) -> Result<()> {
         rootfs.local_rename(&etc_backup, &etc_file)?;
     }
 
-    // However, we leave the (potentially modified) shadow files in place.
-    // In actuality, nothing should change /etc/shadow or /etc/gshadow, so
-    // we'll just have to pay the (tiny) cost of re-checksumming.
+    // However, we leave the (potentially modified) shadow files in place;
+    // we just make sure that users and groups added by scripts without
+    // going through shadow-utils get a (locked) entry there.
+    sync_shadow_files_impl(rootfs, "etc")?;
 
+    // Trees using userdb records rather than altfiles need records for the
+    // new users and groups too.
+    if rootfs.exists(crate::composepost::USERDB_DIR)? {
+        crate::composepost::write_userdb_records(rootfs)?;
+    }
+
+    Ok(())
+}
+
+/// Make the `shadow` and `gshadow` files in `etc_dir` consistent with the
+/// users and groups from `etc_dir` and `/usr/lib`.
+pub fn sync_shadow_files(rootfs_dfd: i32, etc_dir: &str) -> CxxResult<()> {
+    let rootfs = ffiutil::ffi_view_openat_dir(rootfs_dfd);
+    sync_shadow_files_impl(&rootfs, etc_dir)?;
     Ok(())
 }
 
-/// Validate users/groups according to treefile check-passwd/check-groups configuration.
+/// Add a locked `shadow`/`gshadow` entry for every user and group without
+/// one, and drop the entries (and duplicates) without a user or group.
+/// Malformed lines are an error; missing shadow files are left alone.
+#[context("Synchronizing shadow files in /{}", etc_dir)]
+pub(crate) fn sync_shadow_files_impl(rootfs: &openat::Dir, etc_dir: &str) -> Result<()> {
+    let sources = |name: &str| vec![format!("{}/{}", etc_dir, name), format!("usr/lib/{}", name)];
+
+    let mut users = Vec::new();
+    for path in sources("passwd") {
+        if let Some(f) = rootfs.open_file_optional(&path)? {
+            let entries = nameservice::passwd::parse_passwd_content(BufReader::new(f))?;
+            users.extend(entries.into_iter().map(|e| e.name));
+        }
+    }
+    let mut groups = Vec::new();
+    for path in sources("group") {
+        if let Some(f) = rootfs.open_file_optional(&path)? {
+            groups.extend(nameservice::group::parse_group_content(BufReader::new(f))?);
+        }
+    }
+
+    let shadow_path = format!("{}/shadow", etc_dir);
+    if let Some(f) = rootfs.open_file_optional(&shadow_path)? {
+        let entries = nameservice::shadow::parse_shadow_content(BufReader::new(f))
+            .with_context(|| format!("Parsing /{}", shadow_path))?;
+        let names: Vec<&str> = users.iter().map(|u| u.as_str()).collect();
+        let entries = sync_entries(
+            &shadow_path,
+            entries,
+            |e| &e.name,
+            &names,
+            |name| nameservice::shadow::ShadowEntry::new_locked(name),
+        );
+        if let Some(entries) = entries {
+            rewrite_shadow_file(rootfs, &shadow_path, |w| {
+                entries.iter().try_for_each(|e| e.to_writer(w))
+            })?;
+        }
+    }
+
+    let gshadow_path = format!("{}/gshadow", etc_dir);
+    if let Some(f) = rootfs.open_file_optional(&gshadow_path)? {
+        let entries = nameservice::gshadow::parse_gshadow_content(BufReader::new(f))
+            .with_context(|| format!("Parsing /{}", gshadow_path))?;
+        let names: Vec<&str> = groups.iter().map(|g| g.name.as_str()).collect();
+        let entries = sync_entries(
+            &gshadow_path,
+            entries,
+            |e| &e.name,
+            &names,
+            |name| {
+                // SAFETY: `name` comes from `groups`.
+                let group = groups.iter().find(|g| g.name == name).unwrap();
+                nameservice::gshadow::GshadowEntry::new_locked(name, group.users.clone())
+            },
+        );
+        if let Some(entries) = entries {
+            rewrite_shadow_file(rootfs, &gshadow_path, |w| {
+                entries.iter().try_for_each(|e| e.to_writer(w))
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Keep the first of `entries` for each of `names`, and add one made by
+/// `new_entry` for each name without any.  Returns `None` if nothing changed.
+fn sync_entries<T>(
+    path: &str,
+    entries: Vec<T>,
+    entry_name: impl Fn(&T) -> &String,
+    names: &[&str],
+    new_entry: impl Fn(&str) -> T,
+) -> Option<Vec<T>> {
+    let wanted: HashSet<&str> = names.iter().copied().collect();
+    let mut seen = HashSet::new();
+    let mut removed = Vec::new();
+    let mut r = Vec::with_capacity(names.len());
+    for entry in entries {
+        let name = entry_name(&entry).clone();
+        if wanted.contains(name.as_str()) && !seen.contains(&name) {
+            seen.insert(name);
+            r.push(entry);
+        } else {
+            removed.push(name);
+        }
+    }
+    let mut added = Vec::new();
+    for name in names {
+        if seen.insert(name.to_string()) {
+            added.push(*name);
+            r.push(new_entry(name));
+        }
+    }
+    if added.is_empty() && removed.is_empty() {
+        return None;
+    }
+    if !added.is_empty() {
+        println!("Added locked entries to /{}: {}", path, added.join(", "));
+    }
+    if !removed.is_empty() {
+        println!(
+            "Removed stale entries from /{}: {}",
+            path,
+            removed.join(", ")
+        );
+    }
+    Some(r)
+}
+
+/// Replace a shadow file, keeping its mode.
+fn rewrite_shadow_file(
+    rootfs: &openat::Dir,
+    path: &str,
+    f: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let mode = rootfs.metadata(path)?.stat().st_mode & 0o7777;
+    rootfs
+        .write_file_with_sync(path, mode, |w| -> Result<()> {
+            f(w)?;
+            w.flush()?;
+            Ok(())
+        })
+        .with_context(|| format!("failed to write /{}", path))?;
+    Ok(())
+}
+
+/// Validate users/groups according to treefile check-passwd/check-groups and
+/// id-allocation configuration.
 ///
 /// This is a pre-commit validation hook which ensures that the upcoming
 /// users/groups entries are somehow sane. See treefile `check-passwd` and
@@ -547,6 +695,11 @@ pub fn check_passwd_group_entries(
     old_entities.populate_users_from_treefile(treefile, &repo_previous_rev)?;
     old_entities.populate_groups_from_treefile(treefile, &repo_previous_rev)?;
 
+    // Pinned IDs and allowed ranges from `id-allocation`.
+    if let Some(policy) = treefile.parsed.id_allocation.as_ref() {
+        new_entities.validate_treefile_id_allocation(policy)?;
+    }
+
     // See "man 5 passwd". We just make sure the name and uid/gid match,
     // and that none are missing. Don't care about GECOS/dir/shell.
     new_entities.validate_treefile_check_passwd(
@@ -593,6 +746,57 @@ impl PasswdDB {
         Ok(db)
     }
 
+    /// Populate a new DB from a deployed or installed root, where `/etc`
+    /// takes precedence over `/usr/etc`; missing files are skipped.
+    #[context("Populating users and groups DB")]
+    pub(crate) fn populate_from_root(root: &openat::Dir) -> Result<Self> {
+        let mut db = Self::default();
+        for (name, is_passwd) in &[("passwd", true), ("group", false)] {
+            let etc = format!("etc/{}", name);
+            let usretc = format!("usr/etc/{}", name);
+            let usrlib = format!("usr/lib/{}", name);
+            let config = if root.exists(&etc)? { etc } else { usretc };
+            for path in &[config, usrlib] {
+                if !root.exists(path.as_str())? {
+                    continue;
+                }
+                if *is_passwd {
+                    db.add_passwd_content(root.as_raw_fd(), path)?;
+                } else {
+                    db.add_group_content(root.as_raw_fd(), path)?;
+                }
+            }
+        }
+        Ok(db)
+    }
+
+    /// Populate a new DB with the users and groups of a commit; missing
+    /// files are skipped.
+    #[context("Populating users and groups DB from commit {}", rev)]
+    pub(crate) fn populate_from_commit(repo: &ostree::Repo, rev: &str) -> Result<Self> {
+        let mut db = Self::default();
+        let (root, _) = repo.read_commit(rev, gio::NONE_CANCELLABLE)?;
+        for dir in &["usr/etc", "usr/lib"] {
+            if let Some(f) = root.resolve_relative_path(format!("{}/passwd", dir)) {
+                if f.query_exists(gio::NONE_CANCELLABLE) {
+                    let r = BufReader::new(f.read(gio::NONE_CANCELLABLE)?.into_read());
+                    for user in nameservice::passwd::parse_passwd_content(r)? {
+                        db.users.insert(Uid::from_raw(user.uid), user.name);
+                    }
+                }
+            }
+            if let Some(f) = root.resolve_relative_path(format!("{}/group", dir)) {
+                if f.query_exists(gio::NONE_CANCELLABLE) {
+                    let r = BufReader::new(f.read(gio::NONE_CANCELLABLE)?.into_read());
+                    for group in nameservice::group::parse_group_content(r)? {
+                        db.groups.insert(Gid::from_raw(group.gid), group.name);
+                    }
+                }
+            }
+        }
+        Ok(db)
+    }
+
     /// Lookup user name by ID.
     pub fn lookup_user(&self, uid: u32) -> CxxResult<String> {
         let key = Uid::from_raw(uid);
@@ -615,6 +819,24 @@ impl PasswdDB {
         Ok(groupname)
     }
 
+    /// Lookup user ID by name.
+    pub(crate) fn lookup_user_id(&self, username: &str) -> Result<Uid> {
+        self.users
+            .iter()
+            .find(|(_, name)| name.as_str() == username)
+            .map(|(uid, _)| *uid)
+            .ok_or_else(|| anyhow!("failed to find user '{}'", username))
+    }
+
+    /// Lookup group ID by name.
+    pub(crate) fn lookup_group_id(&self, groupname: &str) -> Result<Gid> {
+        self.groups
+            .iter()
+            .find(|(_, name)| name.as_str() == groupname)
+            .map(|(gid, _)| *gid)
+            .ok_or_else(|| anyhow!("failed to find group '{}'", groupname))
+    }
+
     /// Add content from a `group` file.
     #[context("Parsing groups from /{}", group_path)]
     fn add_group_content(&mut self, rootfs_dfd: i32, group_path: &str) -> Result<()> {
@@ -965,4 +1187,154 @@ impl PasswdEntries {
 
         Ok(())
     }
+
+    #[context("Validating entries according to treefile id-allocation")]
+    fn validate_treefile_id_allocation(&self, policy: &IdAllocation) -> Result<()> {
+        let uid_ranges = policy.uid_ranges()?;
+        let gid_ranges = policy.gid_ranges()?;
+        let in_ranges = |ranges: &[std::ops::RangeInclusive<u32>], id: u32| {
+            ranges.is_empty() || ranges.iter().any(|r| r.contains(&id))
+        };
+
+        let mut violations = Vec::new();
+        for (username, (uid, gid)) in &self.users {
+            match policy.users.as_ref().and_then(|u| u.get(username)) {
+                Some(pinned) => {
+                    let (pinned_uid, pinned_gid) = pinned.ids();
+                    if *uid != pinned_uid {
+                        violations.push(format!(
+                            "user {}: UID {}, pinned to {}",
+                            username, uid, pinned_uid
+                        ));
+                    }
+                    if *gid != pinned_gid {
+                        violations.push(format!(
+                            "user {}: GID {}, pinned to {}",
+                            username, gid, pinned_gid
+                        ));
+                    }
+                }
+                None if !in_ranges(&uid_ranges, uid.as_raw()) => {
+                    violations.push(format!("user {}: UID {} not in uid-ranges", username, uid));
+                }
+                None => {}
+            }
+        }
+        for (groupname, gid) in &self.groups {
+            match policy.groups.as_ref().and_then(|g| g.get(groupname)) {
+                Some(pinned_gid) if gid.as_raw() != *pinned_gid => {
+                    violations.push(format!(
+                        "group {}: GID {}, pinned to {}",
+                        groupname, gid, pinned_gid
+                    ));
+                }
+                Some(_) => {}
+                None if !in_ranges(&gid_ranges, gid.as_raw()) => {
+                    violations.push(format!(
+                        "group {}: GID {} not in gid-ranges",
+                        groupname, gid
+                    ));
+                }
+                None => {}
+            }
+        }
+        if !violations.is_empty() {
+            anyhow::bail!(
+                "{} entries violate id-allocation:\n  {}",
+                violations.len(),
+                violations.join("\n  ")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_shadow_files() -> Result<()> {
+        let temp_rootfs = tempfile::tempdir()?;
+        let rootfs = openat::Dir::open(temp_rootfs.path())?;
+        rootfs.ensure_dir_all("etc", 0o755)?;
+        rootfs.ensure_dir_all("usr/lib", 0o755)?;
+        rootfs.write_file_contents("etc/passwd", 0o644, "root:x:0:0::/root:/bin/bash\n")?;
+        rootfs.write_file_contents("etc/group", 0o644, "root:x:0:\n")?;
+        rootfs.write_file_contents(
+            "usr/lib/passwd",
+            0o644,
+            "bin:x:1:1::/:/sbin/nologin\nfoo:x:990:990::/:/sbin/nologin\n",
+        )?;
+        rootfs.write_file_contents("usr/lib/group", 0o644, "bin:x:1:\nfoo:x:990:bin\n")?;
+        rootfs.write_file_contents(
+            "etc/shadow",
+            0o600,
+            "root:$6$salt$hash:18600:0:99999:7:::\nold:!!:::::::\nbin:*:18600:0:99999:7:::\nroot:!!:::::::\n",
+        )?;
+        rootfs.write_file_contents("etc/gshadow", 0o600, "root:::\nbin:::\n")?;
+
+        sync_shadow_files_impl(&rootfs, "etc")?;
+        assert_eq!(
+            rootfs.read_to_string("etc/shadow")?,
+            "root:$6$salt$hash:18600:0:99999:7:::\nbin:*:18600:0:99999:7:::\nfoo:!!:::::::\n"
+        );
+        assert_eq!(
+            rootfs.read_to_string("etc/gshadow")?,
+            "root:::\nbin:::\nfoo:!::bin\n"
+        );
+        assert_eq!(
+            rootfs.metadata("etc/shadow")?.stat().st_mode & 0o7777,
+            0o600
+        );
+
+        // Nothing to do the second time
+        sync_shadow_files_impl(&rootfs, "etc")?;
+
+        rootfs.write_file_contents("etc/gshadow", 0o600, "root:$6$salt$hash\n")?;
+        let e = sync_shadow_files_impl(&rootfs, "etc").unwrap_err();
+        assert!(!format!("{:#}", e).contains("$6$"), "{:#}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_id_allocation() {
+        let policy: IdAllocation = serde_yaml::from_str(indoc::indoc! {"
+            users:
+                etcd: 850
+                zincati: [851, 852]
+            groups:
+                zincati: 852
+            uid-ranges: [\"0-99\", \"800-899\"]
+            gid-ranges: [\"0-99\", \"800-899\"]
+        "})
+        .unwrap();
+        let add = |entries: &mut PasswdEntries, name: &str, uid: u32, gid: u32| {
+            entries
+                .users
+                .insert(name.into(), (Uid::from_raw(uid), Gid::from_raw(gid)));
+            entries.groups.insert(name.into(), Gid::from_raw(gid));
+        };
+        let mut entries = PasswdEntries::default();
+        add(&mut entries, "bin", 1, 1);
+        add(&mut entries, "etcd", 850, 850);
+        add(&mut entries, "other", 820, 820);
+        entries.validate_treefile_id_allocation(&policy).unwrap();
+
+        add(&mut entries, "zincati", 851, 990);
+        add(&mut entries, "dynamic", 990, 990);
+        let e = entries
+            .validate_treefile_id_allocation(&policy)
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", e),
+            indoc::indoc! {"
+                Validating entries according to treefile id-allocation: 4 entries violate id-allocation:
+                  user dynamic: UID 990 not in uid-ranges
+                  user zincati: GID 990, pinned to 852
+                  group dynamic: GID 990 not in gid-ranges
+                  group zincati: GID 990, pinned to 852"}
+        );
+    }
 }
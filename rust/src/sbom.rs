@@ -0,0 +1,345 @@
+//! Generate a software bill of materials (SBOM) for a compose, in the
+//! CycloneDX JSON format: https://cyclonedx.org/specification/overview/
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use crate::treefile::{sha256_hex, Treefile};
+use anyhow::{Context, Result};
+use libdnf_sys::*;
+use openat_ext::OpenatDirExt;
+use serde_derive::Serialize;
+use std::path::Path;
+use std::pin::Pin;
+
+const SPEC_VERSION: &str = "1.3";
+
+/// Prefix for our custom CycloneDX properties.
+const PROPERTY_PREFIX: &str = "rpm-ostree";
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Sbom {
+    bom_format: &'static str,
+    spec_version: &'static str,
+    version: u32,
+    metadata: SbomMetadata,
+    components: Vec<SbomComponent>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SbomMetadata {
+    tools: Vec<SbomTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    component: Option<SbomComponent>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SbomTool {
+    name: &'static str,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+struct SbomComponent {
+    #[serde(rename = "type")]
+    component_type: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purl: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    licenses: Vec<SbomLicense>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hashes: Vec<SbomHash>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    properties: Vec<SbomProperty>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SbomLicense {
+    expression: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SbomHash {
+    alg: &'static str,
+    content: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct SbomProperty {
+    name: String,
+    value: String,
+}
+
+/// The subset of package data we use.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SbomPackage {
+    pub(crate) name: String,
+    pub(crate) evr: String,
+    pub(crate) arch: String,
+    pub(crate) license: String,
+    pub(crate) sourcerpm: String,
+    /// In the `type:hex` form of `get_repodata_chksum_repr()`.
+    pub(crate) chksum: String,
+}
+
+fn property(name: &str, value: &str) -> SbomProperty {
+    SbomProperty {
+        name: format!("{}:{}", PROPERTY_PREFIX, name),
+        value: value.to_string(),
+    }
+}
+
+/// Map libsolv checksum type names to CycloneDX hash algorithms.
+fn hash_alg(name: &str) -> Option<&'static str> {
+    let alg = match name {
+        "md5" => "MD5",
+        "sha1" => "SHA-1",
+        "sha256" => "SHA-256",
+        "sha384" => "SHA-384",
+        "sha512" => "SHA-512",
+        _ => return None,
+    };
+    Some(alg)
+}
+
+impl SbomPackage {
+    fn nevra(&self) -> String {
+        format!("{}-{}.{}", self.name, self.evr, self.arch)
+    }
+
+    fn to_component(&self) -> SbomComponent {
+        let mut properties = vec![property("nevra", &self.nevra())];
+        if !self.sourcerpm.is_empty() {
+            properties.push(property("sourcerpm", &self.sourcerpm));
+        }
+        properties.push(property("digest", &self.chksum));
+        let mut parts = self.chksum.splitn(2, ':');
+        let hashes = match (parts.next().and_then(hash_alg), parts.next()) {
+            (Some(alg), Some(content)) => vec![SbomHash {
+                alg,
+                content: content.to_string(),
+            }],
+            _ => Vec::new(),
+        };
+        let licenses = if self.license.is_empty() {
+            Vec::new()
+        } else {
+            vec![SbomLicense {
+                expression: self.license.clone(),
+            }]
+        };
+        SbomComponent {
+            component_type: "library",
+            name: self.name.clone(),
+            version: Some(self.evr.clone()),
+            purl: Some(format!(
+                "pkg:rpm/{}@{}?arch={}",
+                self.name, self.evr, self.arch
+            )),
+            licenses,
+            hashes,
+            properties,
+            ..Default::default()
+        }
+    }
+}
+
+/// Build the SBOM for the tree `ostree_ref` from its packages, the digests of
+/// its other inputs as returned by `Treefile::get_input_digests()`, and the
+/// SHA-256 digests of the lockfiles used, keyed by their path.
+pub(crate) fn build_sbom(
+    ostree_ref: &str,
+    mut packages: Vec<SbomPackage>,
+    input_digests: &[(String, String)],
+    lockfiles: &[(String, String)],
+) -> Sbom {
+    packages.sort_by_key(|p| p.nevra());
+    let mut components: Vec<_> = packages.iter().map(|p| p.to_component()).collect();
+    // The treefile itself is described by the overall inputhash.
+    for (name, digest) in input_digests
+        .iter()
+        .filter(|(name, _)| !name.starts_with("config/"))
+    {
+        components.push(SbomComponent {
+            component_type: "file",
+            name: name.clone(),
+            hashes: vec![SbomHash {
+                alg: "SHA-256",
+                content: digest.clone(),
+            }],
+            properties: vec![property("input", name)],
+            ..Default::default()
+        });
+    }
+    for (path, digest) in lockfiles {
+        let basename = Path::new(path)
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_else(|| path.into());
+        let name = format!("lockfile/{}", basename);
+        components.push(SbomComponent {
+            component_type: "file",
+            hashes: vec![SbomHash {
+                alg: "SHA-256",
+                content: digest.clone(),
+            }],
+            properties: vec![property("input", &name), property("path", path)],
+            name,
+            ..Default::default()
+        });
+    }
+    let component = if ostree_ref.is_empty() {
+        None
+    } else {
+        Some(SbomComponent {
+            component_type: "operating-system",
+            name: ostree_ref.to_string(),
+            ..Default::default()
+        })
+    };
+    Sbom {
+        bom_format: "CycloneDX",
+        spec_version: SPEC_VERSION,
+        version: 1,
+        metadata: SbomMetadata {
+            tools: vec![SbomTool { name: "rpm-ostree" }],
+            component,
+        },
+        components,
+    }
+}
+
+/// Write the SBOM for a compose to `filename`, and return its SHA-256 digest.
+pub(crate) fn sbom_write(
+    filename: &str,
+    mut packages: Pin<&mut crate::ffi::CxxGObjectArray>,
+    treefile: &Treefile,
+    lockfiles: &Vec<String>,
+    repo: Pin<&mut crate::ffi::OstreeRepo>,
+) -> Result<String> {
+    let mut pkgs = Vec::new();
+    for i in 0..(packages.as_mut().length()) {
+        let pkg = packages.as_mut().get(i);
+        let pkg_ref = unsafe { &mut *(&mut pkg.0 as *mut _ as *mut libdnf_sys::DnfPackage) };
+        pkgs.push(SbomPackage {
+            name: dnf_package_get_name(pkg_ref)?,
+            evr: dnf_package_get_evr(pkg_ref)?,
+            arch: dnf_package_get_arch(pkg_ref)?,
+            license: dnf_package_get_license(pkg_ref)?,
+            sourcerpm: dnf_package_get_sourcerpm(pkg_ref)?,
+            chksum: crate::ffi::get_repodata_chksum_repr(pkg_ref)?,
+        });
+    }
+    let input_digests: Vec<_> = treefile
+        .get_input_digests(repo)?
+        .into_iter()
+        .map(|m| (m.k, m.v))
+        .collect();
+    let lockfiles = lockfiles
+        .iter()
+        .map(|path| {
+            let buf = std::fs::read(path).with_context(|| format!("Reading {}", path))?;
+            Ok((path.clone(), sha256_hex(&buf)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let sbom = build_sbom(&treefile.get_ostree_ref(), pkgs, &input_digests, &lockfiles);
+    let buf = serde_json::to_vec_pretty(&sbom)?;
+
+    let filename = Path::new(filename);
+    let dir = filename
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let dir = openat::Dir::open(dir)?;
+    let basename = filename.file_name().expect("filename");
+    dir.write_file_contents(basename, 0o644, &buf)?;
+    Ok(sha256_hex(&buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pkg(name: &str, license: &str) -> SbomPackage {
+        SbomPackage {
+            name: name.to_string(),
+            evr: "1.0-1.fc34".to_string(),
+            arch: "x86_64".to_string(),
+            license: license.to_string(),
+            sourcerpm: format!("{}-1.0-1.fc34.src.rpm", name),
+            chksum: "sha256:abcd".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_build_sbom() {
+        let inputs = vec![
+            ("add-files/foo".to_string(), "1234".to_string()),
+            ("config/packages".to_string(), "5678".to_string()),
+        ];
+        let lockfiles = vec![("/srv/compose/lockfile.json".to_string(), "9abc".to_string())];
+        let sbom = build_sbom(
+            "fedora/x86_64/foo",
+            vec![pkg("zsh", ""), pkg("bash", "GPLv3+")],
+            &inputs,
+            &lockfiles,
+        );
+        let v = serde_json::to_value(&sbom).unwrap();
+        assert_eq!(v["bomFormat"], "CycloneDX");
+        assert_eq!(v["metadata"]["component"]["name"], "fedora/x86_64/foo");
+        let components = v["components"].as_array().unwrap();
+        assert_eq!(components.len(), 4);
+        assert_eq!(
+            components[0],
+            json!({
+                "type": "library",
+                "name": "bash",
+                "version": "1.0-1.fc34",
+                "purl": "pkg:rpm/bash@1.0-1.fc34?arch=x86_64",
+                "licenses": [{"expression": "GPLv3+"}],
+                "hashes": [{"alg": "SHA-256", "content": "abcd"}],
+                "properties": [
+                    {"name": "rpm-ostree:nevra", "value": "bash-1.0-1.fc34.x86_64"},
+                    {"name": "rpm-ostree:sourcerpm", "value": "bash-1.0-1.fc34.src.rpm"},
+                    {"name": "rpm-ostree:digest", "value": "sha256:abcd"},
+                ],
+            })
+        );
+        assert!(components[1].get("licenses").is_none());
+        assert_eq!(components[2]["type"], "file");
+        assert_eq!(components[2]["name"], "add-files/foo");
+        assert_eq!(
+            components[3],
+            json!({
+                "type": "file",
+                "name": "lockfile/lockfile.json",
+                "hashes": [{"alg": "SHA-256", "content": "9abc"}],
+                "properties": [
+                    {"name": "rpm-ostree:input", "value": "lockfile/lockfile.json"},
+                    {"name": "rpm-ostree:path", "value": "/srv/compose/lockfile.json"},
+                ],
+            })
+        );
+
+        // The output is deterministic
+        let again = build_sbom(
+            "fedora/x86_64/foo",
+            vec![pkg("bash", "GPLv3+"), pkg("zsh", "")],
+            &inputs,
+            &lockfiles,
+        );
+        assert_eq!(sbom, again);
+    }
+
+    #[test]
+    fn test_hash_alg() {
+        let mut p = pkg("foo", "MIT");
+        p.chksum = "sha512:ef".to_string();
+        assert_eq!(p.to_component().hashes[0].alg, "SHA-512");
+        p.chksum = "unknown:ef".to_string();
+        assert!(p.to_component().hashes.is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+//! Generate JSON Schemas for our declarative input formats, derived
+//! from the same serde types we use to parse them.
+//!
+//! This backs the hidden `rpm-ostree ex-json-schema` CLI.
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use crate::extensions::Extensions;
+use crate::lockfile::LockfileConfig;
+use crate::treefile::TreeComposeConfig;
+use anyhow::Result;
+use schemars::schema::RootSchema;
+use std::io::Write;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ex-json-schema")]
+#[structopt(rename_all = "kebab-case")]
+enum Opt {
+    /// Schema for treefiles, as used by `rpm-ostree compose tree`
+    Treefile,
+    /// Schema for lockfiles, as used by `--ex-lockfile`
+    Lockfile,
+    /// Schema for `extensions.yaml`, as used by `rpm-ostree compose extensions`
+    Extensions,
+}
+
+fn schema_for(opt: &Opt) -> RootSchema {
+    match opt {
+        Opt::Treefile => schemars::schema_for!(TreeComposeConfig),
+        Opt::Lockfile => schemars::schema_for!(LockfileConfig),
+        Opt::Extensions => schemars::schema_for!(Extensions),
+    }
+}
+
+/// Main entrypoint for ex-json-schema
+pub fn entrypoint(args: &[&str]) -> Result<()> {
+    // Skip the main `rpm-ostree` argument
+    let opt = Opt::from_iter(args.iter().skip(1));
+    let schema = schema_for(&opt);
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    serde_json::to_writer_pretty(&mut stdout, &schema)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::treefile::{BootLocation, RpmdbBackend, TREEFILE_KEYS};
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+
+    fn schema_json(opt: Opt) -> Value {
+        serde_json::to_value(schema_for(&opt)).unwrap()
+    }
+
+    fn property_names(schema: &Value) -> BTreeSet<&str> {
+        schema["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect()
+    }
+
+    fn enum_values<'a>(schema: &'a Value, name: &str) -> &'a Vec<Value> {
+        schema["definitions"][name]["enum"].as_array().unwrap()
+    }
+
+    /// Tag values of an internally tagged enum like `CheckPasswd`.
+    fn tag_values<'a>(schema: &'a Value, name: &str) -> Vec<&'a str> {
+        schema["definitions"][name]["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["properties"]["type"]["enum"][0].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_treefile_schema_keys() {
+        // This also keeps the suggestions used by strict mode in sync.
+        let schema = schema_json(Opt::Treefile);
+        let legacy = [
+            "gpg_key",
+            "boot_location",
+            "default_target",
+            "automatic_version_prefix",
+        ];
+        let expected: BTreeSet<&str> = TREEFILE_KEYS.iter().chain(legacy.iter()).copied().collect();
+        assert_eq!(property_names(&schema), expected);
+        // Unknown keys are accepted (but rejected in strict mode).
+        assert_eq!(schema["additionalProperties"], Value::Bool(true));
+    }
+
+    #[test]
+    fn test_treefile_schema_enums() {
+        let schema = schema_json(Opt::Treefile);
+
+        let rpmdb = enum_values(&schema, "RpmdbBackend");
+        assert_eq!(rpmdb, &vec!["bdb", "sqlite", "ndb"]);
+        for v in rpmdb {
+            serde_json::from_value::<RpmdbBackend>(v.clone()).unwrap();
+        }
+
+        let boot_location = enum_values(&schema, "BootLocation");
+        assert_eq!(boot_location, &vec!["new", "modules"]);
+        for v in boot_location {
+            serde_json::from_value::<BootLocation>(v.clone()).unwrap();
+        }
+
+        let expected = vec!["none", "previous", "file", "data"];
+        assert_eq!(tag_values(&schema, "CheckPasswd"), expected);
+        assert_eq!(tag_values(&schema, "CheckGroups"), expected);
+    }
+
+    #[test]
+    fn test_lockfile_schema() {
+        let schema = schema_json(Opt::Lockfile);
+        let expected: BTreeSet<&str> = ["packages", "source-packages", "metadata"]
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(property_names(&schema), expected);
+        // The lockfile denies unknown fields
+        assert_eq!(schema["additionalProperties"], Value::Bool(false));
+    }
+
+    #[test]
+    fn test_extensions_schema() {
+        let schema = schema_json(Opt::Extensions);
+        let expected: BTreeSet<&str> = ["extensions", "repos"].iter().copied().collect();
+        assert_eq!(property_names(&schema), expected);
+        let kinds = enum_values(&schema, "ExtensionKind");
+        assert_eq!(kinds, &vec!["os-extension", "development"]);
+        let extension = property_names(&schema["definitions"]["Extension"]);
+        let expected: BTreeSet<&str> = ["packages", "architectures", "match-base-evr", "kind"]
+            .iter()
+            .copied()
+            .collect();
+        assert_eq!(extension, expected);
+    }
+}
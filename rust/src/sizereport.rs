@@ -0,0 +1,595 @@
+//! Account for the final size of a compose, attributed to the packages
+//! and ostree layers that provided each file.
+//!
+//! This backs the treefile `size-budgets`, `compose tree --write-size-report-to`
+//! and the hidden `rpm-ostree ex-size-diff` CLI which compares two reports.
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use crate::cxxrsutil::*;
+use crate::ffiutil;
+use crate::treefile::{SizeBudget, SizeBudgets, Treefile};
+use anyhow::{anyhow, bail, Context, Result};
+use gio::prelude::*;
+use libdnf_sys::*;
+use openat::SimpleType;
+use openat_ext::OpenatDirExt;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use structopt::StructOpt;
+
+/// Size and number of regular files; hardlinks are only counted once.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct SizeEntry {
+    pub(crate) size: u64,
+    pub(crate) files: u64,
+}
+
+impl SizeEntry {
+    fn add(&mut self, size: u64) {
+        self.size += size;
+        self.files += 1;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) struct SizeReport {
+    pub(crate) total: SizeEntry,
+    pub(crate) packages: BTreeMap<String, SizeEntry>,
+    pub(crate) layers: BTreeMap<String, SizeEntry>,
+    pub(crate) unowned: SizeEntry,
+    /// Everything under the paths from `size-budgets`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) paths: BTreeMap<String, SizeEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Owner {
+    Package(String),
+    Layer(String),
+}
+
+/// Which package or layer provided each path of the rootfs.
+#[derive(Debug, Default)]
+struct Owners {
+    paths: HashMap<String, Owner>,
+    packages: Vec<String>,
+    layers: Vec<String>,
+}
+
+impl Owners {
+    /// Record the owner of `path` unless it already has one; callers add
+    /// owners in order of precedence.
+    fn add(&mut self, path: String, owner: &Owner) {
+        self.paths.entry(path).or_insert_with(|| owner.clone());
+    }
+
+    fn add_package(&mut self, name: &str, files: &[String]) {
+        let owner = Owner::Package(name.to_string());
+        for f in files {
+            if let Some(path) = rpm_path_to_rootfs(f) {
+                self.add(path, &owner);
+            }
+        }
+        self.packages.push(name.to_string());
+    }
+
+    fn add_layer(&mut self, layer: &str, paths: Vec<String>) {
+        let owner = Owner::Layer(layer.to_string());
+        for path in paths {
+            self.add(path, &owner);
+        }
+        self.layers.push(layer.to_string());
+    }
+}
+
+/// Convert a path from the RPM header into where it ends up in the
+/// final rootfs, which has `/etc` in `/usr/etc` and the UsrMove symlinks.
+fn rpm_path_to_rootfs(path: &str) -> Option<String> {
+    let path = path.trim_start_matches('/');
+    let first = path.split('/').next().unwrap_or_default();
+    let r = match first {
+        "" => return None,
+        "etc" => format!("usr/{}", path),
+        "bin" | "sbin" | "lib" | "lib64" => format!("usr/{}", path),
+        _ => path.to_string(),
+    };
+    Some(r)
+}
+
+/// Return all non-directory paths in an ostree commit.
+fn ostree_layer_paths(repo: &ostree::Repo, layer: &str) -> Result<Vec<String>> {
+    let (root, _) = repo.read_commit(layer, gio::NONE_CANCELLABLE)?;
+    let mut paths = Vec::new();
+    walk_ostree_dir(&root, "", &mut paths)?;
+    Ok(paths)
+}
+
+fn walk_ostree_dir(dir: &gio::File, prefix: &str, paths: &mut Vec<String>) -> Result<()> {
+    let children = dir.enumerate_children(
+        "standard::name,standard::type",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        gio::NONE_CANCELLABLE,
+    )?;
+    while let Some(info) = children.next_file(gio::NONE_CANCELLABLE)? {
+        let name = info
+            .get_name()
+            .ok_or_else(|| anyhow!("Missing file name"))?;
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid UTF-8 path: {:?}", name))?;
+        let path = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        if info.get_file_type() == gio::FileType::Directory {
+            let child = dir
+                .resolve_relative_path(name)
+                .ok_or_else(|| anyhow!("Failed to resolve {}", path))?;
+            walk_ostree_dir(&child, &path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Walk the rootfs and attribute the size of each regular file; `paths`
+/// are absolute paths whose contents are also accounted for.
+fn build_report(rootfs: &openat::Dir, owners: &Owners, paths: &[String]) -> Result<SizeReport> {
+    let mut report = SizeReport::default();
+    let prefixes: Vec<_> = paths
+        .iter()
+        .filter_map(|p| Some((p, rpm_path_to_rootfs(p.trim_end_matches('/'))?)))
+        .collect();
+    for path in paths {
+        report.paths.entry(path.clone()).or_default();
+    }
+    // Include everything we were told about, even if nothing is left of it.
+    for name in owners.packages.iter() {
+        report.packages.entry(name.clone()).or_default();
+    }
+    for layer in owners.layers.iter() {
+        report.layers.entry(layer.clone()).or_default();
+    }
+    let mut seen = HashSet::new();
+    crate::lint::walk(rootfs, ".", &mut |path, meta| {
+        if meta.simple_type() != SimpleType::File {
+            return Ok(());
+        }
+        let st = meta.stat();
+        if !seen.insert((st.st_dev, st.st_ino)) {
+            return Ok(());
+        }
+        let size = meta.len();
+        for (name, prefix) in prefixes.iter() {
+            let under = path
+                .strip_prefix(prefix.as_str())
+                .map(|rest| rest.is_empty() || rest.starts_with('/'))
+                .unwrap_or_default();
+            if under {
+                report.paths.get_mut(*name).expect("path").add(size);
+            }
+        }
+        let entry = match owners.paths.get(path) {
+            Some(Owner::Package(name)) => report.packages.entry(name.clone()).or_default(),
+            Some(Owner::Layer(layer)) => report.layers.entry(layer.clone()).or_default(),
+            None => &mut report.unowned,
+        };
+        entry.add(size);
+        report.total.add(size);
+        Ok(())
+    })?;
+    Ok(report)
+}
+
+/// Find the owners of the files in the rootfs; `packages` are those in its rpmdb.
+fn get_owners(
+    mut packages: Pin<&mut crate::ffi::CxxGObjectArray>,
+    treefile: &Treefile,
+    repo: &ostree::Repo,
+) -> Result<Owners> {
+    let mut owners = Owners::default();
+    // Override layers win over packages, which win over regular layers.
+    for layer in treefile.get_ostree_override_layers() {
+        let paths = ostree_layer_paths(repo, &layer)
+            .with_context(|| format!("Reading ostree layer {}", layer))?;
+        owners.add_layer(&layer, paths);
+    }
+    let mut pkgs = Vec::new();
+    for i in 0..(packages.as_mut().length()) {
+        let pkg = packages.as_mut().get(i);
+        let pkg_ref = unsafe { &mut *(&mut pkg.0 as *mut _ as *mut libdnf_sys::DnfPackage) };
+        pkgs.push((
+            dnf_package_get_name(pkg_ref)?,
+            dnf_package_get_files(pkg_ref)?,
+        ));
+    }
+    pkgs.sort();
+    for (name, files) in pkgs.iter() {
+        owners.add_package(name, files);
+    }
+    for layer in treefile.get_ostree_layers() {
+        let paths = ostree_layer_paths(repo, &layer)
+            .with_context(|| format!("Reading ostree layer {}", layer))?;
+        owners.add_layer(&layer, paths);
+    }
+    Ok(owners)
+}
+
+/// The absolute paths with a size budget.
+fn budget_paths(treefile: &Treefile) -> Vec<String> {
+    let budgets = treefile.parsed.size_budgets.as_ref();
+    budgets
+        .and_then(|b| b.paths.as_ref())
+        .map(|p| p.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Write a size report for the final rootfs to `filename`; `packages` are
+/// those in its rpmdb.
+pub(crate) fn size_report_write(
+    filename: &str,
+    rootfs_dfd: i32,
+    packages: Pin<&mut crate::ffi::CxxGObjectArray>,
+    treefile: &Treefile,
+    mut repo: Pin<&mut crate::ffi::OstreeRepo>,
+) -> Result<()> {
+    let rootfs = ffiutil::ffi_view_openat_dir(rootfs_dfd);
+    let repo = &repo.gobj_wrap();
+    let owners = get_owners(packages, treefile, repo)?;
+    let report = build_report(&rootfs, &owners, &budget_paths(treefile))?;
+    let buf = serde_json::to_vec_pretty(&report)?;
+    let filename = Path::new(filename);
+    let dir = filename
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let dir = openat::Dir::open(dir)?;
+    let basename = filename.file_name().expect("filename");
+    dir.write_file_contents(basename, 0o644, &buf)?;
+    println!(
+        "Wrote size report: {} bytes in {} files",
+        report.total.size, report.total.files
+    );
+    Ok(())
+}
+
+/// Return a description of each budget which `report` exceeds.
+fn check_budgets(report: &SizeReport, budgets: &SizeBudgets) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    let mut check = |name: String, entry: Option<&SizeEntry>, budget: &SizeBudget| -> Result<()> {
+        let size = entry.map(|e| e.size).unwrap_or_default();
+        let limit = budget.bytes()?;
+        if size > limit {
+            r.push(format!(
+                "{}: {} bytes exceeds budget of {} by {} bytes",
+                name,
+                size,
+                budget,
+                size - limit
+            ));
+        }
+        Ok(())
+    };
+    if let Some(total) = budgets.total.as_ref() {
+        check("total".to_string(), Some(&report.total), total)?;
+    }
+    for (path, budget) in budgets.paths.iter().flatten() {
+        check(path.clone(), report.paths.get(path), budget)?;
+    }
+    for (name, budget) in budgets.packages.iter().flatten() {
+        // Likely a typo, or a package which was dropped from the tree
+        let entry = report
+            .packages
+            .get(name)
+            .ok_or_else(|| anyhow!("size-budgets: package {} is not installed", name))?;
+        check(format!("package {}", name), Some(entry), budget)?;
+    }
+    Ok(r)
+}
+
+/// Implementation of the treefile `size-budgets` field; `packages` are
+/// those in the rpmdb of the rootfs.
+pub(crate) fn check_size_budgets(
+    rootfs_dfd: i32,
+    packages: Pin<&mut crate::ffi::CxxGObjectArray>,
+    treefile: &Treefile,
+    mut repo: Pin<&mut crate::ffi::OstreeRepo>,
+) -> Result<()> {
+    let budgets = if let Some(b) = treefile.parsed.size_budgets.as_ref() {
+        b
+    } else {
+        return Ok(());
+    };
+    let rootfs = ffiutil::ffi_view_openat_dir(rootfs_dfd);
+    let repo = &repo.gobj_wrap();
+    let owners = get_owners(packages, treefile, repo)?;
+    let report = build_report(&rootfs, &owners, &budget_paths(treefile))?;
+    let exceeded = check_budgets(&report, budgets)?;
+    if !exceeded.is_empty() {
+        let mut msg = format!("{} size budget(s) exceeded:", exceeded.len());
+        for e in exceeded {
+            msg.push_str("\n  ");
+            msg.push_str(&e);
+        }
+        bail!(msg);
+    }
+    println!(
+        "Size budgets: ok ({} bytes in {} files)",
+        report.total.size, report.total.files
+    );
+    Ok(())
+}
+
+/// The change in size of one entry between two reports.
+#[derive(Debug, PartialEq)]
+struct SizeDelta {
+    name: String,
+    old: u64,
+    new: u64,
+}
+
+impl SizeDelta {
+    fn delta(&self) -> i128 {
+        self.new as i128 - self.old as i128
+    }
+}
+
+fn diff_maps(
+    prefix: &str,
+    old: &BTreeMap<String, SizeEntry>,
+    new: &BTreeMap<String, SizeEntry>,
+    out: &mut Vec<SizeDelta>,
+) {
+    for name in old
+        .keys()
+        .chain(new.keys().filter(|k| !old.contains_key(*k)))
+    {
+        out.push(SizeDelta {
+            name: format!("{}/{}", prefix, name),
+            old: old.get(name).map(|e| e.size).unwrap_or_default(),
+            new: new.get(name).map(|e| e.size).unwrap_or_default(),
+        });
+    }
+}
+
+/// Compute the entries whose size changed by at least `threshold` bytes,
+/// largest change first.
+fn diff_reports(old: &SizeReport, new: &SizeReport, threshold: u64) -> Vec<SizeDelta> {
+    let mut r = Vec::new();
+    diff_maps("package", &old.packages, &new.packages, &mut r);
+    diff_maps("layer", &old.layers, &new.layers, &mut r);
+    diff_maps("path", &old.paths, &new.paths, &mut r);
+    r.push(SizeDelta {
+        name: "unowned".to_string(),
+        old: old.unowned.size,
+        new: new.unowned.size,
+    });
+    r.retain(|d| d.delta() != 0 && d.delta().abs() >= threshold as i128);
+    r.sort_by(|a, b| {
+        b.delta()
+            .abs()
+            .cmp(&a.delta().abs())
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    r
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ex-size-diff")]
+#[structopt(rename_all = "kebab-case")]
+struct Opt {
+    /// Only show entries whose size changed by at least this many bytes
+    #[structopt(long, default_value = "0")]
+    threshold: u64,
+    /// Report written by `--write-size-report-to` for the old compose
+    old: String,
+    /// Report written by `--write-size-report-to` for the new compose
+    new: String,
+}
+
+fn read_report(path: &str) -> Result<SizeReport> {
+    let f = std::fs::File::open(path).with_context(|| format!("Opening {}", path))?;
+    serde_json::from_reader(std::io::BufReader::new(f)).with_context(|| format!("Parsing {}", path))
+}
+
+/// Main entrypoint for ex-size-diff
+pub fn entrypoint(args: &[&str]) -> Result<()> {
+    // Skip the main `rpm-ostree` argument
+    let opt = Opt::from_iter(args.iter().skip(1));
+    let old = read_report(&opt.old)?;
+    let new = read_report(&opt.new)?;
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    for d in diff_reports(&old, &new, opt.threshold) {
+        writeln!(
+            stdout,
+            "{:+} {} ({} -> {})",
+            d.delta(),
+            d.name,
+            d.old,
+            d.new
+        )?;
+    }
+    let total = SizeDelta {
+        name: "total".to_string(),
+        old: old.total.size,
+        new: new.total.size,
+    };
+    writeln!(
+        stdout,
+        "Total: {:+} ({} -> {})",
+        total.delta(),
+        total.old,
+        total.new
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_rpm_path_to_rootfs() {
+        let cases = [
+            ("/usr/bin/bash", Some("usr/bin/bash")),
+            ("/etc/bashrc", Some("usr/etc/bashrc")),
+            ("/bin/sh", Some("usr/bin/sh")),
+            ("/lib64/libc.so.6", Some("usr/lib64/libc.so.6")),
+            ("/opt/foo", Some("opt/foo")),
+            ("/", None),
+        ];
+        for (path, expected) in cases.iter() {
+            assert_eq!(rpm_path_to_rootfs(path).as_deref(), *expected);
+        }
+    }
+
+    #[test]
+    fn test_build_report() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let rootfs = &openat::Dir::open(td.path())?;
+        rootfs.ensure_dir_all("usr/bin", 0o755)?;
+        rootfs.ensure_dir_all("usr/etc", 0o755)?;
+        rootfs.ensure_dir_all("usr/lib/modules", 0o755)?;
+        rootfs.write_file_contents("usr/bin/bash", 0o755, "x".repeat(100))?;
+        rootfs.write_file_contents("usr/etc/bashrc", 0o644, "x".repeat(10))?;
+        rootfs.write_file_contents("usr/bin/foo", 0o755, "x".repeat(20))?;
+        rootfs.write_file_contents("usr/lib/modules/initramfs.img", 0o644, "x".repeat(1000))?;
+        rootfs.symlink("usr/bin/sh", "bash")?;
+        // A hardlink is only counted once
+        std::fs::hard_link(
+            td.path().join("usr/bin/foo"),
+            td.path().join("usr/bin/foo2"),
+        )?;
+
+        let mut owners = Owners::default();
+        owners.add_layer("override", strings(&["usr/bin/foo"]));
+        owners.add_package(
+            "bash",
+            &strings(&["/usr/bin/bash", "/etc/bashrc", "/bin/sh"]),
+        );
+        owners.add_package("foo", &strings(&["/usr/bin/foo", "/usr/share/doc/foo"]));
+        owners.add_package("empty", &[]);
+        owners.add_layer("layer", strings(&["usr/bin/bash"]));
+
+        let report = build_report(rootfs, &owners, &strings(&["/usr/lib/modules", "/usr/lib"]))?;
+        assert_eq!(
+            report.total,
+            SizeEntry {
+                size: 1130,
+                files: 4
+            }
+        );
+        assert_eq!(
+            report.packages["bash"],
+            SizeEntry {
+                size: 110,
+                files: 2
+            }
+        );
+        assert_eq!(report.packages["foo"], SizeEntry::default());
+        assert_eq!(report.packages["empty"], SizeEntry::default());
+        assert_eq!(report.layers["override"], SizeEntry { size: 20, files: 1 });
+        assert_eq!(report.layers["layer"], SizeEntry::default());
+        assert_eq!(report.paths["/usr/lib/modules"].size, 1000);
+        assert_eq!(report.paths["/usr/lib"].size, 1000);
+        assert_eq!(
+            report.unowned,
+            SizeEntry {
+                size: 1000,
+                files: 1
+            }
+        );
+
+        // Round trip
+        let buf = serde_json::to_vec(&report)?;
+        let parsed: SizeReport = serde_json::from_slice(&buf)?;
+        assert_eq!(parsed, report);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_reports() {
+        let entry = |size| SizeEntry { size, files: 1 };
+        let mut old = SizeReport::default();
+        old.packages.insert("bash".into(), entry(100));
+        old.packages.insert("removed".into(), entry(50));
+        old.packages.insert("same".into(), entry(10));
+        old.unowned = entry(1000);
+        let mut new = SizeReport::default();
+        new.packages.insert("bash".into(), entry(90));
+        new.packages.insert("added".into(), entry(500));
+        new.packages.insert("same".into(), entry(10));
+        new.layers.insert("layer".into(), entry(5));
+        new.unowned = entry(1000);
+
+        let names = |threshold| -> Vec<(String, i128)> {
+            diff_reports(&old, &new, threshold)
+                .into_iter()
+                .map(|d| (d.name.clone(), d.delta()))
+                .collect()
+        };
+        assert_eq!(
+            names(0),
+            vec![
+                ("package/added".to_string(), 500),
+                ("package/removed".to_string(), -50),
+                ("package/bash".to_string(), -10),
+                ("layer/layer".to_string(), 5),
+            ]
+        );
+        assert_eq!(names(50).len(), 2);
+    }
+
+    #[test]
+    fn test_check_budgets() -> Result<()> {
+        let mut report = SizeReport::default();
+        report.total = SizeEntry {
+            size: 3 << 30,
+            files: 10,
+        };
+        report.packages.insert(
+            "kernel-core".into(),
+            SizeEntry {
+                size: 1000,
+                files: 1,
+            },
+        );
+        report.paths.insert(
+            "/usr/lib/modules".into(),
+            SizeEntry {
+                size: 600 << 20,
+                files: 1,
+            },
+        );
+        let budgets: SizeBudgets = serde_yaml::from_str(indoc::indoc! {"
+            total: 4G
+            paths:
+              /usr/lib/modules: 500M
+            packages:
+              kernel-core: 1000
+        "})?;
+        assert_eq!(
+            check_budgets(&report, &budgets)?,
+            vec!["/usr/lib/modules: 629145600 bytes exceeds budget of 500M by 104857600 bytes"]
+        );
+        report.packages.get_mut("kernel-core").unwrap().size += 1;
+        report.total.size = 5 << 30;
+        assert_eq!(check_budgets(&report, &budgets)?.len(), 3);
+
+        let budgets: SizeBudgets = serde_yaml::from_str("packages: {missing: 1}")?;
+        let e = check_budgets(&report, &budgets).err().unwrap().to_string();
+        assert_eq!(e, "size-budgets: package missing is not installed");
+        Ok(())
+    }
+}
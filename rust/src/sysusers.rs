@@ -0,0 +1,1155 @@
+//! Compose-time user and group allocation from sysusers.d fragments,
+//! configured via the treefile `sysusers` section.
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use crate::cxxrsutil::*;
+use crate::ffiutil;
+use crate::nameservice::group::{parse_group_content, GroupEntry};
+use crate::nameservice::passwd::{parse_passwd_content, PasswdEntry};
+use crate::treefile::{IdAllocation, Treefile, UsersBackend};
+use anyhow::{anyhow, bail, Context, Result};
+use camino::Utf8Path;
+use fn_error_context::context;
+use gio::prelude::InputStreamExtManual;
+use gio::FileExt;
+use openat_ext::OpenatDirExt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::io::{BufRead, BufReader, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::pin::Pin;
+
+/// Directories holding sysusers.d fragments; a fragment in /etc overrides
+/// the one with the same name in /usr/lib.
+static SYSUSERS_DIRS: &[&str] = &["usr/etc/sysusers.d", "usr/lib/sysusers.d"];
+
+/// Dynamic IDs are allocated downwards from here, like systemd-sysusers
+/// does with the default `SYS_UID_MAX`.
+const SYSTEM_ID_MAX: u32 = 999;
+const SYSTEM_ID_MIN: u32 = 1;
+
+/// The defaults of systemd-sysusers.
+const DEFAULT_HOME: &str = "/";
+const DEFAULT_SHELL: &str = "/usr/sbin/nologin";
+
+/// The primary group of a `u` entry given as `uid:gid`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GroupRef {
+    Id(u32),
+    Name(String),
+}
+
+/// A sysusers.d entry; see `man 5 sysusers.d`.  Ranges (`r`) are not
+/// supported, and IDs given as a path are allocated like `-`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SysusersEntry {
+    User {
+        name: String,
+        uid: Option<u32>,
+        group: Option<GroupRef>,
+        gecos: String,
+        home: Option<String>,
+        shell: Option<String>,
+    },
+    Group {
+        name: String,
+        gid: Option<u32>,
+    },
+    Member {
+        user: String,
+        group: String,
+    },
+}
+
+/// Split a sysusers.d line into fields, handling quoting.
+fn split_fields(line: &str) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in line.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => field.get_or_insert_with(String::new).push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                field.get_or_insert_with(String::new);
+            }
+            None if c.is_whitespace() => fields.extend(field.take()),
+            None => field.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("Unterminated quote");
+    }
+    fields.extend(field);
+    Ok(fields)
+}
+
+fn parse_id(s: &str) -> Result<Option<u32>> {
+    match s {
+        "-" => Ok(None),
+        s if s.starts_with('/') => Ok(None),
+        s => s
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow!("Invalid ID {:?}", s)),
+    }
+}
+
+/// Parse a sysusers.d line; returns `None` for comments, blank lines and
+/// ranges.
+pub(crate) fn parse_sysusers_line(line: &str) -> Result<Option<SysusersEntry>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields = split_fields(line)?;
+    let field = |i: usize| fields.get(i).map(|s| s.as_str()).filter(|s| *s != "-");
+    let (kind, name) = match (field(0), field(1)) {
+        (Some(kind), Some(name)) => (kind, name.to_string()),
+        _ => bail!("Missing type or name"),
+    };
+    let entry = match kind {
+        "u" | "u!" => {
+            let mut ids = field(2).unwrap_or("-").splitn(2, ':');
+            let uid = parse_id(ids.next().unwrap_or("-"))?;
+            let group = ids.next().map(|g| match g.parse() {
+                Ok(gid) => GroupRef::Id(gid),
+                Err(_) => GroupRef::Name(g.to_string()),
+            });
+            SysusersEntry::User {
+                name,
+                uid,
+                group,
+                gecos: field(3).unwrap_or_default().to_string(),
+                home: field(4).map(String::from),
+                shell: field(5).map(String::from),
+            }
+        }
+        "g" => SysusersEntry::Group {
+            name,
+            gid: parse_id(field(2).unwrap_or("-"))?,
+        },
+        "m" => SysusersEntry::Member {
+            user: name,
+            group: field(2)
+                .ok_or_else(|| anyhow!("Missing group"))?
+                .to_string(),
+        },
+        "r" => return Ok(None),
+        o => bail!("Unsupported type {:?}", o),
+    };
+    Ok(Some(entry))
+}
+
+fn parse_sysusers_content(r: impl BufRead) -> Result<Vec<SysusersEntry>> {
+    let mut entries = Vec::new();
+    for line in r.lines() {
+        let line = line?;
+        if let Some(entry) = parse_sysusers_line(&line).with_context(|| line.clone())? {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Read all the sysusers.d fragments from the rootfs, sorted by file name.
+fn read_fragments(rootfs: &openat::Dir) -> Result<Vec<SysusersEntry>> {
+    let mut fragments = BTreeMap::new();
+    for dir in SYSUSERS_DIRS {
+        if !rootfs.exists(*dir)? {
+            continue;
+        }
+        for entry in rootfs.list_dir(*dir)? {
+            let entry = entry?;
+            let name: &Utf8Path = Path::new(entry.file_name()).try_into()?;
+            if name.as_str().ends_with(".conf") {
+                fragments
+                    .entry(name.to_string())
+                    .or_insert_with(|| format!("{}/{}", dir, name));
+            }
+        }
+    }
+    let mut entries = Vec::new();
+    for path in fragments.values() {
+        let f = BufReader::new(rootfs.open_file(path.as_str())?);
+        entries.extend(parse_sysusers_content(f).with_context(|| format!("Parsing /{}", path))?);
+    }
+    Ok(entries)
+}
+
+/// User and group IDs by name.
+#[derive(Debug, Default)]
+struct IdMap {
+    uids: HashMap<String, u32>,
+    gids: HashMap<String, u32>,
+}
+
+impl IdMap {
+    fn new(passwd: &[PasswdEntry], group: &[GroupEntry]) -> Self {
+        Self {
+            uids: passwd.iter().map(|p| (p.name.clone(), p.uid)).collect(),
+            gids: group.iter().map(|g| (g.name.clone(), g.gid)).collect(),
+        }
+    }
+}
+
+/// A user or group ID which differs from an earlier one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IdChange {
+    /// `UID` or `GID`
+    kind: &'static str,
+    name: String,
+    old: u32,
+    new: u32,
+    /// Whether the old ID comes from the installed tree, rather than the
+    /// previous commit.
+    in_tree: bool,
+}
+
+impl std::fmt::Display for IdChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let source = if self.in_tree {
+            "installed tree"
+        } else {
+            "previous commit"
+        };
+        write!(
+            f,
+            "{} of {}: {} -> {} (from {})",
+            self.kind, self.name, self.old, self.new, source
+        )
+    }
+}
+
+/// The final users and groups.
+#[derive(Debug, Default)]
+struct Allocation {
+    passwd: Vec<PasswdEntry>,
+    group: Vec<GroupEntry>,
+    changes: Vec<IdChange>,
+}
+
+/// Whether `id` is in `ranges`; any ID is if there are none.
+fn in_ranges(ranges: &[RangeInclusive<u32>], id: u32) -> bool {
+    ranges.is_empty() || ranges.iter().any(|r| r.contains(&id))
+}
+
+/// Tracks which IDs are taken.
+#[derive(Debug, Default)]
+struct IdSpace(BTreeMap<u32, String>);
+
+impl IdSpace {
+    fn is_free(&self, id: u32) -> bool {
+        !self.0.contains_key(&id)
+    }
+
+    fn claim(&mut self, kind: &str, id: u32, name: &str) -> Result<()> {
+        match self.0.get(&id) {
+            Some(other) if other != name => {
+                bail!("{} {} of {} is already used by {}", kind, id, name, other)
+            }
+            _ => {
+                self.0.insert(id, name.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// The highest free ID in `ranges` (the system IDs if there are none),
+    /// which is also free and allowed in `other` if given.
+    fn next_free(
+        &self,
+        ranges: &[RangeInclusive<u32>],
+        other: Option<(&IdSpace, &[RangeInclusive<u32>])>,
+    ) -> Result<u32> {
+        let mut ranges = if ranges.is_empty() {
+            vec![SYSTEM_ID_MIN..=SYSTEM_ID_MAX]
+        } else {
+            ranges.to_vec()
+        };
+        ranges.sort_by_key(|r| std::cmp::Reverse(*r.end()));
+        ranges
+            .into_iter()
+            .flat_map(|r| r.rev())
+            .find(|id| {
+                self.is_free(*id)
+                    && other
+                        .map(|(o, r)| o.is_free(*id) && in_ranges(r, *id))
+                        .unwrap_or(true)
+            })
+            .ok_or_else(|| anyhow!("No free IDs left in the allowed ranges"))
+    }
+}
+
+/// Allocate IDs for `entries`, in order of precedence: the one pinned by
+/// `policy`, from the entry, from the previous commit, from the installed
+/// tree (`current_*`) or else a new one in the ranges of `policy`.  Users
+/// and groups in the installed tree without an entry are kept as is.
+fn allocate(
+    entries: &[SysusersEntry],
+    current_passwd: &[PasswdEntry],
+    current_group: &[GroupEntry],
+    previous: &IdMap,
+    policy: &IdAllocation,
+) -> Result<Allocation> {
+    let current = IdMap::new(current_passwd, current_group);
+    let uid_ranges = policy.uid_ranges()?;
+    let gid_ranges = policy.gid_ranges()?;
+    let pinned_user = |name: &str| {
+        policy
+            .users
+            .as_ref()
+            .and_then(|u| u.get(name))
+            .map(|p| p.ids())
+    };
+    // A user pin also applies to the group of the same name.
+    let pinned_gid = |name: &str| {
+        policy
+            .groups
+            .as_ref()
+            .and_then(|g| g.get(name))
+            .copied()
+            .or_else(|| pinned_user(name).map(|(_, gid)| gid.as_raw()))
+    };
+
+    // The first entry for a name wins.  Each user gets a group of the same
+    // name, unless it has an explicit primary group.
+    let mut users = Vec::new();
+    let mut groups: Vec<(String, Option<u32>)> = Vec::new();
+    let mut members: Vec<(&str, &str)> = Vec::new();
+    for entry in entries {
+        match entry {
+            SysusersEntry::User {
+                name, uid, group, ..
+            } => {
+                if !users
+                    .iter()
+                    .any(|u: &&SysusersEntry| entry_name(u) == name.as_str())
+                {
+                    users.push(entry);
+                }
+                if group.is_none() && !groups.iter().any(|(g, _)| g == name) {
+                    groups.push((name.clone(), *uid));
+                }
+            }
+            SysusersEntry::Group { name, gid } => {
+                if !groups.iter().any(|(g, _)| g == name) {
+                    groups.push((name.clone(), *gid));
+                }
+            }
+            SysusersEntry::Member { user, group } => members.push((user.as_str(), group.as_str())),
+        }
+    }
+    let user_names: HashSet<&str> = users.iter().map(|u| entry_name(u)).collect();
+    let group_names: HashSet<&str> = groups.iter().map(|(g, _)| g.as_str()).collect();
+
+    // Reserve the IDs of everything we don't manage first.
+    let mut uid_space = IdSpace::default();
+    let mut gid_space = IdSpace::default();
+    for p in current_passwd
+        .iter()
+        .filter(|p| !user_names.contains(p.name.as_str()))
+    {
+        uid_space.0.entry(p.uid).or_insert_with(|| p.name.clone());
+    }
+    for g in current_group
+        .iter()
+        .filter(|g| !group_names.contains(g.name.as_str()))
+    {
+        gid_space.0.entry(g.gid).or_insert_with(|| g.name.clone());
+    }
+
+    // Then the pinned IDs, the fixed ones, and the ones we already know about.
+    let known = |explicit: Option<u32>, prev: Option<&u32>, cur: Option<&u32>| {
+        explicit.or_else(|| prev.copied()).or_else(|| cur.copied())
+    };
+    let mut gids: HashMap<&str, u32> = HashMap::new();
+    for (name, _) in groups.iter() {
+        if let Some(gid) = pinned_gid(name) {
+            gid_space.claim("GID", gid, name)?;
+            gids.insert(name.as_str(), gid);
+        }
+    }
+    let mut uids: HashMap<&str, u32> = HashMap::new();
+    for user in users.iter() {
+        let name = entry_name(user);
+        if let Some((uid, _)) = pinned_user(name) {
+            uid_space.claim("UID", uid.as_raw(), name)?;
+            uids.insert(name, uid.as_raw());
+        }
+    }
+    for (name, gid) in groups.iter() {
+        if gids.contains_key(name.as_str()) {
+            continue;
+        }
+        let explicit = if users.iter().any(|u| entry_name(u) == name.as_str()) {
+            // The user's UID is only a preference for its group
+            None
+        } else {
+            *gid
+        };
+        if let Some(gid) = known(explicit, previous.gids.get(name), current.gids.get(name)) {
+            gid_space.claim("GID", gid, name)?;
+            gids.insert(name.as_str(), gid);
+        }
+    }
+    for user in users.iter() {
+        let (name, uid) = match user {
+            SysusersEntry::User { name, uid, .. } => (name.as_str(), *uid),
+            _ => unreachable!(),
+        };
+        if uids.contains_key(name) {
+            continue;
+        }
+        if let Some(uid) = known(uid, previous.uids.get(name), current.uids.get(name)) {
+            uid_space.claim("UID", uid, name)?;
+            uids.insert(name, uid);
+        }
+    }
+
+    // Allocate the rest, preferring the same ID for a user and its group.
+    for user in users.iter() {
+        let (name, uid, group) = match user {
+            SysusersEntry::User {
+                name, uid, group, ..
+            } => (name.as_str(), *uid, group),
+            _ => unreachable!(),
+        };
+        if !uids.contains_key(name) {
+            let own_group = group.is_none();
+            let id = match gids.get(name) {
+                Some(gid)
+                    if own_group && uid_space.is_free(*gid) && in_ranges(&uid_ranges, *gid) =>
+                {
+                    *gid
+                }
+                _ if own_group && !gids.contains_key(name) => uid_space
+                    .next_free(&uid_ranges, Some((&gid_space, &gid_ranges)))
+                    .or_else(|_| uid_space.next_free(&uid_ranges, None))?,
+                _ => uid_space.next_free(&uid_ranges, None)?,
+            };
+            uid_space.claim("UID", id, name)?;
+            uids.insert(name, id);
+        }
+        if group.is_none() && !gids.contains_key(name) {
+            let preferred = uid.unwrap_or(uids[name]);
+            let id = if gid_space.is_free(preferred) && in_ranges(&gid_ranges, preferred) {
+                preferred
+            } else {
+                gid_space.next_free(&gid_ranges, None)?
+            };
+            gid_space.claim("GID", id, name)?;
+            gids.insert(name, id);
+        }
+    }
+    for (name, gid) in groups.iter() {
+        if !gids.contains_key(name.as_str()) {
+            let id = match gid {
+                Some(gid) if gid_space.is_free(*gid) && in_ranges(&gid_ranges, *gid) => *gid,
+                _ => gid_space.next_free(&gid_ranges, None)?,
+            };
+            gid_space.claim("GID", id, name)?;
+            gids.insert(name.as_str(), id);
+        }
+    }
+
+    let lookup_gid = |name: &str| {
+        gids.get(name)
+            .or_else(|| current.gids.get(name))
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown group {}", name))
+    };
+
+    let mut ret = Allocation::default();
+    for user in users.iter() {
+        if let SysusersEntry::User {
+            name,
+            group,
+            gecos,
+            home,
+            shell,
+            ..
+        } = user
+        {
+            let gid = match group {
+                Some(GroupRef::Id(gid)) => *gid,
+                Some(GroupRef::Name(g)) => lookup_gid(g)?,
+                None => gids[name.as_str()],
+            };
+            ret.passwd.push(PasswdEntry {
+                name: name.clone(),
+                passwd: "x".to_string(),
+                uid: uids[name.as_str()],
+                gid,
+                gecos: gecos.clone(),
+                home_dir: home.as_deref().unwrap_or(DEFAULT_HOME).to_string(),
+                shell: shell.as_deref().unwrap_or(DEFAULT_SHELL).to_string(),
+            });
+        }
+    }
+    ret.passwd.extend(
+        current_passwd
+            .iter()
+            .filter(|p| !user_names.contains(p.name.as_str()))
+            .cloned(),
+    );
+    for (name, _) in groups.iter() {
+        let users = current_group
+            .iter()
+            .find(|g| &g.name == name)
+            .map(|g| g.users.clone())
+            .unwrap_or_default();
+        ret.group.push(GroupEntry {
+            name: name.clone(),
+            passwd: "x".to_string(),
+            gid: gids[name.as_str()],
+            users,
+        });
+    }
+    ret.group.extend(
+        current_group
+            .iter()
+            .filter(|g| !group_names.contains(g.name.as_str()))
+            .cloned(),
+    );
+    for (user, group) in members {
+        let entry = ret
+            .group
+            .iter_mut()
+            .find(|g| g.name == group)
+            .ok_or_else(|| anyhow!("Unknown group {} for member {}", group, user))?;
+        if !entry.users.iter().any(|u| u == user) {
+            entry.users.push(user.to_string());
+        }
+    }
+    for g in ret.group.iter_mut() {
+        g.users.retain(|u| !u.is_empty());
+    }
+
+    // Root stays in /etc.
+    ret.passwd.retain(|p| p.uid != 0);
+    ret.group.retain(|g| g.gid != 0);
+    ret.passwd
+        .sort_by(|a, b| (a.uid, &a.name).cmp(&(b.uid, &b.name)));
+    ret.group
+        .sort_by(|a, b| (a.gid, &a.name).cmp(&(b.gid, &b.name)));
+
+    for (kind, ids, prev, cur) in &[
+        ("UID", &uids, &previous.uids, &current.uids),
+        ("GID", &gids, &previous.gids, &current.gids),
+    ] {
+        let mut names: Vec<_> = ids.keys().collect();
+        names.sort();
+        for name in names {
+            let new = ids[*name];
+            let olds = [(prev.get(*name), false), (cur.get(*name), true)];
+            for (old, in_tree) in olds.iter() {
+                if let Some(old) = old.filter(|old| **old != new) {
+                    ret.changes.push(IdChange {
+                        kind: *kind,
+                        name: name.to_string(),
+                        old: *old,
+                        new,
+                        in_tree: *in_tree,
+                    });
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+
+fn entry_name(entry: &SysusersEntry) -> &str {
+    match entry {
+        SysusersEntry::User { name, .. } | SysusersEntry::Group { name, .. } => name,
+        SysusersEntry::Member { user, .. } => user,
+    }
+}
+
+/// Read the users and groups of the previous commit, if any.
+fn previous_ids(repo_previous_rev: Option<(&ostree::Repo, &str)>) -> Result<IdMap> {
+    let (repo, previous_rev) = match repo_previous_rev {
+        Some(v) => v,
+        None => return Ok(IdMap::default()),
+    };
+    let (prev_root, _name) = repo.read_commit(previous_rev, gio::NONE_CANCELLABLE)?;
+    let read = |path: &str| -> Result<Option<BufReader<_>>> {
+        match prev_root.resolve_relative_path(path) {
+            Some(f) if f.query_exists(gio::NONE_CANCELLABLE) => Ok(Some(BufReader::new(
+                f.read(gio::NONE_CANCELLABLE)?.into_read(),
+            ))),
+            _ => Ok(None),
+        }
+    };
+    let passwd = match read("usr/lib/passwd")? {
+        Some(r) => parse_passwd_content(r)?,
+        None => Vec::new(),
+    };
+    let group = match read("usr/lib/group")? {
+        Some(r) => parse_group_content(r)?,
+        None => Vec::new(),
+    };
+    Ok(IdMap::new(&passwd, &group))
+}
+
+/// Implementation of the treefile `sysusers` field, after installing packages:
+/// regenerate /usr/lib/passwd and /usr/lib/group from the sysusers.d
+/// fragments in the tree and the treefile entries, verifying that the IDs
+/// in the installed tree are kept.
+pub fn sysusers_compose(
+    mut ffi_repo: Pin<&mut crate::ffi::OstreeRepo>,
+    rootfs_dfd: i32,
+    treefile: &Treefile,
+    previous_rev: &str,
+) -> CxxResult<()> {
+    let config = match treefile.parsed.sysusers.as_ref() {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    let rootfs = ffiutil::ffi_view_openat_dir(rootfs_dfd);
+    let repo = ffi_repo.gobj_wrap();
+    // C side uses "" for None
+    let repo_previous_rev = if previous_rev.is_empty() {
+        None
+    } else {
+        Some((&repo, previous_rev))
+    };
+    let default_policy = IdAllocation::default();
+    sysusers_compose_impl(
+        &rootfs,
+        config.entries.as_deref().unwrap_or_default(),
+        repo_previous_rev,
+        treefile
+            .parsed
+            .id_allocation
+            .as_ref()
+            .unwrap_or(&default_policy),
+    )?;
+    if treefile.get_users_backend() == UsersBackend::Userdb {
+        crate::composepost::write_userdb_records(&rootfs)?;
+    }
+    Ok(())
+}
+
+#[context("Allocating users and groups from sysusers.d")]
+fn sysusers_compose_impl(
+    rootfs: &openat::Dir,
+    treefile_entries: &[String],
+    repo_previous_rev: Option<(&ostree::Repo, &str)>,
+    policy: &IdAllocation,
+) -> Result<()> {
+    static PASSWD_PATH: &str = "usr/lib/passwd";
+    static GROUP_PATH: &str = "usr/lib/group";
+
+    // Treefile entries take precedence over the fragments.
+    let mut entries = parse_treefile_entries(treefile_entries)?;
+    entries.extend(read_fragments(rootfs)?);
+
+    let current_passwd = parse_passwd_content(BufReader::new(rootfs.open_file(PASSWD_PATH)?))?;
+    let current_group = parse_group_content(BufReader::new(rootfs.open_file(GROUP_PATH)?))?;
+    let previous = previous_ids(repo_previous_rev)?;
+    let allocation = allocate(&entries, &current_passwd, &current_group, &previous, policy)?;
+
+    if !allocation.changes.is_empty() {
+        println!("Changed user and group IDs:");
+        for change in allocation.changes.iter() {
+            println!("  {}", change);
+        }
+    }
+    // The installed tree was set up (e.g. by `useradd` in scriptlets) with the
+    // IDs seeded before installing packages; changing them now would leave
+    // anything created with the old ones behind.
+    let in_tree: Vec<_> = allocation.changes.iter().filter(|c| c.in_tree).collect();
+    if !in_tree.is_empty() {
+        bail!(
+            "IDs differ from the installed tree: {}; add the entries to the treefile `sysusers` section to allocate them before installing packages",
+            in_tree
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    write_passwd(rootfs, PASSWD_PATH, &allocation.passwd)?;
+    write_group(rootfs, GROUP_PATH, &allocation.group)?;
+    println!(
+        "Wrote {} users and {} groups",
+        allocation.passwd.len(),
+        allocation.group.len()
+    );
+    crate::passwd::sync_shadow_files_impl(rootfs, "usr/etc")?;
+    Ok(())
+}
+
+/// Allocate the users and groups of the treefile `sysusers` entries before
+/// installing packages, and seed them into the passwd and group files
+/// injected by the passwd prep path, so that `useradd` in scriptlets finds
+/// them rather than allocating other IDs.  Like the rest of those files, they
+/// are then migrated to /usr/lib, where the pass after installation only has
+/// to verify them.
+pub(crate) fn sysusers_compose_prep(
+    rootfs: &openat::Dir,
+    treefile: &Treefile,
+    repo_previous_rev: Option<(&ostree::Repo, &str)>,
+    unified_core: bool,
+) -> Result<()> {
+    let config = match treefile.parsed.sysusers.as_ref() {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    // Data from the previous commit always goes to /etc.
+    let dest = if unified_core { "usr/etc" } else { "etc" };
+    let etc_dir = if rootfs.exists(format!("{}/passwd", dest).as_str())? {
+        dest
+    } else {
+        "etc"
+    };
+    let default_policy = IdAllocation::default();
+    sysusers_compose_prep_impl(
+        rootfs,
+        config.entries.as_deref().unwrap_or_default(),
+        repo_previous_rev,
+        treefile
+            .parsed
+            .id_allocation
+            .as_ref()
+            .unwrap_or(&default_policy),
+        etc_dir,
+    )
+}
+
+#[context("Seeding users and groups from sysusers entries")]
+fn sysusers_compose_prep_impl(
+    rootfs: &openat::Dir,
+    treefile_entries: &[String],
+    repo_previous_rev: Option<(&ostree::Repo, &str)>,
+    policy: &IdAllocation,
+    etc_dir: &str,
+) -> Result<()> {
+    let passwd_path = format!("{}/passwd", etc_dir);
+    let group_path = format!("{}/group", etc_dir);
+    let (seed_passwd, seed_group) = match (
+        rootfs.open_file_optional(passwd_path.as_str())?,
+        rootfs.open_file_optional(group_path.as_str())?,
+    ) {
+        (Some(p), Some(g)) => (
+            parse_passwd_content(BufReader::new(p))?,
+            parse_group_content(BufReader::new(g))?,
+        ),
+        _ => {
+            // Injecting only our entries would replace the ones of the
+            // `setup` package.
+            println!("No passwd and group to seed; allocating sysusers entries after installation");
+            return Ok(());
+        }
+    };
+
+    // Groups named by a user may only come with the packages, and members
+    // are added once everything is installed.
+    let entries = parse_treefile_entries(treefile_entries)?;
+    let known_groups: HashSet<&str> = entries
+        .iter()
+        .filter_map(|e| match e {
+            SysusersEntry::User {
+                name, group: None, ..
+            } => Some(name.as_str()),
+            SysusersEntry::Group { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .chain(seed_group.iter().map(|g| g.name.as_str()))
+        .collect();
+    let entries: Vec<SysusersEntry> = entries
+        .iter()
+        .filter(|e| match e {
+            SysusersEntry::User {
+                group: Some(GroupRef::Name(g)),
+                ..
+            } => known_groups.contains(g.as_str()),
+            SysusersEntry::Member { .. } => false,
+            _ => true,
+        })
+        .cloned()
+        .collect();
+
+    let previous = previous_ids(repo_previous_rev)?;
+    let allocation = allocate(&entries, &seed_passwd, &seed_group, &previous, policy)?;
+    let (user_names, group_names) = entry_names(&entries);
+    let seeded_passwd: Vec<_> = allocation
+        .passwd
+        .into_iter()
+        .filter(|p| user_names.contains(p.name.as_str()))
+        .collect();
+    let seeded_group: Vec<_> = allocation
+        .group
+        .into_iter()
+        .filter(|g| group_names.contains(g.name.as_str()))
+        .collect();
+    if seeded_passwd.is_empty() && seeded_group.is_empty() {
+        return Ok(());
+    }
+
+    // Replace any existing entries with the allocated ones.
+    let mut passwd: Vec<_> = seed_passwd
+        .into_iter()
+        .filter(|e| !seeded_passwd.iter().any(|p| p.name == e.name))
+        .collect();
+    passwd.extend(seeded_passwd.iter().cloned());
+    let mut group: Vec<_> = seed_group
+        .into_iter()
+        .filter(|e| !seeded_group.iter().any(|g| g.name == e.name))
+        .collect();
+    group.extend(seeded_group.iter().cloned());
+    write_passwd(rootfs, &passwd_path, &passwd)?;
+    write_group(rootfs, &group_path, &group)?;
+    println!(
+        "Seeded {} users and {} groups from sysusers entries into /{}",
+        seeded_passwd.len(),
+        seeded_group.len(),
+        etc_dir
+    );
+    Ok(())
+}
+
+fn parse_treefile_entries(treefile_entries: &[String]) -> Result<Vec<SysusersEntry>> {
+    let mut entries = Vec::new();
+    for line in treefile_entries {
+        entries.extend(parse_sysusers_line(line).with_context(|| line.clone())?);
+    }
+    Ok(entries)
+}
+
+/// The names of the users and groups created by `entries`.
+fn entry_names(entries: &[SysusersEntry]) -> (HashSet<&str>, HashSet<&str>) {
+    let mut users = HashSet::new();
+    let mut groups = HashSet::new();
+    for entry in entries {
+        match entry {
+            SysusersEntry::User { name, group, .. } => {
+                users.insert(name.as_str());
+                if group.is_none() {
+                    groups.insert(name.as_str());
+                }
+            }
+            SysusersEntry::Group { name, .. } => {
+                groups.insert(name.as_str());
+            }
+            SysusersEntry::Member { .. } => {}
+        }
+    }
+    (users, groups)
+}
+
+fn write_passwd(rootfs: &openat::Dir, path: &str, entries: &[PasswdEntry]) -> Result<()> {
+    rootfs
+        .write_file_with_sync(path, 0o644, |w| -> Result<()> {
+            for entry in entries {
+                entry.to_writer(w)?;
+            }
+            w.flush()?;
+            Ok(())
+        })
+        .with_context(|| format!("Writing /{}", path))?;
+    Ok(())
+}
+
+fn write_group(rootfs: &openat::Dir, path: &str, entries: &[GroupEntry]) -> Result<()> {
+    rootfs
+        .write_file_with_sync(path, 0o644, |w| -> Result<()> {
+            for entry in entries {
+                entry.to_writer(w)?;
+            }
+            w.flush()?;
+            Ok(())
+        })
+        .with_context(|| format!("Writing /{}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    fn parse(lines: &str) -> Vec<SysusersEntry> {
+        parse_sysusers_content(lines.as_bytes()).unwrap()
+    }
+
+    fn user(name: &str, uid: u32, gid: u32) -> PasswdEntry {
+        PasswdEntry {
+            name: name.to_string(),
+            passwd: "x".to_string(),
+            uid,
+            gid,
+            gecos: String::new(),
+            home_dir: DEFAULT_HOME.to_string(),
+            shell: DEFAULT_SHELL.to_string(),
+        }
+    }
+
+    fn group(name: &str, gid: u32) -> GroupEntry {
+        GroupEntry {
+            name: name.to_string(),
+            passwd: "x".to_string(),
+            gid,
+            users: vec![String::new()],
+        }
+    }
+
+    #[test]
+    fn test_parse_sysusers() {
+        let entries = parse(indoc::indoc! {r#"
+            # comment
+            u root 0 "Super User" /root /bin/bash
+            u dbus 81:dbus 'System Message Bus'
+            g wheel 10
+            g input -
+            m core wheel
+            r - 500-900
+        "#});
+        assert_eq!(
+            entries,
+            vec![
+                SysusersEntry::User {
+                    name: "root".into(),
+                    uid: Some(0),
+                    group: None,
+                    gecos: "Super User".into(),
+                    home: Some("/root".into()),
+                    shell: Some("/bin/bash".into()),
+                },
+                SysusersEntry::User {
+                    name: "dbus".into(),
+                    uid: Some(81),
+                    group: Some(GroupRef::Name("dbus".into())),
+                    gecos: "System Message Bus".into(),
+                    home: None,
+                    shell: None,
+                },
+                SysusersEntry::Group {
+                    name: "wheel".into(),
+                    gid: Some(10),
+                },
+                SysusersEntry::Group {
+                    name: "input".into(),
+                    gid: None,
+                },
+                SysusersEntry::Member {
+                    user: "core".into(),
+                    group: "wheel".into(),
+                },
+            ]
+        );
+        for invalid in &["u", "x foo", "u foo bar", "m foo", "u foo - \"unterminated"] {
+            assert!(parse_sysusers_line(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_allocate() {
+        let entries = parse(indoc::indoc! {r#"
+            u root 0
+            u fixed 500
+            u prev -
+            u installed -
+            u new -
+            g shared -
+            m new shared
+        "#});
+        let current_passwd = vec![
+            user("installed", 998, 998),
+            user("legacy", 999, 999),
+            user("prev", 990, 990),
+        ];
+        let current_group = vec![group("installed", 998), group("legacy", 999)];
+        let previous = IdMap::new(&[user("prev", 980, 980)], &[group("prev", 980)]);
+        let policy = IdAllocation::default();
+
+        let a = allocate(
+            &entries,
+            &current_passwd,
+            &current_group,
+            &previous,
+            &policy,
+        )
+        .unwrap();
+        let uids: Vec<_> = a.passwd.iter().map(|p| (p.name.as_str(), p.uid)).collect();
+        assert_eq!(
+            uids,
+            &[
+                ("fixed", 500),
+                ("prev", 980),
+                ("new", 997),
+                ("installed", 998),
+                ("legacy", 999)
+            ]
+        );
+        let gids: Vec<_> = a.group.iter().map(|g| (g.name.as_str(), g.gid)).collect();
+        assert_eq!(
+            gids,
+            &[
+                ("fixed", 500),
+                ("prev", 980),
+                ("shared", 996),
+                ("new", 997),
+                ("installed", 998),
+                ("legacy", 999)
+            ]
+        );
+        let shared = a.group.iter().find(|g| g.name == "shared").unwrap();
+        assert_eq!(shared.users, &["new"]);
+        assert_eq!(
+            a.changes.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            &["UID of prev: 990 -> 980 (from installed tree)"]
+        );
+
+        // Allocation is deterministic
+        let b = allocate(
+            &entries,
+            &current_passwd,
+            &current_group,
+            &previous,
+            &policy,
+        )
+        .unwrap();
+        assert_eq!(a.passwd, b.passwd);
+        assert_eq!(a.group, b.group);
+
+        // Conflicting fixed IDs are an error
+        let entries = parse("u fixed 999\n");
+        assert!(allocate(
+            &entries,
+            &current_passwd,
+            &current_group,
+            &IdMap::default(),
+            &policy
+        )
+        .is_err());
+        let entries = parse("m foo nosuchgroup\n");
+        assert!(allocate(
+            &entries,
+            &current_passwd,
+            &current_group,
+            &IdMap::default(),
+            &policy
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_sysusers_compose_prep() -> Result<()> {
+        let temp_rootfs = tempfile::tempdir()?;
+        let rootfs = openat::Dir::open(temp_rootfs.path())?;
+        rootfs.create_dir("etc", 0o755)?;
+        rootfs.write_file_contents(
+            "etc/passwd",
+            0o644,
+            "root:x:0:0:root:/root:/bin/bash\nold:x:990:990::/:/sbin/nologin\nbin:x:1:1::/:/sbin/nologin\n",
+        )?;
+        rootfs.write_file_contents("etc/group", 0o644, "root:x:0:\nold:x:990:\nbin:x:1:\n")?;
+        let entries: Vec<String> = [
+            "u root 0",
+            "u old 500",
+            "u new -",
+            "u web -:web",
+            "m new bin",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let policy = IdAllocation::default();
+        sysusers_compose_prep_impl(&rootfs, &entries, None, &policy, "etc")?;
+
+        // The allocated entries replace the injected ones
+        assert_eq!(
+            rootfs.read_to_string("etc/passwd")?,
+            "root:x:0:0:root:/root:/bin/bash\nbin:x:1:1::/:/sbin/nologin\nold:x:500:990::/:/usr/sbin/nologin\nnew:x:999:999::/:/usr/sbin/nologin\n"
+        );
+        assert_eq!(
+            rootfs.read_to_string("etc/group")?,
+            "root:x:0:\nbin:x:1:\nold:x:990:\nnew:x:999:\n"
+        );
+
+        // A scriptlet adds a user (and finds `new` already there), /etc is
+        // migrated to /usr/lib and a package ships a fragment; the seeded IDs
+        // are then only verified
+        let passwd = rootfs.read_to_string("etc/passwd")? + "pkg:x:998:998::/:/sbin/nologin\n";
+        rootfs.write_file_contents("etc/passwd", 0o644, passwd)?;
+        let group = rootfs.read_to_string("etc/group")? + "pkg:x:998:\n";
+        rootfs.write_file_contents("etc/group", 0o644, group)?;
+        rootfs.create_dir("usr", 0o755)?;
+        rootfs.local_rename("etc", "usr/etc")?;
+        rootfs.ensure_dir_all("usr/lib/sysusers.d", 0o755)?;
+        crate::passwd::migrate_passwd_except_root(rootfs.as_raw_fd())?;
+        crate::passwd::migrate_group_except_root(rootfs.as_raw_fd(), &vec![])?;
+        rootfs.write_file_contents("usr/lib/sysusers.d/web.conf", 0o644, "g web -\n")?;
+        sysusers_compose_impl(&rootfs, &entries, None, &policy)?;
+        assert_eq!(
+            rootfs.read_to_string("usr/lib/passwd")?,
+            "bin:x:1:1::/:/sbin/nologin\nold:x:500:990::/:/usr/sbin/nologin\nweb:x:997:997::/:/usr/sbin/nologin\npkg:x:998:998::/:/sbin/nologin\nnew:x:999:999::/:/usr/sbin/nologin\n"
+        );
+        assert_eq!(
+            rootfs.read_to_string("usr/etc/passwd")?,
+            "root:x:0:0:root:/root:/bin/bash\n"
+        );
+
+        // Without injected files, nothing is seeded
+        let temp_rootfs = tempfile::tempdir()?;
+        let empty = openat::Dir::open(temp_rootfs.path())?;
+        sysusers_compose_prep_impl(&empty, &entries, None, &policy, "etc")?;
+        assert!(!empty.exists("etc/passwd")?);
+
+        // An ID changing from the installed tree is an error
+        rootfs.write_file_contents("usr/lib/passwd", 0o644, "old:x:501:990::/:/sbin/nologin\n")?;
+        let e = sysusers_compose_impl(&rootfs, &entries, None, &policy).unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("UID of old: 501 -> 500 (from installed tree)"),
+            "{:#}",
+            e
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sysusers_id_allocation() -> Result<()> {
+        use crate::treefile::tests::{new_test_treefile, VALID_PRELUDE};
+        let workdir = tempfile::tempdir()?;
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc::indoc! {r#"
+            sysusers:
+                entries:
+                    - "u pinned -"
+                    - "u fixed 500"
+                    - "u new -"
+                    - "g grp -"
+            id-allocation:
+                users:
+                    pinned: [900, 901]
+                groups:
+                    grp: 850
+                uid-ranges: ["800-809"]
+                gid-ranges: ["800-809", "850"]
+        "#});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None)?;
+        let temp_rootfs = tempfile::tempdir()?;
+        let rootfs = openat::Dir::open(temp_rootfs.path())?;
+        rootfs.create_dir("etc", 0o755)?;
+        rootfs.write_file_contents("etc/passwd", 0o644, "root:x:0:0:root:/root:/bin/bash\n")?;
+        rootfs.write_file_contents("etc/group", 0o644, "root:x:0:\n")?;
+        sysusers_compose_prep(&rootfs, &tf, None, false)?;
+
+        // Pins come first, then the entries; the rest is allocated in the ranges
+        assert_eq!(
+            rootfs.read_to_string("etc/passwd")?,
+            "root:x:0:0:root:/root:/bin/bash\nfixed:x:500:809::/:/usr/sbin/nologin\nnew:x:808:808::/:/usr/sbin/nologin\npinned:x:900:901::/:/usr/sbin/nologin\n"
+        );
+        assert_eq!(
+            rootfs.read_to_string("etc/group")?,
+            "root:x:0:\nnew:x:808:\nfixed:x:809:\ngrp:x:850:\npinned:x:901:\n"
+        );
+        Ok(())
+    }
+}
@@ -42,6 +42,25 @@ struct SyntheticUpgradeOpts {
     commit_version: Option<String>,
 }
 
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct ComposeTwiceOpts {
+    /// Path to OSTree repository to compose into
+    #[structopt(long)]
+    repo: String,
+
+    /// Value for SOURCE_DATE_EPOCH, if not set in the environment
+    #[structopt(long)]
+    source_date_epoch: Option<i64>,
+
+    /// Treefile to compose
+    treefile: String,
+
+    /// Additional arguments for `rpm-ostree compose tree`, e.g. `--cachedir`
+    #[structopt(last = true)]
+    compose_args: Vec<String>,
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "testutils")]
 #[structopt(rename_all = "kebab-case")]
@@ -52,6 +71,8 @@ enum Opt {
     ValidateParseStatus,
     /// Test that we can 🐄
     Moo,
+    /// Compose a treefile twice in reproducible mode and report any differences
+    ComposeTwice(ComposeTwiceOpts),
 }
 
 /// Returns `true` if a file is ELF; see https://en.wikipedia.org/wiki/Executable_and_Linkable_Format
@@ -261,12 +282,70 @@ fn test_moo() -> Result<()> {
     Ok(())
 }
 
+/// Run `rpm-ostree compose tree` and return the new commit.
+fn compose_once(opts: &ComposeTwiceOpts, epoch: i64) -> Result<String> {
+    let tempdir = tempfile::tempdir()?;
+    let commitid_path = tempdir.path().join("commitid");
+    // Always create a new parentless commit, and don't update the ref.
+    let r = Command::new("rpm-ostree")
+        .args(&["compose", "tree", "--force-nocache", "--no-parent"])
+        .arg(format!("--repo={}", opts.repo))
+        .arg(format!("--write-commitid-to={}", commitid_path.display()))
+        .args(&opts.compose_args)
+        .arg(&opts.treefile)
+        .env("SOURCE_DATE_EPOCH", epoch.to_string())
+        .status()?;
+    if !r.success() {
+        anyhow::bail!("Compose failed: {:?}", r);
+    }
+    Ok(fs::read_to_string(&commitid_path)?.trim().to_string())
+}
+
+#[context("Verifying reproducible compose")]
+fn compose_twice(opts: &ComposeTwiceOpts) -> Result<()> {
+    let epoch = match (crate::utils::source_date_epoch()?, opts.source_date_epoch) {
+        (_, Some(epoch)) => epoch,
+        (Some(epoch), None) => epoch,
+        (None, None) => anyhow::bail!("SOURCE_DATE_EPOCH is not set"),
+    };
+    let first = compose_once(opts, epoch).context("First compose")?;
+    let second = compose_once(opts, epoch).context("Second compose")?;
+    if first == second {
+        println!("Composes are identical: {}", first);
+        return Ok(());
+    }
+
+    let repo = ostree::Repo::new_for_path(&opts.repo);
+    repo.open(gio::NONE_CANCELLABLE)?;
+    let diff = ostree_ext::diff::diff(&repo, &first, &second, None::<&str>)?;
+    let changes = [
+        ("A", &diff.added_dirs),
+        ("A", &diff.added_files),
+        ("D", &diff.removed_dirs),
+        ("D", &diff.removed_files),
+        ("M", &diff.changed_dirs),
+        ("M", &diff.changed_files),
+    ];
+    let mut n = 0;
+    for (kind, paths) in changes.iter() {
+        for path in paths.iter() {
+            println!("{} {}", kind, path);
+            n += 1;
+        }
+    }
+    if n == 0 {
+        println!("Trees are identical; commit metadata differs");
+    }
+    anyhow::bail!("Composes differ: {} {}", first, second);
+}
+
 pub(crate) fn testutils_entrypoint(args: Vec<String>) -> CxxResult<()> {
     let opt = Opt::from_iter(args.iter());
     match opt {
         Opt::GenerateSyntheticUpgrade(ref opts) => update_os_tree(opts)?,
         Opt::ValidateParseStatus => validate_parse_status()?,
         Opt::Moo => test_moo()?,
+        Opt::ComposeTwice(ref opts) => compose_twice(opts)?,
     };
     Ok(())
 }
@@ -24,6 +24,7 @@ use anyhow::{anyhow, bail, Result};
 use c_utf8::CUtf8Buf;
 use nix::unistd::{Gid, Uid};
 use openat_ext::OpenatDirExt;
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, HashMap, HashSet};
@@ -68,12 +69,132 @@ pub struct Treefile {
     pub(crate) parsed: TreeComposeConfig,
     serialized: CUtf8Buf,
     pub(crate) externals: TreefileExternals,
+    /// Unrecognized keys found while parsing, as (filename, key) pairs.
+    unknown_keys: Vec<(String, String)>,
+    /// The individual files of the include chain.
+    layers: Vec<ProvenanceLayer>,
 }
 
 // We only use this while parsing
 struct ConfigAndExternals {
     config: TreeComposeConfig,
     externals: TreefileExternals,
+    /// Unrecognized keys, as (filename, key) pairs, across the include chain.
+    unknown_keys: Vec<(String, String)>,
+    /// Each file of the include chain, in the order `merge_vec_field()` concatenates them.
+    layers: Vec<ProvenanceLayer>,
+    /// Variables in scope for this file, which are inherited by its includes.
+    variables: VariableMap,
+}
+
+/// A single file of the include chain as parsed, before merging; used
+/// to compute which file each value of the final treefile came from.
+struct ProvenanceLayer {
+    filename: String,
+    /// Position in the depth-first include traversal.  For non-array fields,
+    /// the value from the layer with the lowest rank wins.
+    rank: usize,
+    config: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Map fields which are merged key by key, rather than as a whole.
+static PROVENANCE_MAP_KEYS: &[&str] = &["add-commit-metadata", "variables"];
+
+/// Variables available for `${name}` substitution.
+type VariableMap = collections::HashMap<String, String>;
+
+/// Variables which are always defined from the treefile itself, or at compose
+/// time for `${version}` in `os-release`, and hence can't be set via `variables`.
+static RESERVED_VARIABLES: &[&str] = &["basearch", "releasever", "version"];
+
+/// All the keys accepted at the top level of a treefile, excluding
+/// legacy aliases and `packages-$basearch`.  Used to offer suggestions
+/// for unknown keys.
+pub(crate) static TREEFILE_KEYS: &[&str] = &[
+    "ref",
+    "basearch",
+    "rojig",
+    "repos",
+    "lockfile-repos",
+    "selinux",
+    "gpg-key",
+    "include",
+    "arch-include",
+    "conditional-include",
+    "strict",
+    "variables",
+    "packages",
+    "repo-packages",
+    "bootstrap_packages",
+    "ostree-layers",
+    "ostree-override-layers",
+    "exclude-packages",
+    "container",
+    "recommends",
+    "documentation",
+    "install-langs",
+    "initramfs-args",
+    "cliwrap",
+    "readonly-executables",
+    "boot-location",
+    "tmp-is-dir",
+    "units",
+    "default-target",
+    "systemd",
+    "machineid-compat",
+    "releasever",
+    "automatic-version-prefix",
+    "automatic-version-suffix",
+    "mutate-os-release",
+    "os-release",
+    "etc-group-members",
+    "preserve-passwd",
+    "check-passwd",
+    "check-groups",
+    "ignore-removed-users",
+    "ignore-removed-groups",
+    "sysusers",
+    "id-allocation",
+    "users-backend",
+    "postprocess-script",
+    "postprocess",
+    "postprocess-ops",
+    "add-files",
+    "add-paths",
+    "remove-files",
+    "remove-from-packages",
+    "add-commit-metadata",
+    "packages-remove",
+    "units-remove",
+    "postprocess-remove",
+    "add-files-remove",
+    "rpmdb",
+    "lint",
+    "size-budgets",
+    "hardlink-dedup",
+];
+
+/// JSON has no comments, so by convention keys with this prefix are ignored
+/// even in strict mode.
+const COMMENT_KEY_PREFIX: &str = "comment-";
+
+/// Find the known treefile key closest to `key`, if there is one close enough
+/// to plausibly be what was meant.
+fn suggest_treefile_key(key: &str) -> Option<&'static str> {
+    TREEFILE_KEYS
+        .iter()
+        .map(|&k| (strsim::levenshtein(key, k), k))
+        .filter(|&(distance, k)| distance <= std::cmp::max(2, k.len() / 3))
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, k)| k)
+}
+
+/// Format an unknown key for an error message, with a hint if we have one.
+fn describe_unknown_key(key: &str) -> String {
+    match suggest_treefile_key(key) {
+        Some(suggestion) => format!("{} (did you mean {}?)", key, suggestion),
+        None => key.to_string(),
+    }
 }
 
 /// Parse a YAML treefile definition using base architecture `basearch`.
@@ -108,8 +229,14 @@ fn treefile_parse_stream<R: io::Read>(
     // remove from packages-${arch} keys from the extra keys
     let mut archful_pkgs: Option<Vec<String>> = take_archful_pkgs(basearch, &mut treefile)?;
 
-    if fmt == utils::InputFormat::YAML && !treefile.extra.is_empty() {
-        let keys: Vec<&str> = treefile.extra.keys().map(|k| k.as_str()).collect();
+    // Only JSON has the `comment-` convention, since it has no comments
+    if fmt != utils::InputFormat::JSON && !treefile.extra.is_empty() {
+        let mut keys: Vec<String> = treefile
+            .extra
+            .keys()
+            .map(|k| describe_unknown_key(k))
+            .collect();
+        keys.sort();
         return Err(io::Error::new(
             io::ErrorKind::InvalidInput,
             format!("Unknown fields: {}", keys.join(", ")),
@@ -212,6 +339,7 @@ type IncludeMap = collections::BTreeMap<(u64, u64), String>;
 fn treefile_parse<P: AsRef<Path>>(
     filename: P,
     basearch: Option<&str>,
+    inherited_variables: &VariableMap,
     seen_includes: &mut IncludeMap,
 ) -> Result<ConfigAndExternals> {
     let filename = filename.as_ref();
@@ -227,14 +355,21 @@ fn treefile_parse<P: AsRef<Path>>(
             e.insert(filename.to_str().unwrap().to_string());
         }
     };
+    let rank = seen_includes.len() - 1;
     let mut f = io::BufReader::new(f);
     let fmt = utils::InputFormat::detect_from_filename(filename)?;
-    let tf = treefile_parse_stream(fmt, &mut f, basearch).map_err(|e| {
-        io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("Parsing {}: {}", filename.to_string_lossy(), e.to_string()),
-        )
-    })?;
+    let (tf, variables) = treefile_parse_stream(fmt, &mut f, basearch)
+        .and_then(|mut tf| {
+            let variables = tf.variable_scope(basearch, inherited_variables)?;
+            tf.substitute_user_vars(&variables)?;
+            Ok((tf, variables))
+        })
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Parsing {}: {}", filename.to_string_lossy(), e.to_string()),
+            )
+        })?;
     let postprocess_script = if let Some(ref postprocess) = tf.postprocess_script.as_ref() {
         Some(utils::open_file(filename.with_file_name(postprocess))?)
     } else {
@@ -258,6 +393,21 @@ fn treefile_parse<P: AsRef<Path>>(
         CheckGroups::File(ref f) => load_passwd_file(&parent, f)?,
         _ => None,
     };
+    let mut unknown_keys: Vec<(String, String)> = tf
+        .extra
+        .keys()
+        .filter(|k| !k.starts_with(COMMENT_KEY_PREFIX))
+        .map(|k| (filename.to_string_lossy().to_string(), k.clone()))
+        .collect();
+    unknown_keys.sort();
+    let layer = match serde_json::to_value(&tf)? {
+        serde_json::Value::Object(config) => ProvenanceLayer {
+            filename: filename.to_string_lossy().to_string(),
+            rank,
+            config,
+        },
+        _ => unreachable!("treefile serialized as non-object"),
+    };
 
     Ok(ConfigAndExternals {
         config: tf,
@@ -267,6 +417,9 @@ fn treefile_parse<P: AsRef<Path>>(
             passwd,
             group,
         },
+        unknown_keys,
+        layers: vec![layer],
+        variables,
     })
 }
 
@@ -317,6 +470,27 @@ fn merge_hashset_field<T: Eq + std::hash::Hash>(
     }
 }
 
+/// Merge the `systemd` section field by field: the unit lists are appended,
+/// and the drop-ins are merged per unit, with `dest` winning for a file name.
+fn merge_systemd_field(dest: &mut Option<SystemdConfig>, src: &mut Option<SystemdConfig>) {
+    let mut srcv = match src.take() {
+        Some(srcv) => srcv,
+        None => return,
+    };
+    let destv = dest.get_or_insert_with(Default::default);
+    merge_vec_field(&mut destv.preset_enable, &mut srcv.preset_enable);
+    merge_vec_field(&mut destv.preset_disable, &mut srcv.preset_disable);
+    merge_vec_field(&mut destv.mask, &mut srcv.mask);
+    if let Some(src_dropins) = srcv.dropins.take() {
+        let dest_dropins = destv.dropins.get_or_insert_with(Default::default);
+        for (unit, mut dropins) in src_dropins {
+            let destd = dest_dropins.entry(unit).or_default();
+            dropins.append(destd);
+            *destd = dropins;
+        }
+    }
+}
+
 /// Given two configs, merge them.
 fn treefile_merge(dest: &mut TreeComposeConfig, src: &mut TreeComposeConfig) {
     macro_rules! merge_basics {
@@ -347,6 +521,7 @@ fn treefile_merge(dest: &mut TreeComposeConfig, src: &mut TreeComposeConfig) {
         selinux,
         gpg_key,
         include,
+        strict,
         container,
         recommends,
         cliwrap,
@@ -364,10 +539,17 @@ fn treefile_merge(dest: &mut TreeComposeConfig, src: &mut TreeComposeConfig) {
         preserve_passwd,
         check_passwd,
         check_groups,
-        postprocess_script
+        sysusers,
+        id_allocation,
+        users_backend,
+        postprocess_script,
+        lint,
+        size_budgets,
+        hardlink_dedup
     );
     merge_hashsets!(ignore_removed_groups, ignore_removed_users);
-    merge_maps!(add_commit_metadata);
+    merge_maps!(add_commit_metadata, variables, os_release);
+    merge_systemd_field(&mut dest.systemd, &mut src.systemd);
     merge_vecs!(
         repos,
         lockfile_repos,
@@ -381,7 +563,9 @@ fn treefile_merge(dest: &mut TreeComposeConfig, src: &mut TreeComposeConfig) {
         units,
         etc_group_members,
         postprocess,
+        postprocess_ops,
         add_files,
+        add_paths,
         remove_files,
         remove_from_packages,
         repo_packages
@@ -412,11 +596,12 @@ fn treefile_merge_externals(dest: &mut TreefileExternals, src: &mut TreefileExte
 fn treefile_parse_recurse<P: AsRef<Path>>(
     filename: P,
     basearch: Option<&str>,
+    variables: &VariableMap,
     depth: u32,
     seen_includes: &mut IncludeMap,
 ) -> Result<ConfigAndExternals> {
     let filename = filename.as_ref();
-    let mut parsed = treefile_parse(filename, basearch, seen_includes)?;
+    let mut parsed = treefile_parse(filename, basearch, variables, seen_includes)?;
     let include = parsed
         .config
         .include
@@ -436,6 +621,31 @@ fn treefile_parse_recurse<P: AsRef<Path>>(
             }
         }
     }
+    if let Some(conditional_includes) = parsed.config.conditional_include.take() {
+        for conditional in conditional_includes {
+            let conditions = match conditional.condition {
+                Include::Single(v) => vec![v],
+                Include::Multiple(v) => v,
+            };
+            let mut matches = true;
+            for expr in conditions.iter() {
+                let r = eval_include_condition(expr, &parsed.variables).map_err(|e| {
+                    anyhow!(
+                        "Parsing {}: conditional-include: {}",
+                        filename.to_string_lossy(),
+                        e
+                    )
+                })?;
+                matches = matches && r;
+            }
+            if matches {
+                match conditional.include {
+                    Include::Single(v) => includes.push(v),
+                    Include::Multiple(v) => includes.extend(v),
+                }
+            }
+        }
+    }
     for include_path in includes.iter() {
         if depth == INCLUDE_MAXDEPTH {
             return Err(io::Error::new(
@@ -445,15 +655,285 @@ fn treefile_parse_recurse<P: AsRef<Path>>(
             .into());
         }
         let parent = utils::parent_dir(filename).unwrap();
+        let mut include_path = include_path.clone();
+        substitute_var_refs(&mut include_path, &parsed.variables)?;
         let include_path = parent.join(include_path);
-        let mut included =
-            treefile_parse_recurse(include_path, basearch, depth + 1, seen_includes)?;
+        let mut included = treefile_parse_recurse(
+            include_path,
+            basearch,
+            &parsed.variables,
+            depth + 1,
+            seen_includes,
+        )?;
         treefile_merge(&mut parsed.config, &mut included.config);
         treefile_merge_externals(&mut parsed.externals, &mut included.externals);
+        parsed.unknown_keys.append(&mut included.unknown_keys);
+        // Mirror merge_vec_field(), where included values come first
+        included.layers.append(&mut parsed.layers);
+        parsed.layers = included.layers;
     }
+    parsed.config.apply_removals().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Parsing {}: {}", filename.to_string_lossy(), e.to_string()),
+        )
+    })?;
+    // Drop the files of the add-files entries removed above, so that they are
+    // not part of the inputs anymore.
+    let add_files = &parsed.config.add_files;
+    parsed
+        .externals
+        .add_files
+        .retain(|name, _| add_files.iter().flatten().any(|(src, _)| src == name));
     Ok(parsed)
 }
 
+/// Remove all the entries of `values` whose key is in `remove`; it is an
+/// error if one of those isn't present.
+fn remove_vec_entries<T, K: PartialEq + std::fmt::Display>(
+    field: &str,
+    values: &mut Option<Vec<T>>,
+    remove: Option<Vec<K>>,
+    key: fn(&T) -> &K,
+) -> Result<()> {
+    for r in remove.into_iter().flatten() {
+        match values.as_mut() {
+            Some(values) if values.iter().any(|v| key(v) == &r) => {
+                values.retain(|v| key(v) != &r);
+            }
+            _ => bail!("{}: {} is not present", field, r),
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate a `conditional-include` expression.  The supported forms are
+/// `NAME`, `!NAME` (where the variable must be `true` or `false`),
+/// `NAME == VALUE` and `NAME != VALUE`.
+fn eval_include_condition(expr: &str, vars: &VariableMap) -> Result<bool> {
+    let lookup = |name: &str| -> Result<&str> {
+        let name = name.trim();
+        vars.get(name)
+            .map(|v| v.as_str())
+            .ok_or_else(|| anyhow!("Undefined variable {} in expression {:?}", name, expr))
+    };
+    let unquote = |v: &str| -> String {
+        let v = v.trim();
+        let quoted = v.len() >= 2
+            && ((v.starts_with('"') && v.ends_with('"'))
+                || (v.starts_with('\'') && v.ends_with('\'')));
+        if quoted {
+            v[1..v.len() - 1].to_string()
+        } else {
+            v.to_string()
+        }
+    };
+    if let Some((name, value)) = split_once(expr, "!=") {
+        return Ok(lookup(name)? != unquote(value));
+    }
+    if let Some((name, value)) = split_once(expr, "==") {
+        return Ok(lookup(name)? == unquote(value));
+    }
+    let expr_trimmed = expr.trim();
+    let (negate, name) = match expr_trimmed.strip_prefix('!') {
+        Some(name) => (true, name),
+        None => (false, expr_trimmed),
+    };
+    if name.trim().is_empty() {
+        bail!("Invalid expression {:?}", expr);
+    }
+    let value = match lookup(name)? {
+        "true" => true,
+        "false" => false,
+        v => bail!(
+            "Variable {} is not a boolean (value: {:?}) in expression {:?}",
+            name.trim(),
+            v,
+            expr
+        ),
+    };
+    Ok(value != negate)
+}
+
+/// Like `str::split_once()`, which requires a newer Rust.
+fn split_once<'a>(s: &'a str, delim: &str) -> Option<(&'a str, &'a str)> {
+    s.find(delim).map(|i| (&s[..i], &s[i + delim.len()..]))
+}
+
+fn annotated_value(value: &serde_json::Value, source: Option<&str>) -> serde_json::Value {
+    serde_json::json!({ "value": value, "source": source })
+}
+
+/// Returns `true` if `outer` is `inner`, possibly with more array elements or
+/// object members; this is how entries of e.g. `repo-packages` end up being
+/// filtered during processing.
+fn json_contains(outer: &serde_json::Value, inner: &serde_json::Value) -> bool {
+    use serde_json::Value;
+    match (outer, inner) {
+        (Value::Array(outer), Value::Array(inner)) => inner.iter().all(|v| outer.contains(v)),
+        (Value::Object(outer), Value::Object(inner)) => inner.iter().all(|(k, v)| {
+            outer
+                .get(k)
+                .map(|outerv| json_contains(outerv, v))
+                .unwrap_or(false)
+        }),
+        (outer, inner) => outer == inner,
+    }
+}
+
+/// Find the highest priority layer setting `key` (and `subkey` inside it, if provided).
+fn provenance_find_winner<'a>(
+    by_rank: &[&'a ProvenanceLayer],
+    key: &str,
+    subkey: Option<&str>,
+) -> Option<&'a str> {
+    by_rank
+        .iter()
+        .find(|layer| match (layer.config.get(key), subkey) {
+            (Some(v), Some(subkey)) => v.get(subkey).is_some(),
+            (Some(_), None) => true,
+            (None, _) => false,
+        })
+        .map(|layer| layer.filename.as_str())
+}
+
+/// Find the first not yet attributed array element for `key` matching `pred`, in merge order.
+fn provenance_find_element<'a>(
+    layers: &'a [ProvenanceLayer],
+    key: &str,
+    consumed: &mut HashSet<(usize, usize)>,
+    pred: impl Fn(&serde_json::Value) -> bool,
+) -> Option<&'a str> {
+    for (i, layer) in layers.iter().enumerate() {
+        if let Some(serde_json::Value::Array(candidates)) = layer.config.get(key) {
+            for (j, candidate) in candidates.iter().enumerate() {
+                if !consumed.contains(&(i, j)) && pred(candidate) {
+                    consumed.insert((i, j));
+                    return Some(layer.filename.as_str());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Given the final merged treefile, annotate each value with the file it
+/// came from. Array elements are attributed individually, as are the keys
+/// of fields in `PROVENANCE_MAP_KEYS`.
+fn annotate_provenance(
+    config: &serde_json::Map<String, serde_json::Value>,
+    layers: &[ProvenanceLayer],
+) -> serde_json::Value {
+    use serde_json::Value;
+    let mut by_rank: Vec<&ProvenanceLayer> = layers.iter().collect();
+    by_rank.sort_by_key(|layer| layer.rank);
+    let mut ret = serde_json::Map::new();
+    for (key, value) in config.iter() {
+        let annotated = match value {
+            Value::Array(elements) => {
+                let mut consumed = HashSet::new();
+                let elements = elements
+                    .iter()
+                    .map(|e| {
+                        let source =
+                            provenance_find_element(layers, key, &mut consumed, |c| c == e)
+                                .or_else(|| {
+                                    provenance_find_element(layers, key, &mut consumed, |c| {
+                                        json_contains(c, e)
+                                    })
+                                });
+                        annotated_value(e, source)
+                    })
+                    .collect();
+                Value::Array(elements)
+            }
+            Value::Object(map) if PROVENANCE_MAP_KEYS.contains(&key.as_str()) => Value::Object(
+                map.iter()
+                    .map(|(k, v)| {
+                        let source = provenance_find_winner(&by_rank, key, Some(k.as_str()));
+                        (k.clone(), annotated_value(v, source))
+                    })
+                    .collect(),
+            ),
+            v => annotated_value(v, provenance_find_winner(&by_rank, key, None)),
+        };
+        ret.insert(key.clone(), annotated);
+    }
+    Value::Object(ret)
+}
+
+fn validate_variable_name(name: &str) -> Result<()> {
+    if RESERVED_VARIABLES.contains(&name) {
+        bail!("Variable name {} is reserved", name);
+    }
+    Ok(())
+}
+
+/// Deserialize `variables`, accepting booleans and numbers (e.g. `desktop: false`)
+/// as their string form.
+fn deserialize_variables<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<BTreeMap<String, String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    use serde::Deserialize;
+    let vars: Option<BTreeMap<String, serde_json::Value>> = Option::deserialize(deserializer)?;
+    vars.map(|vars| {
+        vars.into_iter()
+            .map(|(name, value)| match value {
+                serde_json::Value::String(s) => Ok((name, s)),
+                serde_json::Value::Bool(_) | serde_json::Value::Number(_) => {
+                    Ok((name, value.to_string()))
+                }
+                _ => Err(D::Error::custom(format!(
+                    "variable {}: expected a string, boolean or number",
+                    name
+                ))),
+            })
+            .collect()
+    })
+    .transpose()
+}
+
+/// Check an `os-release` treefile entry; by this point, references to
+/// treefile variables have been substituted, leaving only `${version}`.
+fn validate_os_release_entry(key: &str, value: &str) -> Result<()> {
+    let valid_key = !key.is_empty()
+        && !key.starts_with(|c: char| c.is_ascii_digit())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+    if !valid_key {
+        bail!("keys may only contain A-Z, 0-9 and _, and must not start with a digit");
+    }
+    if value.contains('\n') {
+        bail!("values must be a single line");
+    }
+    if value.replace("${version}", "").contains("${") {
+        bail!("unknown variable reference in {:?}", value);
+    }
+    Ok(())
+}
+
+/// Replace `${name}` references to `vars` in `s` in place.
+fn substitute_var_refs(s: &mut String, vars: &VariableMap) -> Result<()> {
+    if envsubst::is_templated(&*s) {
+        *s = envsubst::substitute(s.clone(), vars).map_err(|e| anyhow!(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Parse a `NAME=VALUE` variable override, as passed via `compose tree --var`.
+fn parse_variable_override(s: &str) -> Result<(String, String)> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => bail!("Invalid variable override {:?}; expected NAME=VALUE", s),
+    }
+}
+
 // Similar to the importer check but just checks for prefixes since
 // they're files, and also allows /etc since it's before conversion
 fn add_files_path_is_valid(path: &str) -> bool {
@@ -472,23 +952,71 @@ impl Treefile {
     fn new_boxed(
         filename: &Path,
         basearch: Option<&str>,
+        variables: &VariableMap,
         workdir: Option<openat::Dir>,
     ) -> Result<Box<Treefile>> {
+        for name in variables.keys() {
+            validate_variable_name(name)?;
+        }
         let mut seen_includes = collections::BTreeMap::new();
-        let mut parsed = treefile_parse_recurse(filename, basearch, 0, &mut seen_includes)?;
+        let mut parsed =
+            treefile_parse_recurse(filename, basearch, variables, 0, &mut seen_includes)?;
         event!(Level::DEBUG, "parsed successfully");
+        if !variables.is_empty() {
+            // Overrides win over any value from the treefiles
+            let overrides: BTreeMap<String, String> = variables
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            let config = serde_json::json!({ "variables": &overrides });
+            parsed
+                .config
+                .variables
+                .get_or_insert_with(BTreeMap::new)
+                .extend(overrides);
+            if let serde_json::Value::Object(config) = config {
+                parsed.layers.insert(
+                    0,
+                    ProvenanceLayer {
+                        filename: "command line".to_string(),
+                        rank: 0,
+                        config,
+                    },
+                );
+            }
+        }
         parsed.config.handle_repo_packages_overrides();
         parsed.config = parsed.config.substitute_vars()?;
         Treefile::validate_config(&parsed.config)?;
         let dfd = openat::Dir::open(utils::parent_dir(filename).unwrap())?;
         let serialized = Treefile::serialize_json_string(&parsed.config)?;
-        Ok(Box::new(Treefile {
+        let treefile = Box::new(Treefile {
             primary_dfd: dfd,
             parsed: parsed.config,
             _workdir: workdir,
             serialized,
             externals: parsed.externals,
-        }))
+            unknown_keys: parsed.unknown_keys,
+            layers: parsed.layers,
+        });
+        if treefile.parsed.strict.unwrap_or(false) {
+            treefile.validate_strict()?;
+        }
+        Ok(treefile)
+    }
+
+    /// In strict mode, any unknown key anywhere in the include chain is an error.
+    /// Keys starting with `comment-` are still allowed, since that's the
+    /// convention for comments in JSON treefiles.
+    pub(crate) fn validate_strict(&self) -> Result<()> {
+        if self.unknown_keys.is_empty() {
+            return Ok(());
+        }
+        let mut msg = String::from("Unknown treefile keys (strict mode):");
+        for (filename, key) in self.unknown_keys.iter() {
+            msg.push_str(&format!("\n  {}: {}", filename, describe_unknown_key(key)));
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into())
     }
 
     /// Return the raw file descriptor for the workdir
@@ -526,6 +1054,18 @@ impl Treefile {
         self.serialized.to_string()
     }
 
+    /// Like `get_json_string()`, but each value (and each element of
+    /// arrays, and each key of maps like `add-commit-metadata`) is wrapped in an
+    /// object also holding the filename it came from.
+    pub(crate) fn get_json_string_with_provenance(&self) -> Result<String> {
+        let config = match serde_json::to_value(&self.parsed)? {
+            serde_json::Value::Object(config) => config,
+            _ => unreachable!("treefile serialized as non-object"),
+        };
+        let annotated = annotate_provenance(&config, &self.layers);
+        Ok(serde_json::to_string_pretty(&annotated)?)
+    }
+
     pub(crate) fn get_ostree_layers(&self) -> Vec<String> {
         self.parsed.ostree_layers.clone().unwrap_or_default()
     }
@@ -544,6 +1084,10 @@ impl Treefile {
             .collect()
     }
 
+    pub(crate) fn has_size_budgets(&self) -> bool {
+        self.parsed.size_budgets.is_some()
+    }
+
     pub(crate) fn get_packages(&self) -> Vec<String> {
         self.parsed.packages.clone().unwrap_or_default()
     }
@@ -602,6 +1146,10 @@ impl Treefile {
         self.parsed.releasever.as_deref().unwrap_or_default()
     }
 
+    pub(crate) fn get_users_backend(&self) -> UsersBackend {
+        self.parsed.users_backend.unwrap_or(UsersBackend::Altfiles)
+    }
+
     pub(crate) fn get_rpmdb(&self) -> String {
         let s: &str = match self.parsed.rpmdb.as_ref().unwrap_or(&DEFAULT_RPMDB_BACKEND) {
             RpmdbBackend::Bdb => "bdb",
@@ -645,6 +1193,39 @@ impl Treefile {
                 }
             }
         }
+        for p in config.add_paths.iter().flatten() {
+            p.validate()
+                .map_err(|e| anyhow!("Invalid add-paths entry {}: {}", p.path, e))?;
+        }
+        for (i, op) in config.postprocess_ops.iter().flatten().enumerate() {
+            op.validate()
+                .map_err(|e| anyhow!("Invalid postprocess-ops[{}] ({}): {}", i, op, e))?;
+        }
+        if let Some(budgets) = config.size_budgets.as_ref() {
+            budgets
+                .validate()
+                .map_err(|e| anyhow!("Invalid size-budgets: {}", e))?;
+        }
+        if let Some(systemd) = config.systemd.as_ref() {
+            systemd
+                .validate()
+                .map_err(|e| anyhow!("Invalid systemd: {}", e))?;
+        }
+        if let Some(id_allocation) = config.id_allocation.as_ref() {
+            id_allocation
+                .validate()
+                .map_err(|e| anyhow!("Invalid id-allocation: {}", e))?;
+        }
+        if let Some(sysusers) = config.sysusers.as_ref() {
+            for entry in sysusers.entries.iter().flatten() {
+                crate::sysusers::parse_sysusers_line(entry)
+                    .map_err(|e| anyhow!("Invalid sysusers entry {:?}: {}", entry, e))?;
+            }
+        }
+        for (key, value) in config.os_release.iter().flatten() {
+            validate_os_release_entry(key, value)
+                .map_err(|e| anyhow!("Invalid os-release entry {}: {}", key, e))?;
+        }
         if config.repos.is_none() && config.lockfile_repos.is_none() {
             return Err(anyhow!(
                 r#"Treefile has neither "repos" nor "lockfile-repos""#
@@ -717,17 +1298,51 @@ impl Treefile {
         );
 
         for v in it {
-            let rev = repo.resolve_rev(v, false)?.unwrap();
-            let rev = rev.as_str();
-            let (commit, _) = repo.load_commit(rev)?;
-            let content_checksum =
-                ostree::commit_get_content_checksum(&commit).expect("content checksum");
-            let content_checksum = content_checksum.as_str();
+            let content_checksum = layer_content_checksum(repo, v)?;
             hasher.update(content_checksum.as_bytes());
         }
         Ok(hasher.get_string().expect("hash"))
     }
 
+    /// Digests of each input hashed by `get_checksum()`, keyed by a description
+    /// of the input.  These are stored in the commit metadata, so that a change
+    /// of the overall checksum can be explained via `describe_input_digest_changes()`.
+    pub(crate) fn get_input_digests(
+        &self,
+        mut repo: Pin<&mut crate::ffi::OstreeRepo>,
+    ) -> Result<Vec<crate::ffi::StringMapping>> {
+        let repo = &repo.gobj_wrap();
+        let mut digests = self.get_local_input_digests()?;
+        let layers = [
+            ("layer", &self.parsed.ostree_layers),
+            ("override-layer", &self.parsed.ostree_override_layers),
+        ];
+        for (kind, layers) in layers.iter() {
+            for v in layers.iter().flatten() {
+                digests.insert(format!("{}/{}", kind, v), layer_content_checksum(repo, v)?);
+            }
+        }
+        Ok(digests
+            .into_iter()
+            .map(|(k, v)| crate::ffi::StringMapping { k, v })
+            .collect())
+    }
+
+    /// Like `get_input_digests()`, but without the ostree layers.
+    fn get_local_input_digests(&self) -> Result<BTreeMap<String, String>> {
+        let mut digests = BTreeMap::new();
+        if let serde_json::Value::Object(config) = serde_json::to_value(&self.parsed)? {
+            for (k, v) in config {
+                digests.insert(
+                    format!("config/{}", k),
+                    sha256_hex(&serde_json::to_vec(&v)?),
+                );
+            }
+        }
+        self.externals.input_digests(&mut digests)?;
+        Ok(digests)
+    }
+
     /// Perform sanity checks on externally provided input, such
     /// as the executability of `postprocess-script`.
     pub(crate) fn sanitycheck_externals(&self) -> Result<()> {
@@ -769,7 +1384,7 @@ impl RepoPackage {
     }
 }
 
-fn hash_file(hasher: &mut glib::Checksum, mut f: &fs::File) -> Result<()> {
+pub(crate) fn hash_file(hasher: &mut glib::Checksum, mut f: &fs::File) -> Result<()> {
     let mut reader = io::BufReader::with_capacity(128 * 1024, f);
     loop {
         // have to scope fill_buf() so we can consume() below
@@ -787,6 +1402,49 @@ fn hash_file(hasher: &mut glib::Checksum, mut f: &fs::File) -> Result<()> {
     Ok(())
 }
 
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = glib::Checksum::new(glib::ChecksumType::Sha256);
+    hasher.update(data);
+    hasher.get_string().expect("hash")
+}
+
+/// Resolve `rev` to a commit, and return its content checksum.
+fn layer_content_checksum(repo: &ostree::Repo, rev: &str) -> Result<String> {
+    let rev = repo.resolve_rev(rev, false)?.unwrap();
+    let (commit, _) = repo.load_commit(rev.as_str())?;
+    let content_checksum = ostree::commit_get_content_checksum(&commit).expect("content checksum");
+    Ok(content_checksum.to_string())
+}
+
+/// Given the per-input digests of a previous and current compose, as returned by
+/// `Treefile::get_input_digests()` (plus e.g. `rpms`), describe which inputs changed.
+pub(crate) fn describe_input_digest_changes(
+    previous: &Vec<crate::ffi::StringMapping>,
+    current: &Vec<crate::ffi::StringMapping>,
+) -> Vec<String> {
+    let previous: BTreeMap<&str, &str> = previous
+        .iter()
+        .map(|m| (m.k.as_str(), m.v.as_str()))
+        .collect();
+    let current: BTreeMap<&str, &str> = current
+        .iter()
+        .map(|m| (m.k.as_str(), m.v.as_str()))
+        .collect();
+    let mut ret = Vec::new();
+    for (k, v) in current.iter() {
+        match previous.get(k) {
+            Some(prev) if prev == v => {}
+            Some(_) => ret.push(format!("{}: changed", k)),
+            None => ret.push(format!("{}: added", k)),
+        }
+    }
+    for k in previous.keys().filter(|k| !current.contains_key(*k)) {
+        ret.push(format!("{}: removed", k));
+    }
+    ret.sort();
+    ret
+}
+
 impl TreefileExternals {
     pub(crate) fn group_file_mut(&mut self, _sentinel: &CheckFile) -> Result<&mut fs::File> {
         let group_file = self
@@ -806,6 +1464,29 @@ impl TreefileExternals {
         Ok(passwd_file)
     }
 
+    /// Add the digests of each external file to `digests`; see `Treefile::get_input_digests()`.
+    fn input_digests(&self, digests: &mut BTreeMap<String, String>) -> Result<()> {
+        let mut add = |name: String, f: &fs::File| -> Result<()> {
+            let mut hasher = glib::Checksum::new(glib::ChecksumType::Sha256);
+            hash_file(&mut hasher, f)?;
+            digests.insert(name, hasher.get_string().expect("hash"));
+            Ok(())
+        };
+        if let Some(ref f) = self.postprocess_script {
+            add("postprocess-script".to_string(), f)?;
+        }
+        if let Some(ref f) = self.passwd {
+            add("check-passwd".to_string(), f)?;
+        }
+        if let Some(ref f) = self.group {
+            add("check-groups".to_string(), f)?;
+        }
+        for (name, f) in self.add_files.iter() {
+            add(format!("add-files/{}", name), f)?;
+        }
+        Ok(())
+    }
+
     fn hasher_update(&self, hasher: &mut glib::Checksum) -> Result<()> {
         if let Some(ref f) = self.postprocess_script {
             hash_file(hasher, f)?;
@@ -863,7 +1544,7 @@ fn split_whitespace_unless_quoted(element: &str) -> Result<impl Iterator<Item =
     Ok(ret.into_iter())
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Copy, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Copy, Clone)]
 pub(crate) enum BootLocation {
     #[serde(rename = "new")]
     New,
@@ -877,7 +1558,7 @@ impl Default for BootLocation {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
 #[serde(tag = "type")]
 pub(crate) enum CheckGroups {
     #[serde(rename = "none")]
@@ -890,17 +1571,17 @@ pub(crate) enum CheckGroups {
     Data(CheckGroupsData),
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
 pub(crate) struct CheckFile {
     filename: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
 pub(crate) struct CheckGroupsData {
     pub(crate) entries: BTreeMap<String, u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
 #[serde(tag = "type")]
 pub(crate) enum CheckPasswd {
     #[serde(rename = "none")]
@@ -913,12 +1594,12 @@ pub(crate) enum CheckPasswd {
     Data(CheckPasswdData),
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
 pub(crate) struct CheckPasswdData {
     pub(crate) entries: BTreeMap<String, CheckPasswdDataEntries>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq)]
 #[serde(untagged)]
 pub(crate) enum CheckPasswdDataEntries {
     IdValue(u32),
@@ -956,7 +1637,87 @@ impl From<(u32, u32)> for CheckPasswdDataEntries {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The `id-allocation` section: the user and group IDs which the users and
+/// groups created while composing must get.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct IdAllocation {
+    /// Users pinned to a UID (and GID), in the same format as `check-passwd` data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) users: Option<BTreeMap<String, CheckPasswdDataEntries>>,
+    /// Groups pinned to a GID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) groups: Option<BTreeMap<String, u32>>,
+    /// Ranges like `800-899` (or single IDs) for the UIDs of the other users
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) uid_ranges: Option<Vec<String>>,
+    /// Ranges like `800-899` (or single IDs) for the GIDs of the other groups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) gid_ranges: Option<Vec<String>>,
+}
+
+/// Parse an ID range like `800-899`, or a single ID.
+fn parse_id_range(s: &str) -> Result<std::ops::RangeInclusive<u32>> {
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<u32>()
+            .map_err(|_| anyhow!("Invalid ID range {:?}", s))
+    };
+    let mut parts = s.splitn(2, '-');
+    let start = parse(parts.next().unwrap_or_default())?;
+    let end = match parts.next() {
+        Some(end) => parse(end)?,
+        None => start,
+    };
+    if start > end {
+        bail!(
+            "Invalid ID range {:?}: {} is greater than {}",
+            s,
+            start,
+            end
+        );
+    }
+    Ok(start..=end)
+}
+
+impl IdAllocation {
+    pub(crate) fn uid_ranges(&self) -> Result<Vec<std::ops::RangeInclusive<u32>>> {
+        self.uid_ranges
+            .iter()
+            .flatten()
+            .map(|r| parse_id_range(r))
+            .collect()
+    }
+
+    pub(crate) fn gid_ranges(&self) -> Result<Vec<std::ops::RangeInclusive<u32>>> {
+        self.gid_ranges
+            .iter()
+            .flatten()
+            .map(|r| parse_id_range(r))
+            .collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.uid_ranges()?;
+        self.gid_ranges()?;
+        let mut uids = HashMap::new();
+        for (name, ids) in self.users.iter().flatten() {
+            let uid = ids.ids().0;
+            if let Some(other) = uids.insert(uid, name) {
+                bail!("UID {} is pinned for both {} and {}", uid, other, name);
+            }
+        }
+        let mut gids = HashMap::new();
+        for (name, gid) in self.groups.iter().flatten() {
+            if let Some(other) = gids.insert(gid, name) {
+                bail!("GID {} is pinned for both {} and {}", gid, other, name);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub(crate) struct Rojig {
     pub(crate) name: String,
     pub(crate) summary: String,
@@ -964,14 +1725,22 @@ pub(crate) struct Rojig {
     pub(crate) description: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 #[serde(untagged)]
 pub(crate) enum Include {
     Single(String),
     Multiple(Vec<String>),
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub(crate) struct ConditionalInclude {
+    /// One or more expressions, which must all be true
+    #[serde(rename = "if")]
+    pub(crate) condition: Include,
+    pub(crate) include: Include,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 /// The database backend; see https://github.com/coreos/fedora-coreos-tracker/issues/609
 /// and https://fedoraproject.org/wiki/Changes/Sqlite_Rpmdb
@@ -981,10 +1750,20 @@ pub(crate) enum RpmdbBackend {
     Ndb,
 }
 
+/// How the users and groups in /usr/lib are made available through NSS.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum UsersBackend {
+    /// nss-altfiles, reading /usr/lib/passwd and /usr/lib/group
+    Altfiles,
+    /// systemd userdb records in /usr/lib/userdb, read by nss-systemd
+    Userdb,
+}
+
 // Because of how we handle includes, *everything* here has to be
 // Option<T>.  The defaults live in the code (e.g. machineid-compat defaults
 // to `true`).
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
 pub(crate) struct TreeComposeConfig {
     // Compose controls
     #[serde(rename = "ref")]
@@ -1010,6 +1789,17 @@ pub(crate) struct TreeComposeConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "arch-include")]
     pub(crate) arch_include: Option<BTreeMap<String, Include>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "conditional-include")]
+    pub(crate) conditional_include: Option<Vec<ConditionalInclude>>,
+    // Reject unknown keys across the include chain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) strict: Option<bool>,
+    // User-defined variables for `${name}` substitution; a BTreeMap for
+    // deterministic serialization, like add-commit-metadata below.
+    #[serde(default, deserialize_with = "deserialize_variables")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) variables: Option<BTreeMap<String, String>>,
 
     // Core content
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -1064,6 +1854,8 @@ pub(crate) struct TreeComposeConfig {
     #[serde(rename = "default-target")]
     pub(crate) default_target: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) systemd: Option<SystemdConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "machineid-compat")]
     // Defaults to `true`
     pub(crate) machineid_compat: Option<bool>,
@@ -1080,6 +1872,11 @@ pub(crate) struct TreeComposeConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "mutate-os-release")]
     pub(crate) mutate_os_release: Option<String>,
+    // Keys to set in os-release; `${version}` in values is replaced by the
+    // commit version.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "os-release")]
+    pub(crate) os_release: Option<BTreeMap<String, String>>,
 
     // passwd-related bits
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -1100,6 +1897,14 @@ pub(crate) struct TreeComposeConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "ignore-removed-groups")]
     pub(crate) ignore_removed_groups: Option<HashSet<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sysusers: Option<SysusersConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "id-allocation")]
+    pub(crate) id_allocation: Option<IdAllocation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "users-backend")]
+    pub(crate) users_backend: Option<UsersBackend>,
 
     // Content manipulation
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -1110,9 +1915,16 @@ pub(crate) struct TreeComposeConfig {
     // This one is inline, and supports multiple (hence is useful for inheritance)
     pub(crate) postprocess: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "postprocess-ops")]
+    // Builtin operations, which don't need a container or shell
+    pub(crate) postprocess_ops: Option<Vec<PostprocessOp>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "add-files")]
     pub(crate) add_files: Option<Vec<(String, String)>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "add-paths")]
+    pub(crate) add_paths: Option<Vec<AddPath>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "remove-files")]
     pub(crate) remove_files: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -1124,9 +1936,34 @@ pub(crate) struct TreeComposeConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "add-commit-metadata")]
     pub(crate) add_commit_metadata: Option<BTreeMap<String, serde_json::Value>>,
+
+    // Subtractive merging; these are applied once the includes of a
+    // treefile are merged, and then consumed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "packages-remove")]
+    pub(crate) packages_remove: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "units-remove")]
+    pub(crate) units_remove: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "postprocess-remove")]
+    pub(crate) postprocess_remove: Option<Vec<String>>,
+    // By destination path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "add-files-remove")]
+    pub(crate) add_files_remove: Option<Vec<String>>,
     // The database backend
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) rpmdb: Option<RpmdbBackend>,
+    // Checks of the final rootfs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) lint: Option<LintConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "size-budgets")]
+    pub(crate) size_budgets: Option<SizeBudgets>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "hardlink-dedup")]
+    pub(crate) hardlink_dedup: Option<bool>,
 
     #[serde(flatten)]
     pub(crate) legacy_fields: LegacyTreeComposeConfigFields,
@@ -1135,57 +1972,504 @@ pub(crate) struct TreeComposeConfig {
     pub(crate) extra: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq)]
 pub(crate) struct RepoPackage {
     pub(crate) repo: String,
     pub(crate) packages: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-pub(crate) struct LegacyTreeComposeConfigFields {
-    #[serde(skip_serializing)]
-    pub(crate) gpg_key: Option<String>,
-    #[serde(skip_serializing)]
-    pub(crate) boot_location: Option<BootLocation>,
-    #[serde(skip_serializing)]
-    pub(crate) default_target: Option<String>,
-    #[serde(skip_serializing)]
-    pub(crate) automatic_version_prefix: Option<String>,
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AddPathType {
+    Directory,
+    File,
+    Symlink,
 }
 
-impl TreeComposeConfig {
-    /// Look for use of legacy/renamed fields and migrate them to the new field.
-    fn migrate_legacy_fields(mut self) -> Result<Self> {
-        macro_rules! migrate_field {
-            ( $field:ident ) => {{
-                if self.legacy_fields.$field.is_some() && self.$field.is_some() {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        format!("Cannot use new and legacy forms of {}", stringify!($field)),
-                    )
-                    .into());
-                }
-                self.$field = self.$field.or(self.legacy_fields.$field.take());
-            }};
-        }
+/// An entry in `add-paths`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub(crate) struct AddPath {
+    #[serde(rename = "type")]
+    pub(crate) path_type: AddPathType,
+    pub(crate) path: String,
+    // Octal, as a string, e.g. "0755"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) group: Option<String>,
+    // Only for files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) content: Option<String>,
+    // Only for symlinks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) target: Option<String>,
+}
 
-        migrate_field!(gpg_key);
-        migrate_field!(boot_location);
-        migrate_field!(default_target);
-        migrate_field!(automatic_version_prefix);
+/// Parse permission bits given in octal, e.g. "0755".
+pub(crate) fn parse_mode(mode: &str) -> Result<u32> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+        .ok_or_else(|| anyhow!("Invalid mode {:?}", mode))
+}
 
-        Ok(self)
+impl AddPath {
+    /// The permission bits to apply, or the default for this type.
+    pub(crate) fn mode(&self) -> Result<u32> {
+        let mode = match (self.mode.as_deref(), self.path_type) {
+            (Some(mode), _) => parse_mode(mode)?,
+            (None, AddPathType::Directory) => 0o755,
+            (None, AddPathType::File) => 0o644,
+            (None, AddPathType::Symlink) => 0o777,
+        };
+        Ok(mode)
     }
 
-    /// Look for use of ${variable} and replace it by its proper value
-    fn substitute_vars(mut self) -> Result<Self> {
-        let mut substvars: collections::HashMap<String, String> = collections::HashMap::new();
-        // Substitute ${basearch} and ${releasever}
-        if let Some(arch) = &self.basearch {
-            substvars.insert("basearch".to_string(), arch.clone());
-        }
-        if let Some(releasever) = &self.releasever {
-            substvars.insert("releasever".to_string(), releasever.clone());
+    fn validate(&self) -> Result<()> {
+        if !add_files_path_is_valid(&self.path) || self.path.contains("..") {
+            bail!("Unsupported path");
+        }
+        self.mode()?;
+        match self.path_type {
+            AddPathType::Directory if self.content.is_some() || self.target.is_some() => {
+                bail!("Directories cannot have content or target")
+            }
+            AddPathType::File if self.content.is_none() => bail!("Missing content"),
+            AddPathType::File if self.target.is_some() => bail!("Files cannot have a target"),
+            AddPathType::Symlink if self.target.is_none() => bail!("Missing target"),
+            AddPathType::Symlink if self.content.is_some() || self.mode.is_some() => {
+                bail!("Symlinks cannot have content or mode")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An entry in `postprocess-ops`.  Paths are relative to the rootfs, and
+/// symlinks in the middle of a glob are not followed.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub(crate) enum PostprocessOp {
+    /// Recursively delete everything matching a shell-style glob
+    Delete { glob: String },
+    /// Change the permission bits (in octal) of a path
+    Chmod { path: String, mode: String },
+    /// Write a file, replacing any existing one
+    Write {
+        path: String,
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mode: Option<String>,
+    },
+    /// Create a symlink, replacing any existing one
+    Symlink { path: String, target: String },
+}
+
+impl PostprocessOp {
+    /// The path or glob this operation acts on.
+    pub(crate) fn path(&self) -> &str {
+        match self {
+            PostprocessOp::Delete { glob } => glob,
+            PostprocessOp::Chmod { path, .. }
+            | PostprocessOp::Write { path, .. }
+            | PostprocessOp::Symlink { path, .. } => path,
+        }
+    }
+
+    fn path_mut(&mut self) -> &mut String {
+        match self {
+            PostprocessOp::Delete { glob } => glob,
+            PostprocessOp::Chmod { path, .. }
+            | PostprocessOp::Write { path, .. }
+            | PostprocessOp::Symlink { path, .. } => path,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let path = self.path().trim_start_matches('/');
+        if path.is_empty() || path.split('/').any(|c| c == "..") {
+            bail!("Unsupported path");
+        }
+        if let PostprocessOp::Delete { .. } = self {
+            // Don't allow e.g. `/*` to wipe all of the toplevel directories
+            let first = path.split('/').next().unwrap_or_default();
+            if first.contains(|c| c == '*' || c == '?' || c == '[') {
+                bail!("Globs are not supported in the first path component");
+            }
+        }
+        match self {
+            PostprocessOp::Chmod { mode, .. }
+            | PostprocessOp::Write {
+                mode: Some(mode), ..
+            } => parse_mode(mode).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for PostprocessOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            PostprocessOp::Delete { .. } => "delete",
+            PostprocessOp::Chmod { .. } => "chmod",
+            PostprocessOp::Write { .. } => "write",
+            PostprocessOp::Symlink { .. } => "symlink",
+        };
+        write!(f, "{} {}", name, self.path())
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LintPolicy {
+    Off,
+    Warn,
+    Fail,
+}
+
+/// The `lint` section; each rule defaults to `warn`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct LintConfig {
+    /// setuid/setgid files not in `setuid-allowlist`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) setuid: Option<LintPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) setuid_allowlist: Option<Vec<String>>,
+    /// World-writable files, and directories without the sticky bit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) world_writable: Option<LintPolicy>,
+    /// Symlinks whose target doesn't exist in the rootfs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) dangling_symlinks: Option<LintPolicy>,
+    /// Content in /var without a tmpfiles.d entry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) var_without_tmpfiles: Option<LintPolicy>,
+    /// Files owned by a UID or GID not in the passwd/group files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) unknown_owner: Option<LintPolicy>,
+    /// Files whose UID or GID maps to another name than in the previous commit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) changed_owner: Option<LintPolicy>,
+}
+
+/// A size in bytes, either as a number or as a string with a binary unit
+/// suffix like `500M`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub(crate) enum SizeBudget {
+    Bytes(u64),
+    Human(String),
+}
+
+impl SizeBudget {
+    pub(crate) fn bytes(&self) -> Result<u64> {
+        match self {
+            SizeBudget::Bytes(n) => Ok(*n),
+            SizeBudget::Human(s) => parse_size(s),
+        }
+    }
+}
+
+impl std::fmt::Display for SizeBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SizeBudget::Bytes(n) => write!(f, "{}", n),
+            SizeBudget::Human(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Parse a size like `1024`, `500M` or `2G`; units are powers of 1024.
+pub(crate) fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last() {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1u64 << shift))
+        .ok_or_else(|| anyhow!("Invalid size {:?}", s))
+}
+
+/// The `size-budgets` section, checked after postprocessing.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct SizeBudgets {
+    /// The whole tree
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) total: Option<SizeBudget>,
+    /// Everything under each of these absolute paths
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) paths: Option<BTreeMap<String, SizeBudget>>,
+    /// The files owned by each of these packages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) packages: Option<BTreeMap<String, SizeBudget>>,
+}
+
+impl SizeBudgets {
+    fn validate(&self) -> Result<()> {
+        if let Some(total) = self.total.as_ref() {
+            total.bytes()?;
+        }
+        for (path, budget) in self.paths.iter().flatten() {
+            if !path.starts_with('/') || path.trim_matches('/').is_empty() {
+                bail!("Path {:?} is not absolute, or is the root", path);
+            }
+            budget.bytes().map_err(|e| anyhow!("{}: {}", path, e))?;
+        }
+        for (name, budget) in self.packages.iter().flatten() {
+            budget.bytes().map_err(|e| anyhow!("{}: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// The `sysusers` section; if present, users and groups are allocated from
+/// sysusers.d fragments instead of being created by `useradd` in scriptlets.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct SysusersConfig {
+    /// Additional sysusers.d lines, taking precedence over the fragments in the tree
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) entries: Option<Vec<String>>,
+}
+
+/// The `systemd` section, for unit configuration beyond `units`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct SystemdConfig {
+    /// Units to enable via a systemd preset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) preset_enable: Option<Vec<String>>,
+    /// Units to disable via a systemd preset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) preset_disable: Option<Vec<String>>,
+    /// Units to mask
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) mask: Option<Vec<String>>,
+    /// Drop-in snippets, keyed by unit and then by file name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) dropins: Option<BTreeMap<String, BTreeMap<String, String>>>,
+}
+
+impl SystemdConfig {
+    /// All the units referenced by this section.
+    pub(crate) fn units(&self) -> impl Iterator<Item = &str> {
+        self.preset_enable
+            .iter()
+            .chain(self.preset_disable.iter())
+            .chain(self.mask.iter())
+            .flatten()
+            .chain(self.dropins.iter().flat_map(|d| d.keys()))
+            .map(|s| s.as_str())
+    }
+
+    fn validate(&self) -> Result<()> {
+        for unit in self.units() {
+            if unit.contains('/') || !unit.contains('.') {
+                bail!("Invalid unit name {:?}", unit);
+            }
+        }
+        for (unit, dropins) in self.dropins.iter().flatten() {
+            for name in dropins.keys() {
+                if name.contains('/') || !name.ends_with(".conf") {
+                    bail!(
+                        "Invalid drop-in name {:?} for {}; must end in .conf",
+                        name,
+                        unit
+                    );
+                }
+            }
+        }
+        let enable = self.preset_enable.as_deref().unwrap_or_default();
+        for unit in self.preset_disable.iter().flatten() {
+            if enable.contains(unit) {
+                bail!("{} is in both preset-enable and preset-disable", unit);
+            }
+        }
+        for unit in self.mask.iter().flatten() {
+            if enable.contains(unit) {
+                bail!("{} is in both preset-enable and mask", unit);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default)]
+pub(crate) struct LegacyTreeComposeConfigFields {
+    #[serde(skip_serializing)]
+    pub(crate) gpg_key: Option<String>,
+    #[serde(skip_serializing)]
+    pub(crate) boot_location: Option<BootLocation>,
+    #[serde(skip_serializing)]
+    pub(crate) default_target: Option<String>,
+    #[serde(skip_serializing)]
+    pub(crate) automatic_version_prefix: Option<String>,
+}
+
+impl TreeComposeConfig {
+    /// Look for use of legacy/renamed fields and migrate them to the new field.
+    fn migrate_legacy_fields(mut self) -> Result<Self> {
+        macro_rules! migrate_field {
+            ( $field:ident ) => {{
+                if self.legacy_fields.$field.is_some() && self.$field.is_some() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Cannot use new and legacy forms of {}", stringify!($field)),
+                    )
+                    .into());
+                }
+                self.$field = self.$field.or(self.legacy_fields.$field.take());
+            }};
+        }
+
+        migrate_field!(gpg_key);
+        migrate_field!(boot_location);
+        migrate_field!(default_target);
+        migrate_field!(automatic_version_prefix);
+
+        Ok(self)
+    }
+
+    /// Compute the variables in scope for this file: its own `variables`,
+    /// overridden by `inherited` (from including files and the command line),
+    /// along with `basearch` and `releasever`.
+    fn variable_scope(
+        &self,
+        basearch: Option<&str>,
+        inherited: &VariableMap,
+    ) -> Result<VariableMap> {
+        let mut vars = VariableMap::new();
+        for (name, value) in self.variables.iter().flatten() {
+            validate_variable_name(name)?;
+            vars.insert(name.clone(), value.clone());
+        }
+        vars.extend(inherited.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if let Some(arch) = basearch {
+            vars.insert("basearch".to_string(), arch.to_string());
+        }
+        if let Some(releasever) = &self.releasever {
+            vars.entry("releasever".to_string())
+                .or_insert_with(|| releasever.clone());
+        }
+        envsubst::validate_vars(&vars)?;
+        Ok(vars)
+    }
+
+    /// Substitute `${name}` references to `vars` in the fields supporting
+    /// user-defined variables.  As in `substitute_vars()`, references to
+    /// unknown variables are left as is.
+    fn substitute_user_vars(&mut self, vars: &VariableMap) -> Result<()> {
+        for field in vec![
+            &mut self.repos,
+            &mut self.lockfile_repos,
+            &mut self.packages,
+            &mut self.exclude_packages,
+            &mut self.postprocess,
+            &mut self.remove_files,
+            &mut self.packages_remove,
+            &mut self.units_remove,
+            &mut self.postprocess_remove,
+            &mut self.add_files_remove,
+        ] {
+            for s in field.iter_mut().flatten() {
+                substitute_var_refs(s, vars)?;
+            }
+        }
+        for rp in self.repo_packages.iter_mut().flatten() {
+            substitute_var_refs(&mut rp.repo, vars)?;
+            for s in rp.packages.iter_mut() {
+                substitute_var_refs(s, vars)?;
+            }
+        }
+        if let Some(s) = self.postprocess_script.as_mut() {
+            substitute_var_refs(s, vars)?;
+        }
+        for (src, dest) in self.add_files.iter_mut().flatten() {
+            substitute_var_refs(src, vars)?;
+            substitute_var_refs(dest, vars)?;
+        }
+        for p in self.add_paths.iter_mut().flatten() {
+            substitute_var_refs(&mut p.path, vars)?;
+            if let Some(target) = p.target.as_mut() {
+                substitute_var_refs(target, vars)?;
+            }
+        }
+        for op in self.postprocess_ops.iter_mut().flatten() {
+            substitute_var_refs(op.path_mut(), vars)?;
+            if let PostprocessOp::Symlink { target, .. } = op {
+                substitute_var_refs(target, vars)?;
+            }
+        }
+        for v in self.os_release.iter_mut().flat_map(|m| m.values_mut()) {
+            substitute_var_refs(v, vars)?;
+        }
+        for v in self
+            .add_commit_metadata
+            .iter_mut()
+            .flat_map(|m| m.values_mut())
+        {
+            if let serde_json::Value::String(s) = v {
+                substitute_var_refs(s, vars)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the `*-remove` fields to the values merged so far, consuming them.
+    fn apply_removals(&mut self) -> Result<()> {
+        let packages_remove = match self.packages_remove.take() {
+            Some(pkgs) => Some(whitespace_split_packages(&pkgs)?),
+            None => None,
+        };
+        remove_vec_entries(
+            "packages-remove",
+            &mut self.packages,
+            packages_remove,
+            |p| p,
+        )?;
+        remove_vec_entries(
+            "units-remove",
+            &mut self.units,
+            self.units_remove.take(),
+            |u| u,
+        )?;
+        remove_vec_entries(
+            "postprocess-remove",
+            &mut self.postprocess,
+            self.postprocess_remove.take(),
+            |s| s,
+        )?;
+        remove_vec_entries(
+            "add-files-remove",
+            &mut self.add_files,
+            self.add_files_remove.take(),
+            |(_, dest)| dest,
+        )?;
+        Ok(())
+    }
+
+    /// Look for use of ${variable} and replace it by its proper value
+    fn substitute_vars(mut self) -> Result<Self> {
+        let mut substvars: collections::HashMap<String, String> = collections::HashMap::new();
+        if let Some(vars) = &self.variables {
+            substvars.extend(vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        // Substitute ${basearch} and ${releasever}
+        if let Some(arch) = &self.basearch {
+            substvars.insert("basearch".to_string(), arch.clone());
+        }
+        if let Some(releasever) = &self.releasever {
+            substvars.insert("releasever".to_string(), releasever.clone());
         }
         envsubst::validate_vars(&substvars)?;
 
@@ -1472,6 +2756,111 @@ pub(crate) mod tests {
         "});
     }
 
+    #[test]
+    fn test_invalid_unknown_key_suggestion() {
+        let buf = VALID_PRELUDE.to_string() + "exclude-package:\n  - foo\n";
+        let mut input = io::BufReader::new(buf.as_bytes());
+        let e = treefile_parse_stream(utils::InputFormat::YAML, &mut input, Some(ARCH_X86_64))
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Unknown fields: exclude-package (did you mean exclude-packages?)"
+        );
+    }
+
+    #[test]
+    fn test_suggest_treefile_key() {
+        assert_eq!(suggest_treefile_key("recommend"), Some("recommends"));
+        assert_eq!(suggest_treefile_key("add_files"), Some("add-files"));
+        assert_eq!(suggest_treefile_key("frobnicate"), None);
+    }
+
+    #[test]
+    fn test_treefile_keys_known() {
+        // Every key we suggest must be one serde actually recognizes.
+        for key in TREEFILE_KEYS {
+            let buf = format!(r#"{{ "{}": null }}"#, key);
+            let mut input = io::BufReader::new(buf.as_bytes());
+            let tf = treefile_parse_stream(utils::InputFormat::JSON, &mut input, None).unwrap();
+            assert!(tf.extra.is_empty(), "Unknown key {}", key);
+        }
+    }
+
+    #[test]
+    fn test_treefile_strict() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let workdir_d = openat::Dir::open(workdir.path())?;
+        workdir_d.write_file_contents(
+            "base.json",
+            0o644,
+            indoc! {r#"
+                {
+                  "comment-repos": "Comments are still allowed",
+                  "repos": ["baserepo"],
+                  "recommend": false
+                }
+            "#},
+        )?;
+        let tf_path = workdir.path().join("treefile.json");
+        std::fs::write(
+            &tf_path,
+            indoc! {r#"
+                {
+                  "ref": "exampleos/x86_64/blah",
+                  "include": "base.json",
+                  "frobnicate": true
+                }
+            "#},
+        )?;
+        // Without strict mode, unknown keys in JSON are ignored
+        let tf = Treefile::new_boxed(tf_path.as_path(), None, &VariableMap::new(), None)?;
+        let e = tf.validate_strict().err().unwrap();
+        let base_path = workdir.path().join("base.json");
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "Unknown treefile keys (strict mode):\n  {}: frobnicate\n  {}: recommend (did you mean recommends?)",
+                tf_path.to_str().unwrap(),
+                base_path.to_str().unwrap()
+            )
+        );
+
+        // Enabling it in the treefile itself makes parsing fail
+        std::fs::write(
+            &tf_path,
+            indoc! {r#"
+                {
+                  "ref": "exampleos/x86_64/blah",
+                  "include": "base.json",
+                  "strict": true
+                }
+            "#},
+        )?;
+        let e = Treefile::new_boxed(tf_path.as_path(), None, &VariableMap::new(), None)
+            .err()
+            .unwrap();
+        assert!(e
+            .to_string()
+            .contains("recommend (did you mean recommends?)"));
+
+        // And fixing the typo makes it pass
+        workdir_d.write_file_contents(
+            "base.json",
+            0o644,
+            indoc! {r#"
+                {
+                  "comment-repos": "Comments are still allowed",
+                  "repos": ["baserepo"],
+                  "recommends": false
+                }
+            "#},
+        )?;
+        let tf = Treefile::new_boxed(tf_path.as_path(), None, &VariableMap::new(), None)?;
+        assert_eq!(tf.parsed.recommends, Some(false));
+        Ok(())
+    }
+
     pub(crate) fn new_test_treefile<'a, 'b>(
         workdir: &std::path::Path,
         contents: &'a str,
@@ -1482,6 +2871,7 @@ pub(crate) mod tests {
         Ok(Treefile::new_boxed(
             tf_path.as_path(),
             basearch,
+            &VariableMap::new(),
             Some(openat::Dir::open(workdir)?),
         )?)
     }
@@ -1594,6 +2984,777 @@ arch-include:
         Ok(())
     }
 
+    #[test]
+    fn test_treefile_variables() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let workdir_d = openat::Dir::open(workdir.path())?;
+        workdir_d.write_file_contents(
+            "base-next.yaml",
+            0o644,
+            indoc! {"
+                variables:
+                    stream: stable
+                    desktop: gnome
+                repos:
+                    - fedora-${stream}
+                packages:
+                    - ${desktop}-shell
+                postprocess:
+                    - echo ${stream} ${unknown}
+            "},
+        )?;
+        workdir_d.write_file_contents(
+            "base-cli.yaml",
+            0o644,
+            "packages:\n  - ${desktop}-${stream}\n",
+        )?;
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {"
+            variables:
+                stream: next
+            include: base-${stream}.yaml
+            add-commit-metadata:
+                stream: ${stream}
+                count: 1
+        "});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None)?;
+        let tf = &tf.parsed;
+        assert_eq!(tf.repos.as_ref().unwrap()[0], "fedora-next");
+        assert_eq!(tf.packages.as_ref().unwrap()[0], "gnome-shell");
+        assert_eq!(tf.postprocess.as_ref().unwrap()[0], "echo next ${unknown}");
+        let metadata = tf.add_commit_metadata.as_ref().unwrap();
+        assert_eq!(metadata["stream"], "next");
+        let variables = tf.variables.as_ref().unwrap();
+        assert_eq!(variables["stream"], "next");
+        assert_eq!(variables["desktop"], "gnome");
+
+        // Overrides win over the treefile, and are inherited by includes
+        let mut overrides = VariableMap::new();
+        overrides.insert("stream".into(), "cli".into());
+        overrides.insert("desktop".into(), "kde".into());
+        let tf_path = workdir.path().join("treefile.yaml");
+        let tf = Treefile::new_boxed(&tf_path, None, &overrides, None)?;
+        assert_eq!(tf.parsed.packages.as_ref().unwrap()[0], "kde-cli");
+        assert_eq!(tf.parsed.variables.as_ref().unwrap()["stream"], "cli");
+        let v: serde_json::Value = serde_json::from_str(&tf.get_json_string_with_provenance()?)?;
+        assert_eq!(v["variables"]["stream"]["source"], "command line");
+
+        overrides.insert("basearch".into(), "x86_64".into());
+        assert!(Treefile::new_boxed(&tf_path, None, &overrides, None).is_err());
+
+        // Booleans and numbers are accepted as their string form
+        let inputs = vec![
+            (
+                utils::InputFormat::JSON,
+                r#"{"variables": {"desktop": false, "release": 34, "ratio": 1.5}}"#,
+            ),
+            (
+                utils::InputFormat::TOML,
+                "[variables]\ndesktop = false\nrelease = 34\nratio = 1.5\n",
+            ),
+            (
+                utils::InputFormat::YAML,
+                "variables:\n  desktop: false\n  release: 34\n  ratio: 1.5\n",
+            ),
+        ];
+        for (fmt, input) in inputs {
+            let mut input = io::BufReader::new(input.as_bytes());
+            let tf = treefile_parse_stream(fmt, &mut input, None)?;
+            let variables = tf.variables.as_ref().unwrap();
+            assert_eq!(variables["desktop"], "false");
+            assert_eq!(variables["release"], "34");
+            assert_eq!(variables["ratio"], "1.5");
+        }
+        let mut input = io::BufReader::new(r#"{"variables": {"foo": [1]}}"#.as_bytes());
+        assert!(treefile_parse_stream(utils::InputFormat::JSON, &mut input, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_include_condition() {
+        let mut vars = VariableMap::new();
+        vars.insert("basearch".into(), "x86_64".into());
+        vars.insert("desktop".into(), "true".into());
+        vars.insert("stream".into(), "next".into());
+        let eval = |expr| eval_include_condition(expr, &vars);
+        assert!(eval("desktop").unwrap());
+        assert!(!eval("!desktop").unwrap());
+        assert!(eval("basearch == x86_64").unwrap());
+        assert!(eval("basearch=='x86_64'").unwrap());
+        assert!(!eval("basearch == aarch64").unwrap());
+        assert!(eval("stream != \"stable\"").unwrap());
+        let e = eval("releasever == 33").unwrap_err().to_string();
+        assert!(e.contains("Undefined variable releasever"), "{}", e);
+        let e = eval("stream").unwrap_err().to_string();
+        assert!(e.contains("not a boolean"), "{}", e);
+        assert!(eval("!").is_err());
+    }
+
+    #[test]
+    fn test_treefile_conditional_include() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let workdir_d = openat::Dir::open(workdir.path())?;
+        for name in &["desktop", "next", "stable", "x86"] {
+            workdir_d.write_file_contents(
+                format!("{}.yaml", name),
+                0o644,
+                format!("packages:\n  - {}-pkg\n", name),
+            )?;
+        }
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {"
+            variables:
+                desktop: false
+                stream: next
+            conditional-include:
+                - if: desktop
+                  include: desktop.yaml
+                - if:
+                    - stream == next
+                    - '!desktop'
+                  include: [next.yaml]
+                - if: stream == stable
+                  include: stable.yaml
+                - if: basearch == x86_64
+                  include: x86.yaml
+        "});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), Some("x86_64"))?;
+        let pkgs = tf.parsed.packages.as_ref().unwrap();
+        assert!(pkgs.contains(&"next-pkg".to_string()));
+        assert!(pkgs.contains(&"x86-pkg".to_string()));
+        assert!(!pkgs.contains(&"desktop-pkg".to_string()));
+        assert!(!pkgs.contains(&"stable-pkg".to_string()));
+        assert!(tf.parsed.conditional_include.is_none());
+
+        // Without a basearch, the last expression references an undefined variable
+        let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.contains("Undefined variable basearch"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn test_treefile_toml() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let workdir_d = openat::Dir::open(workdir.path())?;
+        workdir_d.write_file_contents(
+            "base.toml",
+            0o644,
+            indoc! {r#"
+                repos = ["fedora"]
+                packages = ["kernel systemd"]
+                selinux = false
+                add-files = [["foo", "/usr/share/foo"]]
+
+                [check-passwd]
+                type = "none"
+
+                [add-commit-metadata]
+                version = "1.0"
+            "#},
+        )?;
+        workdir_d.write_file_contents("foo", 0o644, "foo")?;
+        workdir_d.write_file_contents(
+            "mid.json",
+            0o644,
+            r#"{"include": "base.toml", "packages": ["bash"]}"#,
+        )?;
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str("include: mid.json\n");
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None)?;
+        let tf = &tf.parsed;
+        assert_eq!(
+            &tf.packages.as_ref().unwrap()[0..3],
+            &["kernel", "systemd", "bash"]
+        );
+        assert_eq!(tf.repos.as_ref().unwrap(), &vec!["fedora", "baserepo"]);
+        assert_eq!(tf.selinux, Some(false));
+        assert_eq!(tf.get_check_passwd(), &CheckPasswd::None);
+        assert_eq!(tf.add_commit_metadata.as_ref().unwrap()["version"], "1.0");
+
+        // Like YAML, unknown keys are an error
+        let mut input = io::BufReader::new("packagse = [\"foo\"]\n".as_bytes());
+        let e = treefile_parse_stream(utils::InputFormat::TOML, &mut input, None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            e.contains("Unknown fields: packagse (did you mean packages?)"),
+            "{}",
+            e
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_treefile_removals() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let workdir_d = openat::Dir::open(workdir.path())?;
+        workdir_d.write_file_contents("foo", 0o644, "foo")?;
+        workdir_d.write_file_contents(
+            "base.yaml",
+            0o644,
+            indoc! {"
+                packages:
+                    - foo-tools baz
+                units:
+                    - foo.service
+                    - bar.service
+                postprocess:
+                    - echo base
+                add-files:
+                    - [foo, /usr/share/foo]
+                    - [foo, /usr/share/bar]
+            "},
+        )?;
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {"
+            include: base.yaml
+            packages-remove:
+                - foo-tools bar
+            units-remove:
+                - foo.service
+            postprocess-remove:
+                - echo base
+            add-files-remove:
+                - /usr/share/foo
+        "});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None)?;
+        let pkgs = tf.parsed.packages.as_ref().unwrap();
+        assert!(!pkgs.contains(&"foo-tools".to_string()));
+        assert!(!pkgs.contains(&"bar".to_string()));
+        assert!(pkgs.contains(&"baz".to_string()));
+        assert_eq!(tf.parsed.units.as_ref().unwrap(), &vec!["bar.service"]);
+        assert!(tf.parsed.postprocess.as_ref().unwrap().is_empty());
+        assert_eq!(
+            tf.parsed.add_files.as_ref().unwrap(),
+            &vec![("foo".to_string(), "/usr/share/bar".to_string())]
+        );
+        assert!(tf.parsed.packages_remove.is_none());
+        assert!(!tf.get_json_string().contains("-remove"));
+
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {"
+            include: base.yaml
+            units-remove:
+                - baz.service
+        "});
+        let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            e.contains("units-remove: baz.service is not present"),
+            "{}",
+            e
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_treefile_add_paths() {
+        let workdir = tempfile::tempdir().unwrap();
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {r#"
+            variables:
+                name: foo
+            add-paths:
+                - type: directory
+                  path: /usr/lib/${name}
+                  mode: "0750"
+                  user: root
+                  group: wheel
+                - type: file
+                  path: /etc/${name}.conf
+                  content: "bar=baz"
+                - type: symlink
+                  path: /usr/lib/${name}/${name}.conf
+                  target: ../../etc/${name}.conf
+        "#});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None).unwrap();
+        let paths = tf.parsed.add_paths.as_ref().unwrap();
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[0].path, "/usr/lib/foo");
+        assert_eq!(paths[0].mode().unwrap(), 0o750);
+        assert_eq!(paths[1].path_type, AddPathType::File);
+        assert_eq!(paths[1].mode().unwrap(), 0o644);
+        assert_eq!(paths[2].path, "/usr/lib/foo/foo.conf");
+        assert_eq!(paths[2].target.as_deref(), Some("../../etc/foo.conf"));
+
+        for invalid in &[
+            "{type: directory, path: /var/lib/foo}",
+            "{type: directory, path: /usr/lib/foo, mode: \"0999\"}",
+            "{type: file, path: /usr/lib/foo}",
+            "{type: symlink, path: /usr/lib/foo}",
+            "{type: symlink, path: /usr/lib/foo, target: bar, mode: \"0644\"}",
+        ] {
+            let mut buf = VALID_PRELUDE.to_string();
+            buf.push_str(&format!("add-paths:\n  - {}\n", invalid));
+            let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(e.contains("Invalid add-paths entry"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_treefile_postprocess_ops() {
+        let workdir = tempfile::tempdir().unwrap();
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {r#"
+            postprocess-ops:
+                - type: delete
+                  glob: /usr/share/doc/*/${basearch}
+                - type: chmod
+                  path: /usr/bin/foo
+                  mode: "4755"
+        "#});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), Some("x86_64")).unwrap();
+        let ops = tf.parsed.postprocess_ops.as_ref().unwrap();
+        assert_eq!(
+            ops[0],
+            PostprocessOp::Delete {
+                glob: "/usr/share/doc/*/x86_64".into()
+            }
+        );
+        assert_eq!(ops[1].to_string(), "chmod /usr/bin/foo");
+
+        for invalid in &[
+            "{type: delete, glob: /}",
+            "{type: delete, glob: /usr/../etc}",
+            "{type: delete, glob: \"/*\"}",
+            "{type: delete, glob: \"/u*/share/doc\"}",
+            "{type: chmod, path: /usr/bin/foo, mode: \"rwx\"}",
+            "{type: write, path: /usr/lib/foo, content: foo, mode: \"17777\"}",
+        ] {
+            let mut buf = VALID_PRELUDE.to_string();
+            buf.push_str(&format!("postprocess-ops:\n  - {}\n", invalid));
+            let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(e.contains("Invalid postprocess-ops[0]"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1234").unwrap(), 1234);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("500M").unwrap(), 500 * 1024 * 1024);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        for invalid in &["", "M", "1.5G", "-1", "10X", "99999999999T"] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_treefile_size_budgets() {
+        let workdir = tempfile::tempdir().unwrap();
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {r#"
+            size-budgets:
+                total: 2G
+                paths:
+                    /usr/lib/modules: 500M
+                packages:
+                    kernel-core: 104857600
+        "#});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None).unwrap();
+        assert!(tf.has_size_budgets());
+        let budgets = tf.parsed.size_budgets.as_ref().unwrap();
+        assert_eq!(budgets.total.as_ref().unwrap().bytes().unwrap(), 2 << 30);
+        let packages = budgets.packages.as_ref().unwrap();
+        assert_eq!(packages["kernel-core"], SizeBudget::Bytes(104857600));
+
+        for invalid in &[
+            "{total: 2X}",
+            "{paths: {usr/lib/modules: 1G}}",
+            "{packages: {kernel: lots}}",
+        ] {
+            let mut buf = VALID_PRELUDE.to_string();
+            buf.push_str(&format!("size-budgets: {}\n", invalid));
+            let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(e.contains("Invalid size-budgets"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_treefile_users_backend() {
+        let workdir = tempfile::tempdir().unwrap();
+        let tf = new_test_treefile(workdir.path(), VALID_PRELUDE, None).unwrap();
+        assert_eq!(tf.get_users_backend(), UsersBackend::Altfiles);
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str("users-backend: userdb\n");
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None).unwrap();
+        assert_eq!(tf.get_users_backend(), UsersBackend::Userdb);
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str("users-backend: files\n");
+        assert!(new_test_treefile(workdir.path(), buf.as_str(), None).is_err());
+    }
+
+    #[test]
+    fn test_treefile_id_allocation() {
+        let workdir = tempfile::tempdir().unwrap();
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {r#"
+            id-allocation:
+                users:
+                    etcd: 850
+                    zincati: [851, 852]
+                groups:
+                    zincati: 852
+                uid-ranges: ["0-99", "800-899", "65534"]
+        "#});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None).unwrap();
+        let policy = tf.parsed.id_allocation.as_ref().unwrap();
+        assert_eq!(
+            policy.uid_ranges().unwrap(),
+            vec![0..=99, 800..=899, 65534..=65534]
+        );
+        assert!(policy.gid_ranges().unwrap().is_empty());
+        assert_eq!(
+            policy.users.as_ref().unwrap()["zincati"].ids(),
+            (Uid::from_raw(851), Gid::from_raw(852))
+        );
+
+        for invalid in &[
+            "{uid-ranges: [\"899-800\"]}",
+            "{gid-ranges: [\"800-\"]}",
+            "{uid-ranges: [abc]}",
+            "{users: {foo: 850, bar: [850, 1]}}",
+            "{groups: {foo: 850, bar: 850}}",
+        ] {
+            let mut buf = VALID_PRELUDE.to_string();
+            buf.push_str(&format!("id-allocation: {}\n", invalid));
+            let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(e.contains("Invalid id-allocation"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_treefile_sysusers() {
+        let workdir = tempfile::tempdir().unwrap();
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {r#"
+            sysusers:
+                entries:
+                    - "u core - \"CoreOS Admin\" /var/home/core /bin/bash"
+                    - "g docker 975"
+        "#});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None).unwrap();
+        let sysusers = tf.parsed.sysusers.as_ref().unwrap();
+        assert_eq!(sysusers.entries.as_ref().unwrap().len(), 2);
+
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str("sysusers: {}\n");
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None).unwrap();
+        assert!(tf.parsed.sysusers.as_ref().unwrap().entries.is_none());
+
+        for invalid in &["x foo", "u foo abc", "m foo"] {
+            let mut buf = VALID_PRELUDE.to_string();
+            buf.push_str(&format!("sysusers: {{entries: [\"{}\"]}}\n", invalid));
+            let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(e.contains("Invalid sysusers entry"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_treefile_systemd() {
+        let workdir = tempfile::tempdir().unwrap();
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {r#"
+            systemd:
+                preset-enable: [zincati.service]
+                preset-disable: [sshd.socket]
+                mask: [systemd-repart.service]
+                dropins:
+                    sshd.service:
+                        10-restart.conf: |
+                            [Service]
+                            Restart=always
+        "#});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None).unwrap();
+        let systemd = tf.parsed.systemd.as_ref().unwrap();
+        assert_eq!(
+            systemd.units().collect::<Vec<_>>(),
+            &[
+                "zincati.service",
+                "sshd.socket",
+                "systemd-repart.service",
+                "sshd.service"
+            ]
+        );
+        assert_eq!(
+            systemd.dropins.as_ref().unwrap()["sshd.service"]["10-restart.conf"],
+            "[Service]\nRestart=always\n"
+        );
+
+        // Includes are merged field by field
+        std::fs::write(
+            workdir.path().join("base.yaml"),
+            indoc! {r#"
+                systemd:
+                    preset-enable: [chronyd.service]
+                    mask: [dnf-makecache.timer]
+                    dropins:
+                        sshd.service:
+                            10-restart.conf: "base"
+                            20-limits.conf: "base"
+                        chronyd.service:
+                            10-foo.conf: "base"
+            "#},
+        )
+        .unwrap();
+        buf.push_str("include: base.yaml\n");
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None).unwrap();
+        let systemd = tf.parsed.systemd.as_ref().unwrap();
+        assert_eq!(
+            systemd.preset_enable.as_ref().unwrap(),
+            &["chronyd.service", "zincati.service"]
+        );
+        assert_eq!(systemd.preset_disable.as_ref().unwrap(), &["sshd.socket"]);
+        assert_eq!(
+            systemd.mask.as_ref().unwrap(),
+            &["dnf-makecache.timer", "systemd-repart.service"]
+        );
+        let dropins = systemd.dropins.as_ref().unwrap();
+        assert_eq!(
+            dropins["sshd.service"]["10-restart.conf"],
+            "[Service]\nRestart=always\n"
+        );
+        assert_eq!(dropins["sshd.service"]["20-limits.conf"], "base");
+        assert_eq!(dropins["chronyd.service"]["10-foo.conf"], "base");
+
+        for invalid in &[
+            "{preset-enable: [foo]}",
+            "{mask: [../foo.service]}",
+            "{dropins: {foo.service: {override: bar}}}",
+            "{preset-enable: [foo.service], preset-disable: [foo.service]}",
+            "{preset-enable: [foo.service], mask: [foo.service]}",
+        ] {
+            let mut buf = VALID_PRELUDE.to_string();
+            buf.push_str(&format!("systemd: {}\n", invalid));
+            let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(e.contains("Invalid systemd"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_treefile_os_release() {
+        let workdir = tempfile::tempdir().unwrap();
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {r#"
+            releasever: 34
+            variables:
+                stream: next
+            os-release:
+                VARIANT_ID: coreos
+                IMAGE_ID: fedora-coreos-${stream}
+                BUILD_ID: ${releasever}.${version}
+        "#});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None).unwrap();
+        let os_release = tf.parsed.os_release.as_ref().unwrap();
+        assert_eq!(os_release["VARIANT_ID"], "coreos");
+        assert_eq!(os_release["IMAGE_ID"], "fedora-coreos-next");
+        assert_eq!(os_release["BUILD_ID"], "34.${version}");
+
+        for invalid in &[
+            "{variant_id: coreos}",
+            "{1D: foo}",
+            "{IMAGE_ID: \"a\\nb\"}",
+            "{IMAGE_ID: \"${nosuchvar}\"}",
+        ] {
+            let mut buf = VALID_PRELUDE.to_string();
+            buf.push_str(&format!("os-release: {}\n", invalid));
+            let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(e.contains("Invalid os-release entry"), "{}", e);
+        }
+
+        // `${version}` can't be shadowed by a user-defined variable
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str("variables: {version: \"1\"}\nos-release: {BUILD_ID: \"${version}\"}\n");
+        let e = new_test_treefile(workdir.path(), buf.as_str(), None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.contains("Variable name version is reserved"), "{}", e);
+    }
+
+    #[test]
+    fn test_treefile_reserved_variables() {
+        let workdir = tempfile::tempdir().unwrap();
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str("variables: {releasever: \"33\"}\n");
+        assert!(new_test_treefile(workdir.path(), buf.as_str(), None).is_err());
+    }
+
+    #[test]
+    fn test_parse_variable_override() {
+        assert_eq!(
+            parse_variable_override("stream=next").unwrap(),
+            ("stream".to_string(), "next".to_string())
+        );
+        assert_eq!(
+            parse_variable_override("cmd=a=b").unwrap(),
+            ("cmd".to_string(), "a=b".to_string())
+        );
+        assert!(parse_variable_override("stream").is_err());
+        assert!(parse_variable_override("=next").is_err());
+    }
+
+    #[test]
+    fn test_add_files_remove_inputs() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let workdir_d = openat::Dir::open(workdir.path())?;
+        workdir_d.write_file_contents("foo", 0o644, "foo")?;
+        workdir_d.write_file_contents("bar", 0o644, "bar")?;
+        workdir_d.write_file_contents(
+            "base.yaml",
+            0o644,
+            "add-files:\n  - [foo, /usr/share/foo]\n  - [bar, /usr/share/bar]\n",
+        )?;
+        workdir_d.write_file_contents(
+            "base-foo.yaml",
+            0o644,
+            "add-files:\n  - [foo, /usr/share/foo]\n",
+        )?;
+        let checksum = |tf: &Treefile| -> Result<String> {
+            let mut hasher = glib::Checksum::new(glib::ChecksumType::Sha256);
+            tf.parsed.hasher_update(&mut hasher)?;
+            tf.externals.hasher_update(&mut hasher)?;
+            Ok(hasher.get_string().expect("hash"))
+        };
+
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str("include: base.yaml\nadd-files-remove:\n  - /usr/share/bar\n");
+        let removed = new_test_treefile(workdir.path(), buf.as_str(), None)?;
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str("include: base-foo.yaml\n");
+        let never_added = new_test_treefile(workdir.path(), buf.as_str(), None)?;
+
+        let digests = removed.get_local_input_digests()?;
+        assert!(digests.contains_key("add-files/foo"));
+        assert!(!digests.contains_key("add-files/bar"));
+        assert_eq!(digests, never_added.get_local_input_digests()?);
+        assert_eq!(checksum(&removed)?, checksum(&never_added)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_digests() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let workdir_d = openat::Dir::open(workdir.path())?;
+        workdir_d.write_file_contents("foo", 0o644, "foo")?;
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str("add-files:\n  - [foo, /usr/share/foo]\n");
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None)?;
+        let previous = tf.get_local_input_digests()?;
+        assert!(previous.contains_key("config/ref"));
+        assert!(previous.contains_key("config/packages"));
+
+        workdir_d.write_file_contents("foo", 0o644, "bar")?;
+        buf.push_str("selinux: false\n");
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None)?;
+        let mut current = tf.get_local_input_digests()?;
+        current.remove("config/repos");
+        let to_vec = |m: &BTreeMap<String, String>| -> Vec<crate::ffi::StringMapping> {
+            m.iter()
+                .map(|(k, v)| crate::ffi::StringMapping {
+                    k: k.clone(),
+                    v: v.clone(),
+                })
+                .collect()
+        };
+        assert_eq!(
+            describe_input_digest_changes(&to_vec(&previous), &to_vec(&current)),
+            vec![
+                "add-files/foo: changed",
+                "config/repos: removed",
+                "config/selinux: added"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_treefile_provenance() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let workdir_d = openat::Dir::open(workdir.path())?;
+        workdir_d.write_file_contents(
+            "foo.yaml",
+            0o644,
+            indoc! {"
+                repos:
+                    - foo
+                packages:
+                    - fooinclude
+                selinux: false
+                add-commit-metadata:
+                    foo-key: foo
+                    shared-key: from-include
+            "},
+        )?;
+        let mut buf = VALID_PRELUDE.to_string();
+        buf.push_str(indoc! {"
+            include: foo.yaml
+            selinux: true
+            add-commit-metadata:
+                shared-key: from-treefile
+        "});
+        let tf = new_test_treefile(workdir.path(), buf.as_str(), None)?;
+        let top = workdir.path().join("treefile.yaml");
+        let top = top.to_str().unwrap();
+        let foo = workdir.path().join("foo.yaml");
+        let foo = foo.to_str().unwrap();
+        let v: serde_json::Value = serde_json::from_str(&tf.get_json_string_with_provenance()?)?;
+        assert_eq!(
+            v["selinux"],
+            serde_json::json!({ "value": true, "source": top })
+        );
+        assert_eq!(v["ref"]["source"], top);
+        let repos = v["repos"].as_array().unwrap();
+        assert_eq!(
+            repos,
+            &vec![
+                serde_json::json!({ "value": "foo", "source": foo }),
+                serde_json::json!({ "value": "baserepo", "source": top }),
+            ]
+        );
+        let pkgs = v["packages"].as_array().unwrap();
+        assert_eq!(pkgs.len(), 6);
+        assert_eq!(
+            pkgs[0],
+            serde_json::json!({ "value": "fooinclude", "source": foo })
+        );
+        assert!(pkgs[1..].iter().all(|p| p["source"] == top));
+        let metadata = &v["add-commit-metadata"];
+        assert_eq!(metadata["foo-key"]["source"], foo);
+        assert_eq!(
+            metadata["shared-key"],
+            serde_json::json!({ "value": "from-treefile", "source": top })
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_treefile_merge() {
         let basearch = Some(ARCH_X86_64);
@@ -1848,6 +4009,20 @@ pub(crate) fn treefile_new(
     basearch: &str,
     workdir: i32,
 ) -> CxxResult<Box<Treefile>> {
+    treefile_new_with_variables(filename, basearch, workdir, &Vec::new())
+}
+
+/// Like `treefile_new()`, but with variable overrides in `NAME=VALUE` form.
+pub(crate) fn treefile_new_with_variables(
+    filename: &str,
+    basearch: &str,
+    workdir: i32,
+    variables: &Vec<String>,
+) -> CxxResult<Box<Treefile>> {
+    let variables = variables
+        .iter()
+        .map(|s| parse_variable_override(s))
+        .collect::<Result<VariableMap>>()?;
     let basearch = opt_string(basearch);
     let workdir = if workdir != -1 {
         Some(crate::ffiutil::ffi_view_openat_dir(workdir))
@@ -1857,6 +4032,7 @@ pub(crate) fn treefile_new(
     Ok(Treefile::new_boxed(
         filename.as_ref(),
         basearch.as_deref(),
+        &variables,
         workdir,
     )?)
 }
@@ -0,0 +1,508 @@
+//! Rewrite treefiles into their current canonical form, i.e. without the
+//! legacy and deprecated fields which are otherwise handled at parse time
+//! by `migrate_legacy_fields()`.
+//!
+//! This backs the hidden `rpm-ostree ex-treefile-migrate` CLI.
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use crate::utils::{self, InputFormat};
+use anyhow::{anyhow, bail, Context, Result};
+use openat_ext::OpenatDirExt;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashSet;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ex-treefile-migrate")]
+#[structopt(rename_all = "kebab-case")]
+struct Opt {
+    /// Only print the changes, don't rewrite any file
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Don't migrate the files included by the treefile
+    #[structopt(long)]
+    no_includes: bool,
+
+    /// Path to the treefile
+    treefile: PathBuf,
+}
+
+/// Legacy field names, along with their replacement.
+static RENAMED_KEYS: &[(&str, &str)] = &[
+    ("gpg_key", "gpg-key"),
+    ("boot_location", "boot-location"),
+    ("default_target", "default-target"),
+    ("automatic_version_prefix", "automatic-version-prefix"),
+];
+
+/// Deprecated fields which are ignored, and hence can just be dropped.
+static DROPPED_KEYS: &[&str] = &["rojig"];
+
+/// Deprecated field whose entries are folded into `packages`.
+const BOOTSTRAP_PACKAGES: &str = "bootstrap_packages";
+
+#[derive(Debug, PartialEq)]
+enum Change {
+    Rename(&'static str, &'static str),
+    Drop(&'static str),
+    MergeIntoPackages,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Rename(old, new) => write!(f, "renamed {} to {}", old, new),
+            Change::Drop(key) => write!(f, "dropped deprecated {}", key),
+            Change::MergeIntoPackages => write!(f, "moved {} into packages", BOOTSTRAP_PACKAGES),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Migration {
+    changes: Vec<Change>,
+    /// The new contents of the file
+    contents: String,
+    /// Whether comments and formatting were kept as is
+    preserved_formatting: bool,
+}
+
+fn key(k: &str) -> Value {
+    Value::String(k.to_string())
+}
+
+/// Compute the changes needed to migrate `config`.
+fn plan_changes(config: &Mapping) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    for &(old, new) in RENAMED_KEYS {
+        if config.contains_key(&key(old)) {
+            if config.contains_key(&key(new)) {
+                bail!("Cannot use new and legacy forms of {}", new);
+            }
+            changes.push(Change::Rename(old, new));
+        }
+    }
+    for &k in DROPPED_KEYS {
+        if config.contains_key(&key(k)) {
+            changes.push(Change::Drop(k));
+        }
+    }
+    if config.contains_key(&key(BOOTSTRAP_PACKAGES)) {
+        changes.push(Change::MergeIntoPackages);
+    }
+    Ok(changes)
+}
+
+/// Apply `changes` to `config`, keeping the order of the keys.
+fn apply_changes(config: Mapping, changes: &[Change]) -> Result<Mapping> {
+    let bootstrap_pkgs = match config.get(&key(BOOTSTRAP_PACKAGES)) {
+        Some(Value::Sequence(pkgs)) => pkgs.clone(),
+        Some(_) => bail!("Invalid field {}: expected array", BOOTSTRAP_PACKAGES),
+        None => Vec::new(),
+    };
+    let has_packages = config.contains_key(&key("packages"));
+    let mut ret = Mapping::new();
+    for (k, v) in config {
+        let name = k.as_str().unwrap_or_default();
+        let renamed = changes.iter().find_map(|c| match c {
+            Change::Rename(old, new) if *old == name => Some(*new),
+            _ => None,
+        });
+        if let Some(new) = renamed {
+            ret.insert(key(new), v);
+        } else if changes
+            .iter()
+            .any(|c| matches!(c, Change::Drop(dropped) if *dropped == name))
+        {
+            continue;
+        } else if name == BOOTSTRAP_PACKAGES {
+            if !has_packages {
+                ret.insert(key("packages"), v);
+            }
+        } else if name == "packages" {
+            match v {
+                Value::Sequence(mut pkgs) => {
+                    pkgs.extend(bootstrap_pkgs.iter().cloned());
+                    ret.insert(k, Value::Sequence(pkgs));
+                }
+                _ => bail!("Invalid field packages: expected array"),
+            }
+        } else {
+            ret.insert(k, v);
+        }
+    }
+    Ok(ret)
+}
+
+/// Find the line starting the top-level `key` in a YAML document.
+fn yaml_find_key(lines: &[String], key: &str) -> Option<usize> {
+    lines.iter().position(|line| {
+        line.starts_with(key) && line[key.len()..].trim_start_matches(' ').starts_with(':')
+    })
+}
+
+/// Returns the index of the last line of the top-level YAML block starting at
+/// `start`, i.e. excluding trailing blank lines and comments of the next key.
+fn yaml_block_end(lines: &[String], start: usize) -> usize {
+    let mut last = start;
+    for (i, line) in lines.iter().enumerate().skip(start + 1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') || line.starts_with('-') {
+            last = i;
+        } else {
+            break;
+        }
+    }
+    last
+}
+
+/// Whether the top-level key on `line` has its value in the following block,
+/// as opposed to e.g. a flow sequence on the same line.
+fn yaml_is_block_header(line: &str) -> bool {
+    let value = line.splitn(2, ':').nth(1).unwrap_or_default().trim();
+    value.is_empty() || value.starts_with('#')
+}
+
+/// Apply `changes` to a YAML document, preserving comments and formatting.
+/// Returns `None` if that's not possible.
+fn yaml_apply_changes(contents: &str, changes: &[Change]) -> Option<String> {
+    let mut lines: Vec<String> = contents.split('\n').map(|s| s.to_string()).collect();
+    for change in changes {
+        match change {
+            Change::Rename(old, new) => {
+                let idx = yaml_find_key(&lines, old)?;
+                lines[idx] = format!("{}{}", new, &lines[idx][old.len()..]);
+            }
+            Change::Drop(k) => {
+                let idx = yaml_find_key(&lines, k)?;
+                let end = yaml_block_end(&lines, idx);
+                lines.drain(idx..=end);
+            }
+            Change::MergeIntoPackages => {
+                let bidx = yaml_find_key(&lines, BOOTSTRAP_PACKAGES)?;
+                let pidx = match yaml_find_key(&lines, "packages") {
+                    Some(pidx) => pidx,
+                    None => {
+                        lines[bidx] =
+                            format!("packages{}", &lines[bidx][BOOTSTRAP_PACKAGES.len()..]);
+                        continue;
+                    }
+                };
+                if !yaml_is_block_header(&lines[bidx]) || !yaml_is_block_header(&lines[pidx]) {
+                    return None;
+                }
+                let bend = yaml_block_end(&lines, bidx);
+                let pend = yaml_block_end(&lines, pidx);
+                let indent: String = {
+                    let first_item = lines[pidx + 1..=pend]
+                        .iter()
+                        .find(|l| l.trim_start().starts_with('-'))?;
+                    first_item
+                        .chars()
+                        .take_while(|c| c.is_whitespace())
+                        .collect()
+                };
+                let items: Vec<String> = lines[bidx + 1..=bend]
+                    .iter()
+                    .filter(|l| !l.trim().is_empty())
+                    .map(|l| format!("{}{}", indent, l.trim_start()))
+                    .collect();
+                // Remove the bootstrap_packages block last if it comes first,
+                // so that the indices stay valid.
+                if bidx > pend {
+                    lines.drain(bidx..=bend);
+                }
+                for (i, item) in items.into_iter().enumerate() {
+                    lines.insert(pend + 1 + i, item);
+                }
+                if bidx < pidx {
+                    lines.drain(bidx..=bend);
+                }
+            }
+        }
+    }
+    Some(lines.join("\n"))
+}
+
+/// Migrate the contents of a treefile in format `fmt`; returns `None` if
+/// there's nothing to do.
+fn migrate_contents(fmt: &InputFormat, contents: &str) -> Result<Option<Migration>> {
+    let value: Value = utils::parse_stream(fmt, &mut contents.as_bytes())?;
+    let config = match value {
+        Value::Mapping(m) => m,
+        _ => bail!("Expected an object"),
+    };
+    let changes = plan_changes(&config)?;
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    let migrated = Value::Mapping(apply_changes(config, &changes)?);
+
+    if *fmt == InputFormat::YAML {
+        // Only keep the textual edits if they yield exactly the same result
+        if let Some(new_contents) = yaml_apply_changes(contents, &changes) {
+            if serde_yaml::from_str::<Value>(&new_contents).ok().as_ref() == Some(&migrated) {
+                return Ok(Some(Migration {
+                    changes,
+                    contents: new_contents,
+                    preserved_formatting: true,
+                }));
+            }
+        }
+    }
+    let contents = match fmt {
+        InputFormat::YAML => serde_yaml::to_string(&migrated)?,
+        InputFormat::JSON => serde_json::to_string_pretty(&migrated)? + "\n",
+        InputFormat::TOML => toml::to_string_pretty(&toml::Value::try_from(&migrated)?)?,
+    };
+    Ok(Some(Migration {
+        changes,
+        contents,
+        // JSON has no comments anyway
+        preserved_formatting: *fmt == InputFormat::JSON,
+    }))
+}
+
+/// Gather the literal include paths of a treefile.
+fn includes_of(config: &Mapping) -> Vec<String> {
+    fn push_include(v: &Value, ret: &mut Vec<String>) {
+        match v {
+            Value::String(s) => ret.push(s.clone()),
+            Value::Sequence(v) => ret.extend(v.iter().filter_map(|s| s.as_str()).map(String::from)),
+            _ => {}
+        }
+    }
+    let mut ret = Vec::new();
+    if let Some(v) = config.get(&key("include")) {
+        push_include(v, &mut ret);
+    }
+    if let Some(Value::Mapping(m)) = config.get(&key("arch-include")) {
+        for (_, v) in m.iter() {
+            push_include(v, &mut ret);
+        }
+    }
+    if let Some(Value::Sequence(v)) = config.get(&key("conditional-include")) {
+        for v in v.iter().filter_map(|c| c.get("include")) {
+            push_include(v, &mut ret);
+        }
+    }
+    ret
+}
+
+/// Migrate `filename`, and unless `no_includes` is set, all the files it
+/// includes.  Returns the changes made to each file.
+fn migrate_recurse(
+    filename: &Path,
+    dry_run: bool,
+    no_includes: bool,
+    seen: &mut HashSet<PathBuf>,
+    results: &mut Vec<(PathBuf, Migration)>,
+) -> Result<()> {
+    if !seen.insert(filename.canonicalize()?) {
+        return Ok(());
+    }
+    let fmt = InputFormat::detect_from_filename(filename)?;
+    let contents = std::fs::read_to_string(filename)
+        .with_context(|| format!("Reading {}", filename.display()))?;
+    let migration = migrate_contents(&fmt, &contents)
+        .with_context(|| format!("Migrating {}", filename.display()))?;
+    if !no_includes {
+        let config: Value = utils::parse_stream(&fmt, &mut contents.as_bytes())?;
+        let parent = utils::parent_dir(filename).unwrap();
+        for include in includes_of(config.as_mapping().unwrap()) {
+            if include.contains("${") {
+                eprintln!(
+                    "warning: {}: skipping include {} which uses variables",
+                    filename.display(),
+                    include
+                );
+                continue;
+            }
+            migrate_recurse(&parent.join(include), dry_run, no_includes, seen, results)?;
+        }
+    }
+    if let Some(migration) = migration {
+        if !dry_run {
+            let mode = std::fs::metadata(filename)?.permissions().mode() & 0o7777;
+            let dir = openat::Dir::open(utils::parent_dir(filename).unwrap())?;
+            let basename = filename
+                .file_name()
+                .map(Path::new)
+                .ok_or_else(|| anyhow!("Expected a filename"))?;
+            dir.write_file_contents(basename, mode, migration.contents.as_bytes())
+                .with_context(|| format!("Writing {}", filename.display()))?;
+        }
+        results.push((filename.to_path_buf(), migration));
+    }
+    Ok(())
+}
+
+/// Main entrypoint for ex-treefile-migrate
+pub fn entrypoint(args: &[&str]) -> Result<()> {
+    // Skip the main `rpm-ostree` argument
+    let opt = Opt::from_iter(args.iter().skip(1));
+    let mut results = Vec::new();
+    migrate_recurse(
+        &opt.treefile,
+        opt.dry_run,
+        opt.no_includes,
+        &mut HashSet::new(),
+        &mut results,
+    )?;
+    if results.is_empty() {
+        println!("No changes needed");
+    }
+    for (filename, migration) in results.iter() {
+        for change in migration.changes.iter() {
+            println!("{}: {}", filename.display(), change);
+        }
+        if !migration.preserved_formatting {
+            println!(
+                "{}: note: comments and formatting were not preserved",
+                filename.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn test_migrate_yaml() -> Result<()> {
+        let contents = indoc! {"
+            # An example treefile
+            ref: exampleos/x86_64/blah
+            gpg_key: 1234  # our key
+            rojig:
+              name: exampleos
+              summary: ExampleOS
+              license: MIT
+
+            packages:
+              # Core bits
+              - bash
+              - systemd
+            bootstrap_packages:
+              - filesystem
+              - setup
+            # Everything else is default
+            default_target: multi-user.target
+        "};
+        let migration = migrate_contents(&InputFormat::YAML, contents)?.unwrap();
+        assert_eq!(
+            migration.changes,
+            vec![
+                Change::Rename("gpg_key", "gpg-key"),
+                Change::Rename("default_target", "default-target"),
+                Change::Drop("rojig"),
+                Change::MergeIntoPackages,
+            ]
+        );
+        assert!(migration.preserved_formatting);
+        assert_eq!(
+            migration.contents,
+            indoc! {"
+                # An example treefile
+                ref: exampleos/x86_64/blah
+                gpg-key: 1234  # our key
+
+                packages:
+                  # Core bits
+                  - bash
+                  - systemd
+                  - filesystem
+                  - setup
+                # Everything else is default
+                default-target: multi-user.target
+            "}
+        );
+        // And it's now a no-op
+        assert!(migrate_contents(&InputFormat::YAML, &migration.contents)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_yaml_fallback() -> Result<()> {
+        // Flow sequences can't be merged textually
+        let contents = "packages: [bash]\nbootstrap_packages: [setup]\n";
+        let migration = migrate_contents(&InputFormat::YAML, contents)?.unwrap();
+        assert!(!migration.preserved_formatting);
+        let v: Value = serde_yaml::from_str(&migration.contents)?;
+        assert_eq!(v, serde_yaml::from_str::<Value>("packages: [bash, setup]")?);
+
+        // Without packages, this is just a rename
+        let contents = "bootstrap_packages: [setup]\n";
+        let migration = migrate_contents(&InputFormat::YAML, contents)?.unwrap();
+        assert!(migration.preserved_formatting);
+        assert_eq!(migration.contents, "packages: [setup]\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_json() -> Result<()> {
+        let contents = r#"{"ref": "foo", "packages": ["bash"], "automatic_version_prefix": "33"}"#;
+        let migration = migrate_contents(&InputFormat::JSON, contents)?.unwrap();
+        let expected = indoc! {r#"
+            {
+              "ref": "foo",
+              "packages": [
+                "bash"
+              ],
+              "automatic-version-prefix": "33"
+            }
+        "#};
+        assert_eq!(migration.contents, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_conflict() {
+        let contents = "gpg_key: foo\ngpg-key: bar\n";
+        let e = migrate_contents(&InputFormat::YAML, contents).unwrap_err();
+        assert_eq!(e.to_string(), "Cannot use new and legacy forms of gpg-key");
+    }
+
+    #[test]
+    fn test_migrate_includes() -> Result<()> {
+        let workdir = tempfile::tempdir()?;
+        let d = workdir.path();
+        std::fs::write(d.join("base.json"), r#"{"default_target": "foo"}"#)?;
+        std::fs::write(d.join("x86_64.toml"), "boot_location = \"new\"\n")?;
+        std::fs::write(
+            d.join("treefile.yaml"),
+            "include: base.json\narch-include:\n  x86_64: x86_64.toml\nrojig: {}\n",
+        )?;
+        let treefile = d.join("treefile.yaml");
+        let mut results = Vec::new();
+        migrate_recurse(&treefile, true, false, &mut HashSet::new(), &mut results)?;
+        assert_eq!(results.len(), 3);
+        // Nothing was written
+        assert!(std::fs::read_to_string(d.join("base.json"))?.contains("default_target"));
+
+        let mut results = Vec::new();
+        migrate_recurse(&treefile, false, false, &mut HashSet::new(), &mut results)?;
+        assert_eq!(
+            std::fs::read_to_string(d.join("treefile.yaml"))?,
+            "include: base.json\narch-include:\n  x86_64: x86_64.toml\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(d.join("x86_64.toml"))?,
+            "boot-location = \"new\"\n"
+        );
+        let mut results = Vec::new();
+        migrate_recurse(&treefile, false, false, &mut HashSet::new(), &mut results)?;
+        assert!(results.is_empty());
+        Ok(())
+    }
+}
@@ -25,6 +25,7 @@ use curl::easy::Easy;
 pub enum InputFormat {
     YAML,
     JSON,
+    TOML,
 }
 
 impl InputFormat {
@@ -36,6 +37,8 @@ impl InputFormat {
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Expected a filename"))?;
         if basename.ends_with(".yaml") || basename.ends_with(".yml") {
             Ok(Self::YAML)
+        } else if basename.ends_with(".toml") {
+            Ok(Self::TOML)
         } else {
             Ok(Self::JSON)
         }
@@ -66,6 +69,18 @@ where
             })?;
             pf
         }
+        InputFormat::TOML => {
+            // The toml crate has no streaming API
+            let mut buf = String::new();
+            input.read_to_string(&mut buf)?;
+            let pf: T = toml::from_str(&buf).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("serde-toml: {}", e.to_string()),
+                )
+            })?;
+            pf
+        }
     };
     Ok(parsed)
 }
@@ -214,10 +229,44 @@ pub(crate) fn running_in_systemd() -> bool {
     *RUNNING_IN_SYSTEMD
 }
 
+/// Parse the `SOURCE_DATE_EPOCH` environment variable; if set, composes
+/// are done in reproducible mode, with timestamps clamped to it.
+/// See https://reproducible-builds.org/specs/source-date-epoch/
+pub(crate) fn source_date_epoch() -> Result<Option<i64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(v) if !v.is_empty() => Ok(Some(parse_source_date_epoch(&v)?)),
+        _ => Ok(None),
+    }
+}
+
+fn parse_source_date_epoch(v: &str) -> Result<i64> {
+    let epoch = v
+        .parse::<i64>()
+        .with_context(|| format!("Invalid SOURCE_DATE_EPOCH {:?}", v))?;
+    if epoch < 0 {
+        bail!("Invalid SOURCE_DATE_EPOCH {:?}", v);
+    }
+    Ok(epoch)
+}
+
+/// Bridged version of `source_date_epoch()`, with -1 meaning unset.
+pub(crate) fn get_source_date_epoch() -> CxxResult<i64> {
+    Ok(source_date_epoch()?.unwrap_or(-1))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_source_date_epoch() {
+        assert_eq!(parse_source_date_epoch("1620000000").unwrap(), 1620000000);
+        assert_eq!(parse_source_date_epoch("0").unwrap(), 0);
+        for invalid in &["-1", "yesterday", "1.5", " 12"] {
+            assert!(parse_source_date_epoch(invalid).is_err(), "{}", invalid);
+        }
+    }
+
     fn subs() -> HashMap<String, String> {
         let mut h = HashMap::new();
         h.insert("basearch".to_string(), "ppc64le".to_string());
@@ -254,6 +303,28 @@ mod tests {
         assert_eq!(r, "fedora");
     }
 
+    #[test]
+    fn test_detect_input_format() -> Result<()> {
+        let detect = InputFormat::detect_from_filename::<&str>;
+        assert!(detect("foo/treefile.yaml")? == InputFormat::YAML);
+        assert!(detect("treefile.yml")? == InputFormat::YAML);
+        assert!(detect("treefile.toml")? == InputFormat::TOML);
+        assert!(detect("treefile.json")? == InputFormat::JSON);
+        assert!(detect("treefile")? == InputFormat::JSON);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_stream_toml() -> Result<()> {
+        let mut input = io::BufReader::new("a = 1\nb = [\"x\"]\n".as_bytes());
+        let v: serde_json::Value = parse_stream(&InputFormat::TOML, &mut input)?;
+        assert_eq!(v, serde_json::json!({"a": 1, "b": ["x"]}));
+        let mut input = io::BufReader::new("a = ".as_bytes());
+        let r: Result<serde_json::Value> = parse_stream(&InputFormat::TOML, &mut input);
+        assert!(r.unwrap_err().to_string().starts_with("serde-toml: "));
+        Ok(())
+    }
+
     #[test]
     fn test_decompose_sha256_nevra() -> Result<()> {
         assert!(decompose_sha256_nevra("").is_err());
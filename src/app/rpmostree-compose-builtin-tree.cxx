@@ -72,10 +72,16 @@ static char *opt_touch_if_changed;
 static char *opt_previous_commit;
 static gboolean opt_dry_run;
 static gboolean opt_print_only;
+static gboolean opt_print_json;
+static gboolean opt_strict;
+static char **opt_variables;
+static gboolean opt_explain_inputhash;
 static char *opt_write_commitid_to;
 static char *opt_write_composejson_to;
 static gboolean opt_no_parent;
 static char *opt_write_lockfile_to;
+static char *opt_write_sbom_to;
+static char *opt_write_size_report_to;
 static char **opt_lockfiles;
 static gboolean opt_lockfile_strict;
 static char *opt_parent;
@@ -100,13 +106,18 @@ static GOptionEntry install_option_entries[] = {
   { "proxy", 0, 0, G_OPTION_ARG_STRING, &opt_proxy, "HTTP proxy", "PROXY" },
   { "dry-run", 0, 0, G_OPTION_ARG_NONE, &opt_dry_run, "Just print the transaction and exit", NULL },
   { "print-only", 0, 0, G_OPTION_ARG_NONE, &opt_print_only, "Just expand any includes and print treefile", NULL },
+  { "print-json", 0, 0, G_OPTION_ARG_NONE, &opt_print_json, "Like --print-only, but annotate each value with the file it came from", NULL },
+  { "strict", 0, 0, G_OPTION_ARG_NONE, &opt_strict, "Reject unknown keys in the treefile and its includes", NULL },
+  { "var", 0, 0, G_OPTION_ARG_STRING_ARRAY, &opt_variables, "Set treefile variable NAME, overriding any value from the treefile", "NAME=VALUE" },
   { "touch-if-changed", 0, 0, G_OPTION_ARG_STRING, &opt_touch_if_changed, "Update the modification time on FILE if a new commit was created", "FILE" },
   { "previous-commit", 0, 0, G_OPTION_ARG_STRING, &opt_previous_commit, "Use this commit for change detection", "COMMIT" },
+  { "explain-inputhash", 0, 0, G_OPTION_ARG_NONE, &opt_explain_inputhash, "Print which inputs changed since the previous commit", NULL },
   { "workdir", 0, 0, G_OPTION_ARG_STRING, &opt_workdir, "Working directory", "WORKDIR" },
   { "workdir-tmpfs", 0, G_OPTION_FLAG_HIDDEN, G_OPTION_ARG_NONE, &opt_workdir_tmpfs, "Use tmpfs for working state", NULL },
   { "ex-write-lockfile-to", 0, 0, G_OPTION_ARG_STRING, &opt_write_lockfile_to, "Write lockfile to FILE", "FILE" },
   { "ex-lockfile", 0, 0, G_OPTION_ARG_STRING_ARRAY, &opt_lockfiles, "Read lockfile from FILE", "FILE" },
   { "ex-lockfile-strict", 0, 0, G_OPTION_ARG_NONE, &opt_lockfile_strict, "With --ex-lockfile, only allow installing locked packages", NULL },
+  { "write-sbom-to", 0, 0, G_OPTION_ARG_STRING, &opt_write_sbom_to, "Write a CycloneDX software bill of materials to FILE", "FILE" },
   { NULL }
 };
 
@@ -121,6 +132,7 @@ static GOptionEntry commit_option_entries[] = {
   { "write-composejson-to", 0, 0, G_OPTION_ARG_STRING, &opt_write_composejson_to, "Write JSON to FILE containing information about the compose run", "FILE" },
   { "no-parent", 0, 0, G_OPTION_ARG_NONE, &opt_no_parent, "Always commit without a parent", NULL },
   { "parent", 0, 0, G_OPTION_ARG_STRING, &opt_parent, "Commit with specific parent", "REV" },
+  { "write-size-report-to", 0, 0, G_OPTION_ARG_STRING, &opt_write_size_report_to, "Write a JSON report of the size of the tree per package to FILE", "FILE" },
   { NULL }
 };
 
@@ -212,6 +224,67 @@ inputhash_from_commit (OstreeRepo *repo,
   return TRUE;
 }
 
+/* Digests of each input of the input hash, stored as rpmostree.inputhashes so
+ * that a change of rpmostree.inputhash can be explained later on. */
+static gboolean
+compute_input_digests (RpmOstreeTreeComposeContext *self,
+                       HyGoal                       goal,
+                       GVariant                   **out_digests,
+                       GError                     **error)
+{
+  g_autoptr(GChecksum) rpms_checksum = g_checksum_new (G_CHECKSUM_SHA256);
+  if (!rpmostree_dnf_add_checksum_goal (rpms_checksum, goal, NULL, error))
+    return FALSE;
+
+  auto digests = (*self->treefile_rs)->get_input_digests(*self->repo);
+  g_autoptr(GVariantBuilder) builder = g_variant_builder_new (G_VARIANT_TYPE ("a{ss}"));
+  for (auto &digest : digests)
+    g_variant_builder_add (builder, "{ss}", digest.k.c_str(), digest.v.c_str());
+  g_variant_builder_add (builder, "{ss}", "rpms", g_checksum_get_string (rpms_checksum));
+  *out_digests = g_variant_ref_sink (g_variant_builder_end (builder));
+  return TRUE;
+}
+
+static rust::Vec<rpmostreecxx::StringMapping>
+input_digests_to_mapping (GVariant *digests)
+{
+  rust::Vec<rpmostreecxx::StringMapping> ret;
+  GVariantIter iter;
+  const char *k, *v;
+  g_variant_iter_init (&iter, digests);
+  while (g_variant_iter_next (&iter, "{&s&s}", &k, &v))
+    ret.push_back(rpmostreecxx::StringMapping{k, v});
+  return ret;
+}
+
+/* Print which inputs changed compared to the previous commit */
+static gboolean
+print_input_digest_changes (RpmOstreeTreeComposeContext *self,
+                            GVariant                    *new_digests,
+                            GError                     **error)
+{
+  g_autoptr(GVariant) commit_v = NULL;
+  if (!ostree_repo_load_variant (self->repo, OSTREE_OBJECT_TYPE_COMMIT,
+                                 self->previous_checksum, &commit_v, error))
+    return FALSE;
+
+  g_autoptr(GVariant) commit_metadata = g_variant_get_child_value (commit_v, 0);
+  g_autoptr(GVariant) previous_digests =
+    g_variant_lookup_value (commit_metadata, "rpmostree.inputhashes", G_VARIANT_TYPE ("a{ss}"));
+  if (!previous_digests)
+    {
+      g_print ("Previous commit found, but without rpmostree.inputhashes metadata key\n");
+      return TRUE;
+    }
+
+  auto changes = rpmostreecxx::describe_input_digest_changes (input_digests_to_mapping (previous_digests),
+                                                              input_digests_to_mapping (new_digests));
+  g_print ("Changed inputs since previous commit:%s\n", changes.empty() ? " none" : "");
+  for (auto &change : changes)
+    g_print ("  %s\n", change.c_str());
+  return TRUE;
+}
+
 static gboolean
 try_load_previous_sepolicy (RpmOstreeTreeComposeContext *self,
                             GCancellable                 *cancellable,
@@ -269,6 +342,7 @@ static gboolean
 install_packages (RpmOstreeTreeComposeContext  *self,
                   gboolean                     *out_unmodified,
                   char                        **out_new_inputhash,
+                  GVariant                    **out_input_digests,
                   GCancellable                 *cancellable,
                   GError                      **error)
 {
@@ -392,6 +466,16 @@ install_packages (RpmOstreeTreeComposeContext  *self,
 
   g_print ("Input state hash: %s\n", ret_new_inputhash);
 
+  g_autoptr(GVariant) ret_input_digests = NULL;
+  if (!compute_input_digests (self, dnf_context_get_goal (dnfctx), &ret_input_digests, error))
+    return FALSE;
+
+  if (self->previous_checksum && opt_explain_inputhash)
+    {
+      if (!print_input_digest_changes (self, ret_input_digests, error))
+        return FALSE;
+    }
+
   /* Only look for previous checksum if caller has passed *out_unmodified */
   if (self->previous_checksum && out_unmodified != NULL)
     {
@@ -500,9 +584,26 @@ install_packages (RpmOstreeTreeComposeContext  *self,
       g_signal_handler_disconnect (hifstate, progress_sigid);
     }
 
+  if (opt_write_sbom_to)
+    {
+      g_autoptr(GPtrArray) pkgs = rpmostree_context_get_packages (self->corectx);
+      g_assert (pkgs);
+      auto pkgs_v = rpmostreecxx::CxxGObjectArray(pkgs);
+      rust::Vec<rust::String> lockfiles;
+      for (char **it = opt_lockfiles; it && *it; it++)
+        lockfiles.push_back(std::string(*it));
+      auto sbom_digest = rpmostreecxx::sbom_write (opt_write_sbom_to, pkgs_v,
+                                                   **self->treefile_rs, lockfiles,
+                                                   *self->repo);
+      /* Stored along with the add-commit-metadata keys */
+      g_hash_table_replace (self->metadata, g_strdup ("rpmostree.sbom-sha256"),
+                            g_variant_ref_sink (g_variant_new_string (sbom_digest.c_str())));
+    }
+
   if (out_unmodified)
     *out_unmodified = FALSE;
   *out_new_inputhash = util::move_nullify (ret_new_inputhash);
+  *out_input_digests = util::move_nullify (ret_input_digests);
   return TRUE;
 }
 
@@ -563,6 +664,19 @@ repo_is_on_netfs (OstreeRepo  *repo)
     }
 }
 
+/* Load the treefile, applying --var and --strict */
+static rust::Box<rpmostreecxx::Treefile>
+treefile_new_from_opts (const char *treefile_path, const char *arch, int workdir_dfd)
+{
+  rust::Vec<rust::String> variables;
+  for (char **it = opt_variables; it && *it; it++)
+    variables.push_back(std::string(*it));
+  auto treefile = rpmostreecxx::treefile_new_with_variables (treefile_path, arch, workdir_dfd, variables);
+  if (opt_strict)
+    treefile->validate_strict();
+  return treefile;
+}
+
 /* Prepare a context - this does some generic pre-compose initialization from
  * the arguments such as loading the treefile and any specified metadata.
  */
@@ -696,7 +810,7 @@ rpm_ostree_compose_context_new (const char    *treefile_pathstr,
       arch = dnf_context_get_base_arch (ctx);
   }
   self->treefile_path = g_file_new_for_path (treefile_pathstr);
-  self->treefile_rs = rpmostreecxx::treefile_new(gs_file_get_path_cached (self->treefile_path), arch, self->workdir_dfd);
+  self->treefile_rs = treefile_new_from_opts (gs_file_get_path_cached (self->treefile_path), arch.c_str(), self->workdir_dfd);
   self->corectx = rpmostree_context_new_compose (self->cachedir_dfd, self->build_repo,
                                                  **self->treefile_rs);
   /* In the legacy compose path, we don't want to use any of the core's selinux stuff,
@@ -912,10 +1026,11 @@ impl_install_tree (RpmOstreeTreeComposeContext *self,
 
   /* Download rpm-md repos, packages, do install */
   g_autofree char *new_inputhash = NULL;
+  g_autoptr(GVariant) input_digests = NULL;
   { gboolean unmodified = FALSE;
 
     if (!install_packages (self, opt_force_nocache ? NULL : &unmodified,
-                           &new_inputhash, cancellable, error))
+                           &new_inputhash, &input_digests, cancellable, error))
       return FALSE;
 
     gboolean is_dry_run = opt_dry_run || (opt_download_only || opt_download_only_rpms);
@@ -992,6 +1107,8 @@ impl_install_tree (RpmOstreeTreeComposeContext *self,
   /* Insert our input hash */
   g_hash_table_replace (self->metadata, g_strdup ("rpmostree.inputhash"),
                         g_variant_ref_sink (g_variant_new_string (new_inputhash)));
+  g_hash_table_replace (self->metadata, g_strdup ("rpmostree.inputhashes"),
+                        g_variant_ref (input_digests));
 
   *out_changed = TRUE;
   return TRUE;
@@ -1082,8 +1199,29 @@ impl_commit_tree (RpmOstreeTreeComposeContext *self,
   if (self->treefile_rs)
     {
       auto previous_rev = self->previous_checksum?: "";
+      rpmostreecxx::sysusers_compose (*self->repo, self->rootfs_dfd,
+                                      **self->treefile_rs, previous_rev);
       rpmostreecxx::check_passwd_group_entries (*self->repo, self->rootfs_dfd,
                                                 **self->treefile_rs, previous_rev);
+      rpmostreecxx::compose_hardlink_dedup (self->rootfs_dfd, **self->treefile_rs);
+      rpmostreecxx::lint_rootfs (*self->repo, self->rootfs_dfd, **self->treefile_rs,
+                                 previous_rev);
+
+      if (opt_write_size_report_to || (*self->treefile_rs)->has_size_budgets())
+        {
+          g_autoptr(RpmOstreeRefSack) refsack =
+            rpmostree_get_refsack_for_root (self->rootfs_dfd, ".", error);
+          if (!refsack)
+            return FALSE;
+          g_autoptr(GPtrArray) pkgs = rpmostree_sack_get_sorted_packages (refsack->sack);
+          auto pkgs_v = rpmostreecxx::CxxGObjectArray(pkgs);
+          if (opt_write_size_report_to)
+            rpmostreecxx::size_report_write (opt_write_size_report_to, self->rootfs_dfd,
+                                             pkgs_v, **self->treefile_rs, *self->repo);
+          /* Checked on the same final tree as the size report */
+          rpmostreecxx::check_size_budgets (self->rootfs_dfd, pkgs_v, **self->treefile_rs,
+                                            *self->repo);
+        }
     }
 
   /* See comment above */
@@ -1176,8 +1314,9 @@ static gboolean
 parse_and_print_treefile (const char *treefile_path, GError **error)
 {
   g_autofree char *arch = rpm_ostree_get_basearch ();
-  auto treefile_rs = rpmostreecxx::treefile_new (treefile_path, arch, -1);
-  auto buf = treefile_rs->get_json_string();
+  auto treefile_rs = treefile_new_from_opts (treefile_path, arch, -1);
+  auto buf = opt_print_json ? treefile_rs->get_json_string_with_provenance ()
+                            : treefile_rs->get_json_string ();
   g_print ("%s\n", buf.c_str());
   return TRUE;
 }
@@ -1209,7 +1348,7 @@ rpmostree_compose_builtin_install (int             argc,
 
   const char *treefile_path = argv[1];
 
-  if (opt_print_only)
+  if (opt_print_only || opt_print_json)
     return parse_and_print_treefile (treefile_path, error);
 
   if (!opt_repo)
@@ -1385,7 +1524,7 @@ rpmostree_compose_builtin_tree (int             argc,
 
   const char *treefile_path = argv[1];
 
-  if (opt_print_only)
+  if (opt_print_only || opt_print_json)
     return parse_and_print_treefile (treefile_path, error);
 
   if (!opt_repo)
@@ -3881,6 +3881,32 @@ process_ostree_layers (RpmOstreeContext *self,
   return TRUE;
 }
 
+/* librpm records the current time as the install time of each package, even
+ * with a fixed transaction ID; in reproducible mode, rewrite it in the rpmdb.
+ */
+static gboolean
+rpmdb_clamp_installtime (rpmts        ts,
+                         rpm_time_t   installtime,
+                         GError     **error)
+{
+  if (rpmtsOpenDB (ts, O_RDWR) != 0)
+    return glnx_throw (error, "Failed to open rpmdb for writing");
+  g_auto(rpmdbMatchIterator) mi = rpmtsInitIterator (ts, RPMDBI_PACKAGES, NULL, 0);
+  if (!mi)
+    return TRUE;
+  /* Modified headers are written back when moving to the next one */
+  rpmdbSetIteratorRewrite (mi, 1);
+  Header h;
+  while ((h = rpmdbNextIterator (mi)) != NULL)
+    {
+      headerDel (h, RPMTAG_INSTALLTIME);
+      if (!headerPutUint32 (h, RPMTAG_INSTALLTIME, &installtime, 1))
+        return glnx_throw (error, "Failed to set install time of %s", headerGetString (h, RPMTAG_NAME));
+      rpmdbSetIteratorModified (mi, 1);
+    }
+  return TRUE;
+}
+
 gboolean
 rpmostree_context_assemble (RpmOstreeContext      *self,
                             GCancellable          *cancellable,
@@ -4370,6 +4396,18 @@ rpmostree_context_assemble (RpmOstreeContext      *self,
   tdata.ctx = self;
   rpmtsSetNotifyCallback (rpmdb_ts, ts_callback, &tdata);
 
+  /* In reproducible mode, use SOURCE_DATE_EPOCH as the transaction ID rather
+   * than the current time, and add the packages in a stable order.  The
+   * install times are clamped once the transaction is done. */
+  auto source_date_epoch = rpmostreecxx::get_source_date_epoch();
+  if (source_date_epoch >= 0)
+    {
+      rpmtsSetTid (rpmdb_ts, (rpm_tid_t) source_date_epoch);
+      g_ptr_array_sort (overlays, compare_pkgs);
+      g_ptr_array_sort (overrides_replace, compare_pkgs);
+      g_ptr_array_sort (overrides_remove, compare_pkgs);
+    }
+
   /* Skip validating scripts since we already validated them above */
   RpmOstreeTsAddInstallFlags rpmdb_instflags = RPMOSTREE_TS_FLAG_NOVALIDATE_SCRIPTS;
   for (guint i = 0; i < overlays->len; i++)
@@ -4418,6 +4456,12 @@ rpmostree_context_assemble (RpmOstreeContext      *self,
         return FALSE;
     }
 
+  if (source_date_epoch >= 0)
+    {
+      if (!rpmdb_clamp_installtime (rpmdb_ts, (rpm_time_t) source_date_epoch, error))
+        return FALSE;
+    }
+
   task->end("");
 
   /* And finally revert the _dbpath setting because libsolv relies on it as well
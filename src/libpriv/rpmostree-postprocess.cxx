@@ -466,8 +466,19 @@ rpmostree_postprocess_final (int            rootfs_dfd,
     util::rethrow_prefixed(e, "failed to migrate 'group' to /usr/lib");
   }
 
+  /* Users and groups created by scripts without shadow-utils lack entries there */
+  rpmostreecxx::sync_shadow_files(rootfs_dfd, "usr/etc");
+
   /* NSS configuration to look at the new files */
-  rpmostreecxx::composepost_nsswitch_altfiles(rootfs_dfd);
+  const char *users_backend = NULL;
+  if (!_rpmostree_jsonutil_object_get_optional_string_member (treefile,
+                                                              "users-backend",
+                                                              &users_backend, error))
+    return FALSE;
+  if (g_strcmp0 (users_backend, "userdb") == 0)
+    rpmostreecxx::composepost_nsswitch_userdb(rootfs_dfd);
+  else
+    rpmostreecxx::composepost_nsswitch_altfiles(rootfs_dfd);
 
   if (selinux)
     {
@@ -475,7 +486,15 @@ rpmostree_postprocess_final (int            rootfs_dfd,
         return glnx_prefix_error (error, "SELinux postprocess");
     }
 
-  rpmostreecxx::convert_var_to_tmpfiles_d (rootfs_dfd, *cancellable);
+  {
+    /* Used to split the generated tmpfiles.d entries by owning package */
+    g_autoptr(RpmOstreeRefSack) refsack = rpmostree_get_refsack_for_root (rootfs_dfd, ".", error);
+    if (!refsack)
+      return FALSE;
+    g_autoptr(GPtrArray) pkgs = rpmostree_sack_get_sorted_packages (refsack->sack);
+    auto pkgs_v = rpmostreecxx::CxxGObjectArray(pkgs);
+    rpmostreecxx::convert_var_to_tmpfiles_d (rootfs_dfd, pkgs_v, *cancellable);
+  }
 
   rpmostreecxx::rootfs_prepare_links(rootfs_dfd);
 
@@ -928,10 +947,23 @@ rpmostree_compose_commit (int            rootfs_fd,
   g_autoptr(GVariant) metadata = g_variant_dict_end (metadata_dict);
 
   g_autofree char *new_revision = NULL;
-  if (!ostree_repo_write_commit (repo, parent_revision, "", "", metadata,
-                                 (OstreeRepoFile*)root_tree, &new_revision,
-                                 cancellable, error))
-    return glnx_prefix_error (error, "While writing commit");
+  /* In reproducible mode, the commit timestamp is SOURCE_DATE_EPOCH */
+  auto source_date_epoch = rpmostreecxx::get_source_date_epoch();
+  if (source_date_epoch >= 0)
+    {
+      if (!ostree_repo_write_commit_with_time (repo, parent_revision, "", "", metadata,
+                                               (OstreeRepoFile*)root_tree,
+                                               (guint64) source_date_epoch, &new_revision,
+                                               cancellable, error))
+        return glnx_prefix_error (error, "While writing commit");
+    }
+  else
+    {
+      if (!ostree_repo_write_commit (repo, parent_revision, "", "", metadata,
+                                     (OstreeRepoFile*)root_tree, &new_revision,
+                                     cancellable, error))
+        return glnx_prefix_error (error, "While writing commit");
+    }
 
   if (gpg_keyid)
     {
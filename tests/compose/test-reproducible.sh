@@ -0,0 +1,23 @@
+#!/bin/bash
+set -xeuo pipefail
+
+dn=$(cd "$(dirname "$0")" && pwd)
+# shellcheck source=libcomposetest.sh
+. "${dn}/libcomposetest.sh"
+
+epoch=1620000000
+
+runasroot env SOURCE_DATE_EPOCH=${epoch} rpm-ostree compose tree ${compose_base_argv} \
+    --write-commitid-to=$(pwd)/commitid.txt "${treefile}"
+commit=$(cat commitid.txt)
+ostree --repo=${repo} checkout -U --subpath=/usr/share/rpm ${commit} rpmdb
+rpm -qa --dbpath=$(pwd)/rpmdb --qf '%{INSTALLTIME} %{INSTALLTID}\n' | sort -u > installtime.txt
+assert_streq "$(cat installtime.txt)" "${epoch} ${epoch}"
+echo "ok rpmdb install times"
+
+# Two composes with the same inputs yield the same commit
+runasroot rpm-ostree testutils compose-twice --repo=${repo} \
+    --source-date-epoch=${epoch} "${treefile}" -- \
+    --unified-core --cachedir=${test_tmpdir}/cache > compose-twice.txt
+assert_file_has_content compose-twice.txt 'Composes are identical'
+echo "ok compose-twice"